@@ -23,8 +23,10 @@ extern crate stemjail;
 use getopts::Options;
 use std::env;
 use std::process;
-use stemjail::config::get_configs;
-use stemjail::config::portal::Portal;
+use std::path::Path;
+use stemjail::config::{get_configs, get_owned_config};
+use stemjail::config::portal::{protected_paths, Portal, PortalConfig};
+use stemjail::config::profile::check_writable_binds;
 use stemjail::srv::portal_listen;
 
 macro_rules! exit_error {
@@ -54,11 +56,30 @@ fn main() {
     env_logger::init().unwrap();
 
     // TODO: Add dynamic configuration reload
-    let portal = Portal::new(
-        match get_configs(stemjail::PORTAL_PROFILES_PATH) {
+    let portal_config = if Path::new(stemjail::PORTAL_CONFIG_PATH).exists() {
+        match get_owned_config(stemjail::PORTAL_CONFIG_PATH) {
             Ok(c) => c,
-            Err(e) => exit_error!("Failed to get configuration: {}", e),
-        },
+            Err(e) => exit_error!("Failed to get the portal configuration: {}", e),
+        }
+    } else {
+        PortalConfig::default()
+    };
+    let configs = match get_configs(stemjail::PORTAL_PROFILES_PATH) {
+        Ok(c) => c,
+        Err(e) => exit_error!("Failed to get configuration: {}", e),
+    };
+    let protected = match protected_paths() {
+        Ok(p) => p,
+        Err(e) => exit_error!("Failed to get the protected paths: {}", e),
+    };
+    match check_writable_binds(&configs, &protected) {
+        Ok(()) => {}
+        Err(e) => exit_error!("Refusing the configuration: {}", e),
+    }
+    let portal = Portal::new(
+        configs,
+        portal_config,
+        protected,
         ! matches.opt_present("unconfined"),
     );
     info!("Loaded configuration: {}", portal);
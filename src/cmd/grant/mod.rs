@@ -0,0 +1,208 @@
+// Copyright (C) 2016 Mickaël Salaün
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 of the License.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+#![allow(deprecated)]
+
+/// `Request::call(&self, PortalFsmInit)` use `PortalFsm`
+
+use bincode::rustc_serialize::{DecodingResult, EncodingResult};
+use config::profile::check_writable_path;
+use getopts::Options;
+use jail::{Jail, JailFn};
+use self::fsm_kage::KageFsm;
+use self::fsm_portal::{PortalFsmInit, PortalFsm};
+use srv::{GetJailRequest, ManagerAction};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Sender, channel};
+use super::util;
+use unix_socket::UnixStream;
+
+mod fsm_kage;
+mod fsm_portal;
+
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+pub enum GrantAction {
+    DoGrant(GrantRequest),
+}
+
+impl GrantAction {
+    pub fn call(&self, stream: UnixStream, manager_tx: Sender<ManagerAction>, protected: &[PathBuf])
+            -> Result<(), String> {
+        match self {
+            &GrantAction::DoGrant(ref req) => req.call(PortalFsm::new(stream), manager_tx, protected),
+        }
+    }
+}
+
+/// Sent by the portal to the jail monitor as well
+#[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
+pub struct GrantRequest {
+    pub jail: u64,
+    pub path: PathBuf,
+    pub write: bool,
+}
+
+#[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
+pub struct GrantResponse {
+    pub error: Option<String>,
+}
+impl_encdec!(GrantResponse);
+
+impl GrantRequest {
+    /// Check the request path, and the writable accesses against the absolute `protected` paths
+    /// (only known by the portal and the monitors)
+    pub fn check(&self, protected: &[PathBuf]) -> Result<(), String> {
+        try!(util::check_request_path(&self.path));
+        if self.write {
+            try!(check_writable_path(&self.path, protected));
+        }
+        Ok(())
+    }
+
+    fn call(&self, machine: PortalFsmInit, manager_tx: Sender<ManagerAction>, protected: &[PathBuf])
+            -> Result<(), String> {
+        try!(self.check(protected));
+        let (response_tx, response_rx) = channel();
+        let action = ManagerAction::GetJail(GetJailRequest {
+            id: self.jail,
+            response: response_tx,
+        });
+        // TODO: Add error typing
+        match manager_tx.send(action) {
+            Ok(()) => {},
+            Err(e) => return Err(format!("Failed to send the jail request: {}", e)),
+        };
+        let response = match response_rx.recv() {
+            Ok(r) => match r.channel {
+                Some(c) => self.forward(c),
+                None => GrantResponse {
+                    error: Some(format!("No jail with the ID {}", self.jail)),
+                },
+            },
+            Err(e) => return Err(format!("Failed to receive the jail response: {}", e)),
+        };
+        machine.send_grant_response(response)
+    }
+
+    /// Forward the request to the jail monitor through its privileged channel
+    fn forward(&self, channel: Arc<Mutex<UnixStream>>) -> GrantResponse {
+        let ret = match channel.lock() {
+            Ok(mut stream) => {
+                match util::send(&mut *stream, self.clone()) {
+                    Ok(()) => util::recv(&mut *stream),
+                    Err(e) => Err(e),
+                }
+            }
+            Err(e) => Err(format!("Failed to lock the jail channel: {}", e)),
+        };
+        match ret {
+            Ok(r) => r,
+            Err(e) => GrantResponse {
+                error: Some(e),
+            },
+        }
+    }
+}
+
+/// Grant request handled by the monitor
+pub struct GrantBundle {
+    pub request: GrantRequest,
+    pub response: Sender<GrantResponse>,
+}
+
+impl fmt::Debug for GrantBundle {
+    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
+        write!(out, "GrantBundle {{ request: {:?} }}", self.request)
+    }
+}
+
+impl JailFn for GrantBundle {
+    // The portal is trusted, even for confined jails
+    fn call(&mut self, jail: &mut Jail) {
+        let ret = jail.grant_access(self.request.path.clone(), self.request.write);
+        debug!("Grant result: {:?}", ret);
+        let _ = self.response.send(GrantResponse {
+            error: ret.err(),
+        });
+    }
+}
+
+pub struct GrantKageCmd {
+    name: String,
+    opts: Options,
+}
+
+impl GrantKageCmd {
+    pub fn new() -> GrantKageCmd {
+        let mut opts = Options::new();
+        opts.optflag("h", "help", "Print this message");
+        opts.optopt("j", "jail", "Set the jail ID (cf. info --jails)", "ID");
+        opts.optflag("w", "write", "Grant write access");
+        GrantKageCmd {
+            name: "grant".to_string(),
+            opts: opts,
+        }
+    }
+}
+
+impl super::KageCommand for GrantKageCmd {
+    fn get_name<'a>(&'a self) -> &'a String {
+        &self.name
+    }
+
+    fn get_usage(&self) -> String {
+        let msg = format!("Usage for the {} command: {} --jail ID [--write] PATH",
+                          self.name, self.name);
+        format!("{}", self.opts.usage(msg.as_ref()))
+    }
+
+    fn call(&mut self, args: &Vec<String>) -> Result<(), String> {
+        let matches = match self.opts.parse(args.as_slice()) {
+            Ok(m) => m,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        if matches.opt_present("help") {
+            println!("{}", self.get_usage());
+            return Ok(());
+        }
+        let jail = match matches.opt_str("jail") {
+            Some(id) => match id.parse() {
+                Ok(i) => i,
+                Err(e) => return Err(format!("Invalid jail ID: {}", e)),
+            },
+            None => return Err("Missing jail ID".to_string()),
+        };
+        let path = match matches.free.len() {
+            1 => PathBuf::from(&matches.free[0]),
+            0 => return Err("Missing path".to_string()),
+            _ => return Err("Unknown trailing argument".to_string()),
+        };
+        let req = GrantRequest {
+            jail: jail,
+            path: path,
+            write: matches.opt_present("write"),
+        };
+        // The portal checks the protected paths
+        try!(req.check(&[]));
+
+        let machine = try!(KageFsm::new());
+        let machine = try!(machine.send_grant_request(req));
+        match try!(machine.recv_grant_response()).error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
@@ -16,10 +16,10 @@
 
 use bufstream::BufStream;
 use cmd::PortalCall;
-use cmd::util::{recv, send};
+use cmd::util::{handshake, recv, send};
 use PORTAL_SOCKET_PATH;
 use std::marker::PhantomData;
-use super::{DotRequest, DotResponse, InfoAction};
+use super::{DotRequest, DotResponse, InfoAction, JailsRequest, JailsResponse};
 use unix_socket::UnixStream;
 
 macro_rules! fsm_next {
@@ -38,6 +38,8 @@ mod state {
     pub struct Init;
     #[allow(dead_code)]
     pub struct RecvDot;
+    #[allow(dead_code)]
+    pub struct RecvJails;
 }
 
 pub struct KageFsm<T> {
@@ -49,10 +51,11 @@ pub struct KageFsm<T> {
 impl KageFsm<state::Init> {
     pub fn new() -> Result<KageFsm<state::Init>, String> {
         let server = PORTAL_SOCKET_PATH;
-        let bstream = match UnixStream::connect(&server) {
+        let mut bstream = match UnixStream::connect(&server) {
             Ok(s) => BufStream::new(s),
             Err(e) => return Err(format!("Failed to connect: {}", e)),
         };
+        let _ = try!(handshake(&mut bstream));
         Ok(KageFsm {
             bstream: bstream,
             _state: PhantomData,
@@ -64,6 +67,13 @@ impl KageFsm<state::Init> {
         try!(send(&mut self.bstream, action));
         Ok(fsm_next!(self))
     }
+
+    pub fn send_jails_request(mut self, req: JailsRequest)
+            -> Result<KageFsm<state::RecvJails>, String> {
+        let action = PortalCall::Info(InfoAction::ListJails(req));
+        try!(send(&mut self.bstream, action));
+        Ok(fsm_next!(self))
+    }
 }
 
 impl KageFsm<state::RecvDot> {
@@ -71,3 +81,9 @@ impl KageFsm<state::RecvDot> {
         recv(&mut self.bstream)
     }
 }
+
+impl KageFsm<state::RecvJails> {
+    pub fn recv_jails_response(mut self) -> Result<JailsResponse, String> {
+        recv(&mut self.bstream)
+    }
+}
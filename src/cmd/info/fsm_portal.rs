@@ -17,7 +17,7 @@
 use bufstream::BufStream;
 use cmd::util::send;
 use std::marker::PhantomData;
-use super::DotResponse;
+use super::{DotResponse, JailsResponse};
 use unix_socket::UnixStream;
 
 // Private states
@@ -46,4 +46,10 @@ impl PortalFsm<state::Init> {
         try!(send(&mut bstream, response));
         Ok(())
     }
+
+    pub fn send_jails_response(self, response: JailsResponse) -> Result<(), String> {
+        let mut bstream = BufStream::new(self.stream);
+        try!(send(&mut bstream, response));
+        Ok(())
+    }
 }
@@ -20,7 +20,7 @@ use bincode::rustc_serialize::{DecodingResult, EncodingResult};
 use getopts::Options;
 use self::fsm_kage::KageFsm;
 use self::fsm_portal::{PortalFsmInit, PortalFsm};
-use srv::{GetDotRequest, ManagerAction};
+use srv::{GetDotRequest, ListJailsRequest, ManagerAction};
 use std::fs::File;
 use std::io::Write;
 use std::path::Path;
@@ -33,12 +33,14 @@ mod fsm_portal;
 #[derive(Debug, RustcDecodable, RustcEncodable)]
 pub enum InfoAction {
     GetDot(DotRequest),
+    ListJails(JailsRequest),
 }
 
 impl InfoAction {
     pub fn call(&self, stream: UnixStream, manager_tx: Sender<ManagerAction>) -> Result<(), String> {
         match self {
             &InfoAction::GetDot(ref req) => req.call(PortalFsm::new(stream), manager_tx),
+            &InfoAction::ListJails(ref req) => req.call(PortalFsm::new(stream), manager_tx),
         }
     }
 }
@@ -72,6 +74,41 @@ impl DotRequest {
     }
 }
 
+#[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
+pub struct JailDesc {
+    pub id: u64,
+    pub name: String,
+}
+
+#[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
+pub struct JailsResponse {
+    pub jails: Vec<JailDesc>,
+}
+impl_encdec!(JailsResponse);
+
+#[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
+pub struct JailsRequest;
+
+impl JailsRequest {
+    fn call(&self, machine: PortalFsmInit, manager_tx: Sender<ManagerAction>) -> Result<(), String> {
+        let (response_tx, response_rx) = channel();
+        let action = ManagerAction::ListJails(ListJailsRequest {
+            response: response_tx,
+        });
+        // TODO: Add error typing
+        match manager_tx.send(action) {
+            Ok(()) => {},
+            Err(e) => return Err(format!("Failed to send the jails request: {}", e)),
+        };
+        let jails = match response_rx.recv() {
+            Ok(r) => r.jails.into_iter().map(|(id, name)| JailDesc { id: id, name: name }).collect(),
+            Err(e) => return Err(format!("Failed to receive the jails response: {}", e)),
+        };
+        try!(machine.send_jails_response(JailsResponse { jails: jails }));
+        Ok(())
+    }
+}
+
 pub struct InfoKageCmd {
     name: String,
     opts: Options,
@@ -82,6 +119,7 @@ impl InfoKageCmd {
         let mut opts = Options::new();
         opts.optflag("h", "help", "Print this message");
         opts.optflag("d", "dot", "Export the StemFlow graph to DOT");
+        opts.optflag("j", "jails", "List the running jails");
         opts.optopt("o", "output", "Write the information to a file", "PATH");
         InfoKageCmd {
             name: "info".to_string(),
@@ -110,6 +148,15 @@ impl InfoKageCmd {
             }
         }
     }
+
+    pub fn do_jails() -> Result<(), String> {
+        let machine = try!(KageFsm::new());
+        let machine = try!(machine.send_jails_request(JailsRequest));
+        for jail in try!(machine.recv_jails_response()).jails.iter() {
+            println!("{}\t{}", jail.id, jail.name);
+        }
+        Ok(())
+    }
 }
 
 impl super::KageCommand for InfoKageCmd {
@@ -137,6 +184,11 @@ impl super::KageCommand for InfoKageCmd {
             return InfoKageCmd::do_dot(matches.opt_str("output"));
         }
 
+        if matches.opt_present("jails") {
+            check_remaining!(matches);
+            return InfoKageCmd::do_jails();
+        }
+
         Err("No command".into())
     }
 }
@@ -18,11 +18,13 @@
 /// struct `PortalAck` -> `PartalRequest`
 
 use bincode::rustc_serialize::{DecodingResult, EncodingResult};
+use std::fmt;
 
 #[macro_use]
 mod util;
 
 // Commands
+pub mod grant;
 pub mod info;
 mod mount;
 mod run;
@@ -39,6 +41,7 @@ pub trait KageCommand {
 pub enum PortalCall {
     Run(run::RunAction),
     Info(info::InfoAction),
+    Grant(grant::GrantAction),
 }
 impl_encdec!(PortalCall);
 
@@ -61,8 +64,22 @@ pub enum PortalRequest {
     CreateTty,
 }
 
+/// Monitor request sent to the jail with the client state machine to respond to
+pub struct MonitorBundle<T, M> {
+    pub request: T,
+    // TODO: Remove the Option (need to revamp the JailFn::call() use)
+    pub machine: Option<M>,
+}
+
+impl<T, M> fmt::Debug for MonitorBundle<T, M> where T: fmt::Debug {
+    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
+        write!(out, "MonitorBundle {{ request: {:?} }}", self.request)
+    }
+}
+
 fn list_kage_cmds<'a>() -> Vec<Box<KageCommand + 'a>> {
     vec!(
+        Box::new(self::grant::GrantKageCmd::new()) as Box<KageCommand>,
         Box::new(self::info::InfoKageCmd::new()) as Box<KageCommand>,
         Box::new(self::mount::MountKageCmd::new()) as Box<KageCommand>,
         Box::new(self::run::RunKageCmd::new()) as Box<KageCommand>,
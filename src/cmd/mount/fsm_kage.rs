@@ -16,18 +16,28 @@
 
 /// Finite-state machine for a `KageCommand` call
 
-use bufstream::BufStream;
 use cmd::MonitorCall;
-use cmd::util::send;
+use cmd::util::{handshake, recv, send};
 use MONITOR_SOCKET_PATH;
 use std::marker::PhantomData;
-use super::{MountAction, MountRequest};
+use super::{MountAction, MountRequest, MountResponse, UnmountRequest};
 use unix_socket::UnixStream;
 
+macro_rules! fsm_next {
+    ($myself: expr) => {
+        KageFsm {
+            stream: $myself.stream,
+            _state: PhantomData,
+        }
+    }
+}
+
 // Private states
 mod state {
     #[allow(dead_code)]
     pub struct Init;
+    #[allow(dead_code)]
+    pub struct RecvAck;
 }
 
 pub struct KageFsm<T> {
@@ -35,22 +45,38 @@ pub struct KageFsm<T> {
     _state: PhantomData<T>,
 }
 
-// Dummy FSM for now, but help to keep it consistent and enforce number of actions
 impl KageFsm<state::Init> {
     pub fn new() -> Result<KageFsm<state::Init>, String> {
-        let stream = match UnixStream::connect(MONITOR_SOCKET_PATH) {
+        let mut stream = match UnixStream::connect(MONITOR_SOCKET_PATH) {
             Ok(s) => s,
             Err(e) => return Err(format!("Failed to connect: {}", e)),
         };
+        let _ = try!(handshake(&mut stream));
         Ok(KageFsm {
             stream: stream,
             _state: PhantomData,
         })
     }
 
-    pub fn send_mount(self, req: MountRequest) -> Result<(), String> {
+    pub fn send_mount(mut self, req: MountRequest) -> Result<KageFsm<state::RecvAck>, String> {
         let action = MonitorCall::Mount(MountAction::DoMount(req));
-        let mut bstream = BufStream::new(self.stream);
-        send(&mut bstream, action)
+        try!(send(&mut self.stream, action));
+        Ok(fsm_next!(self))
+    }
+
+    pub fn send_unmount(mut self, req: UnmountRequest) -> Result<KageFsm<state::RecvAck>, String> {
+        let action = MonitorCall::Mount(MountAction::DoUnmount(req));
+        try!(send(&mut self.stream, action));
+        Ok(fsm_next!(self))
+    }
+}
+
+impl KageFsm<state::RecvAck> {
+    pub fn recv_mount_response(mut self) -> Result<(), String> {
+        let response: MountResponse = try!(recv(&mut self.stream));
+        match response.error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
     }
 }
@@ -12,26 +12,51 @@
 // You should have received a copy of the GNU Lesser General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
+#![allow(deprecated)]
+
+use bincode::rustc_serialize::{DecodingResult, EncodingResult};
 use getopts::Options;
 use jail::{BindMount, Jail, JailFn};
 use self::fsm_kage::KageFsm;
+use self::fsm_monitor::MonitorFsmInit;
 use std::path::PathBuf;
 use std::sync::mpsc::Sender;
+use super::util;
+use unix_socket::UnixStream;
 
 mod fsm_kage;
+mod fsm_monitor;
 
 #[derive(Debug, RustcDecodable, RustcEncodable)]
 pub enum MountAction {
     DoMount(MountRequest),
-    //DoUnmount(MountRequest),
+    DoUnmount(UnmountRequest),
 }
 
 impl MountAction {
-    pub fn call(self, cmd_tx: Sender<Box<JailFn>>) -> Result<(), String> {
+    pub fn call(self, cmd_tx: Sender<Box<JailFn>>, client: UnixStream) -> Result<(), String> {
         let ret = match self {
             MountAction::DoMount(req) => {
                 match req.check() {
-                    Ok(_) => cmd_tx.send(Box::new(req)),
+                    Ok(_) => {
+                        let bundle = MonitorBundle {
+                            request: req,
+                            machine: Some(MonitorFsmInit::new(client)),
+                        };
+                        cmd_tx.send(Box::new(bundle) as Box<JailFn>)
+                    }
+                    Err(e) => return Err(format!("Request error: {}", e)),
+                }
+            }
+            MountAction::DoUnmount(req) => {
+                match req.check() {
+                    Ok(_) => {
+                        let bundle = MonitorBundle {
+                            request: req,
+                            machine: Some(MonitorFsmInit::new(client)),
+                        };
+                        cmd_tx.send(Box::new(bundle) as Box<JailFn>)
+                    }
                     Err(e) => return Err(format!("Request error: {}", e)),
                 }
             }
@@ -48,13 +73,14 @@ pub struct MountRequest {
     pub src: PathBuf,
     pub dst: PathBuf,
     pub write: bool,
+    pub exec: bool,
     pub parent: bool,
 }
 
-impl<'a> Into<BindMount> for &'a mut MountRequest {
+impl<'a> Into<BindMount> for &'a MountRequest {
     fn into(self) -> BindMount {
         BindMount::new(self.src.clone(), self.dst.clone())
-            .writable(self.write).from_parent(self.parent)
+            .writable(self.write).executable(self.exec).from_parent(self.parent)
     }
 }
 
@@ -67,20 +93,68 @@ impl MountRequest {
         if ! self.dst.is_absolute() {
             return Err("The mount destination is not an absolute path".to_string());
         }
-        // FIXME: Add domain transition check (cf. parent mount)
+        // The domain transition check is done by the monitor (cf. Jail::gain_bind)
         Ok(())
     }
 }
 
-impl JailFn for MountRequest {
-    fn call(&mut self, jail: &mut Jail) {
-        if jail.is_confined() {
-            warn!("Unauthorized command");
-            return;
+#[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
+pub struct MountResponse {
+    pub error: Option<String>,
+}
+impl_encdec!(MountResponse);
+
+type MonitorBundle<T> = super::MonitorBundle<T, MonitorFsmInit>;
+
+impl<T> MonitorBundle<T> {
+    fn send_response(&mut self, ret: Result<(), String>) {
+        let response = MountResponse {
+            error: ret.err(),
+        };
+        match self.machine.take() {
+            Some(m) => {
+                match m.send_mount_response(response) {
+                    Ok(()) => {}
+                    Err(e) => error!("Connection result: {:?}", e),
+                }
+            }
+            None => error!("No connection possible"),
         }
-        let ret = jail.import_bind(&self.into());
-        // TODO: Send result to client
+    }
+}
+
+impl JailFn for MonitorBundle<MountRequest> {
+    fn call(&mut self, jail: &mut Jail) {
+        let bind: BindMount = (&self.request).into();
+        let ret = if jail.is_confined() {
+            // Same policy as the access requests
+            jail.gain_bind(&bind)
+        } else {
+            jail.import_bind(&bind).map_err(|e| format!("Failed to mount: {}", e))
+        };
         debug!("Mount result: {:?}", ret);
+        self.send_response(ret);
+    }
+}
+
+#[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
+pub struct UnmountRequest {
+    pub dst: PathBuf,
+}
+
+impl UnmountRequest {
+    /// Same validation as a release request (cf. shim::ReleaseRequest)
+    pub fn check(&self) -> Result<(), String> {
+        util::check_request_path(&self.dst)
+    }
+}
+
+impl JailFn for MonitorBundle<UnmountRequest> {
+    // Giving up an access is allowed for confined jails too
+    fn call(&mut self, jail: &mut Jail) {
+        let ret = jail.release_access(&self.request.dst).map(|_| ());
+        debug!("Unmount result: {:?}", ret);
+        self.send_response(ret);
     }
 }
 
@@ -96,7 +170,9 @@ impl MountKageCmd {
         opts.optopt("s", "source", "Set the source path", "SRC");
         opts.optopt("d", "destination", "Set the destination path", "DST");
         opts.optflag("w", "write", "Set the bind mount writable");
+        opts.optflag("x", "exec", "Allow to execute files from the bind mount");
         opts.optflag("p", "parent", "Get the source from the parent domain");
+        opts.optflag("u", "unmount", "Unmount the destination path and release its access");
         MountKageCmd {
             name: "mount".to_string(),
             opts: opts,
@@ -124,8 +200,25 @@ impl super::KageCommand for MountKageCmd {
             println!("{}", self.get_usage());
             return Ok(());
         }
-        let src = get_path!(matches, "source");
         let dst = get_path!(matches, "destination");
+        if matches.opt_present("unmount") {
+            if matches.opt_present("source") || matches.opt_present("write") ||
+                    matches.opt_present("exec") || matches.opt_present("parent") {
+                return Err("Only the destination is needed to unmount".to_string());
+            }
+            check_remaining!(matches);
+            let req = UnmountRequest {
+                dst: dst,
+            };
+            match req.check() {
+                Ok(_) => {}
+                e => return e,
+            }
+            let machine = try!(KageFsm::new());
+            let machine = try!(machine.send_unmount(req));
+            return machine.recv_mount_response();
+        }
+        let src = get_path!(matches, "source");
 
         check_remaining!(matches);
 
@@ -133,6 +226,7 @@ impl super::KageCommand for MountKageCmd {
             src: src,
             dst: dst,
             write: matches.opt_present("write"),
+            exec: matches.opt_present("exec"),
             parent: matches.opt_present("parent"),
         };
         match req.check() {
@@ -141,8 +235,7 @@ impl super::KageCommand for MountKageCmd {
         }
 
         let machine = try!(KageFsm::new());
-        machine.send_mount(req)
-
-        // TODO: Add ack
+        let machine = try!(machine.send_mount(req));
+        machine.recv_mount_response()
     }
 }
@@ -15,7 +15,7 @@
 /// Finite-state machine for a `KageCommand` call
 
 use cmd::{PortalAck, PortalCall, PortalRequest};
-use cmd::util::{recv, send};
+use cmd::util::{handshake, recv, send};
 use fdpass;
 use libc;
 use PORTAL_SOCKET_PATH;
@@ -50,10 +50,11 @@ macro_rules! fsm_new {
 impl KageFsm<state::Init> {
     pub fn new() -> Result<KageFsm<state::Init>, String> {
         let server = PORTAL_SOCKET_PATH;
-        let stream = match UnixStream::connect(&server) {
+        let mut stream = match UnixStream::connect(&server) {
             Ok(s) => s,
             Err(e) => return Err(format!("Failed to connect: {}", e)),
         };
+        let _ = try!(handshake(&mut stream));
         Ok(fsm_new!(stream))
     }
 
@@ -20,7 +20,7 @@ use getopts::Options;
 use jail;
 use self::fsm_kage::KageFsm;
 use self::fsm_portal::{RequestInit, RequestFsm};
-use srv::{DomDesc, ManagerAction, NewDomRequest};
+use srv::{AddJailRequest, DelJailRequest, DomDesc, ManagerAction, NewDomRequest};
 use std::sync::mpsc::{Sender, channel};
 use super::{PortalAck, PortalRequest};
 use unix_socket::UnixStream;
@@ -46,10 +46,27 @@ pub struct RunRequest {
     pub profile: Option<String>,
     pub command: Vec<String>,
     pub stdio: bool,
+    /// Detect the accesses of static binaries with a syscall broker
+    pub seccomp: bool,
+    /// Detect the accesses of static binaries with ptrace
+    pub trace: bool,
 }
 
 impl RunRequest {
+    pub fn check(&self) -> Result<(), String> {
+        if self.seccomp && self.trace {
+            return Err("The seccomp and trace options are mutually exclusive".to_string());
+        }
+        // The watched syscalls and their registers are only known for x86_64
+        if (self.seccomp || self.trace) && ! cfg!(target_arch="x86_64") {
+            return Err("The seccomp and trace options are not available on this architecture"
+                       .to_string());
+        }
+        Ok(())
+    }
+
     fn call(&self, machine: RequestInit, manager_tx: Sender<ManagerAction>) -> Result<(), String> {
+        try!(self.check());
         let (response_tx, response_rx) = channel();
         let action = ManagerAction::NewDom(NewDomRequest {
             desc: match self.profile {
@@ -80,11 +97,15 @@ impl RunRequest {
             None => return Err("Missing executable in the command (first argument)".to_string()),
         };
 
+        let name = profile_dom.jdom.dom.name.clone();
         let mut j = jail::Jail::new(
             profile_dom.jdom,
             vec!(),
             confined
-        );
+        ).procfs(profile_dom.procfs).sysfs(profile_dom.sysfs).watch(profile_dom.watch)
+            .deny(profile_dom.deny).potential(profile_dom.potential).links(profile_dom.links)
+            .protected(profile_dom.protected).seccomp(self.seccomp)
+            .trace(self.trace).request_size_max(profile_dom.request_size_max);
 
         let ack = PortalAck {
             request: if self.stdio {
@@ -97,7 +118,7 @@ impl RunRequest {
 
         let (machine, stdio) = if self.stdio {
             let (machine, fd) = try!(machine.recv_fd());
-            // XXX: Allocate a new TTY inside the jail?
+            // The primary session TTY is relayed by the portal, other TTYs come from the jail devpts
             match jail::SessionIo::new(&fd) {
                 Ok(f) => (machine, Some(f)),
                 Err(e) => panic!("Failed to create stdio: {}", e),
@@ -111,6 +132,35 @@ impl RunRequest {
             |(i, x)| if i == 0 { None } else { Some(x.clone()) } ).collect();
 
         j.run(&exe, &args, stdio);
+
+        // Register the jail to be able to grant it new accesses
+        let jail_id = match j.take_grant_channel() {
+            Some(grant_channel) => {
+                let (response_tx, response_rx) = channel();
+                let action = ManagerAction::AddJail(AddJailRequest {
+                    name: name,
+                    channel: grant_channel,
+                    response: response_tx,
+                });
+                match manager_tx.send(action) {
+                    Ok(()) => match response_rx.recv() {
+                        Ok(r) => {
+                            info!("New jail ID: {}", r.id);
+                            Some(r.id)
+                        }
+                        Err(e) => {
+                            warn!("Failed to register the jail: {}", e);
+                            None
+                        }
+                    },
+                    Err(e) => {
+                        warn!("Failed to register the jail: {}", e);
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
         match j.get_stdio() {
             &Some(ref s) => {
                 try!(machine.send_fd(s))
@@ -120,6 +170,12 @@ impl RunRequest {
         debug!("Waiting jail to end");
         let ret = j.wait();
         debug!("Jail end: {:?}", ret);
+        match jail_id {
+            Some(id) => {
+                let _ = manager_tx.send(ManagerAction::DelJail(DelJailRequest { id: id }));
+            }
+            None => {}
+        }
         Ok(())
     }
 }
@@ -135,6 +191,8 @@ impl RunKageCmd {
         opts.optflag("h", "help", "Print this message");
         opts.optopt("p", "profile", "Use a specific profile", "NAME");
         opts.optflag("t", "tty", "Create and connect to the remote TTY");
+        opts.optflag("s", "seccomp", "Detect the accesses with a syscall broker");
+        opts.optflag("", "trace", "Detect the accesses with a (slower) syscall tracer");
         RunKageCmd {
             name: "run".to_string(),
             opts: opts,
@@ -167,8 +225,11 @@ impl super::KageCommand for RunKageCmd {
         let req = RunRequest {
             profile: profile,
             command: argi.map(|x| x.to_string()).collect(),
-            stdio: stdio
+            stdio: stdio,
+            seccomp: matches.opt_present("seccomp"),
+            trace: matches.opt_present("trace"),
         };
+        try!(req.check());
 
         let machine = try!(KageFsm::new());
         let (machine, ret) = try!(machine.send_run(req));
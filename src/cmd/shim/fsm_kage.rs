@@ -15,16 +15,22 @@
 /// Finite-state machine for a `KageCommand` call
 
 use cmd::MonitorCall;
-use cmd::util::{recv, send};
+use cmd::util::{handshake, recv, send};
+use fdpass;
 use MONITOR_SOCKET_PATH;
 use std::marker::PhantomData;
-use super::{AccessRequest, AccessResponse, ListRequest, ListResponse, ShimAction};
+use super::{AccessData, AccessRequest, AccessResponse, DomainEvent, ListRequest, ListResponse};
+use super::ReleaseRequest;
+use super::{ReleaseResponse, ShimAction};
+use tty::FileDesc;
 use unix_socket::UnixStream;
+use util::{Features, FEATURE_ACCESS_BATCH, FEATURE_SHARED_CACHE, FEATURE_SUBSCRIBE};
 
 macro_rules! fsm_next {
     ($myself: expr) => {
         KageFsm {
             stream: $myself.stream,
+            features: $myself.features,
             _state: PhantomData,
         }
     }
@@ -39,21 +45,41 @@ mod state {
     pub struct RecvList;
     #[allow(dead_code)]
     pub struct RecvAcl;
+    #[allow(dead_code)]
+    pub struct RecvRelease;
+    #[allow(dead_code)]
+    pub struct RecvEvents;
+    #[allow(dead_code)]
+    pub struct RecvCache;
 }
 
 pub struct KageFsm<T> {
     stream: UnixStream,
+    /// Supported by the monitor
+    features: Features,
     _state: PhantomData<T>,
 }
 
+impl<T> KageFsm<T> {
+    fn require(&self, feature: Features, name: &str) -> Result<(), String> {
+        if self.features.contains(feature) {
+            Ok(())
+        } else {
+            Err(format!("The monitor doesn't support {}", name))
+        }
+    }
+}
+
 impl KageFsm<state::Init> {
     pub fn new() -> Result<KageFsm<state::Init>, String> {
-        let stream = match UnixStream::connect(MONITOR_SOCKET_PATH) {
+        let mut stream = match UnixStream::connect(MONITOR_SOCKET_PATH) {
             Ok(s) => s,
             Err(e) => return Err(format!("Failed to connect: {}", e)),
         };
+        let features = try!(handshake(&mut stream));
         Ok(KageFsm {
             stream: stream,
+            features: features,
             _state: PhantomData,
         })
     }
@@ -71,6 +97,35 @@ impl KageFsm<state::Init> {
         try!(send(&mut self.stream, action));
         Ok(fsm_next!(self))
     }
+
+    pub fn send_access_batch(mut self, batch: Vec<AccessData>)
+            -> Result<KageFsm<state::RecvAcl>, String> {
+        try!(self.require(FEATURE_ACCESS_BATCH, "access batches"));
+        let action = MonitorCall::Shim(ShimAction::AccessBatch(batch));
+        try!(send(&mut self.stream, action));
+        Ok(fsm_next!(self))
+    }
+
+    pub fn send_subscribe(mut self) -> Result<KageFsm<state::RecvEvents>, String> {
+        try!(self.require(FEATURE_SUBSCRIBE, "subscriptions"));
+        let action = MonitorCall::Shim(ShimAction::Subscribe);
+        try!(send(&mut self.stream, action));
+        Ok(fsm_next!(self))
+    }
+
+    pub fn send_shared_cache_request(mut self) -> Result<KageFsm<state::RecvCache>, String> {
+        try!(self.require(FEATURE_SHARED_CACHE, "the shared cache"));
+        let action = MonitorCall::Shim(ShimAction::SharedCache);
+        try!(send(&mut self.stream, action));
+        Ok(fsm_next!(self))
+    }
+
+    pub fn send_release_request(mut self, req: ReleaseRequest)
+            -> Result<KageFsm<state::RecvRelease>, String> {
+        let action = MonitorCall::Shim(ShimAction::Release(req));
+        try!(send(&mut self.stream, action));
+        Ok(fsm_next!(self))
+    }
 }
 
 impl KageFsm<state::RecvList> {
@@ -84,3 +139,24 @@ impl KageFsm<state::RecvAcl> {
         recv(&mut self.stream)
     }
 }
+
+impl KageFsm<state::RecvRelease> {
+    pub fn recv_release_response(mut self) -> Result<ReleaseResponse, String> {
+        recv(&mut self.stream)
+    }
+}
+
+impl KageFsm<state::RecvEvents> {
+    pub fn recv_event(&mut self) -> Result<DomainEvent, String> {
+        recv(&mut self.stream)
+    }
+}
+
+impl KageFsm<state::RecvCache> {
+    pub fn recv_cache_fd(mut self) -> Result<FileDesc, String> {
+        match fdpass::recv_fd(&mut self.stream, vec!(0u8)) {
+            Ok(fd) => Ok(fd),
+            Err(e) => Err(format!("Failed to receive the shared cache FD: {}", e)),
+        }
+    }
+}
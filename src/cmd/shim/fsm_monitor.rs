@@ -13,8 +13,10 @@
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
 use cmd::util::send;
+use fdpass;
+use std::fs::File;
 use std::marker::PhantomData;
-use super::{AccessResponse, ListResponse};
+use super::{AccessResponse, ListResponse, ReleaseResponse};
 use unix_socket::UnixStream;
 
 // Private states
@@ -46,4 +48,15 @@ impl MonitorFsm<state::Init> {
     pub fn send_access_response(mut self, response: AccessResponse) -> Result<(), String> {
         send(&mut self.stream, response)
     }
+
+    pub fn send_release_response(mut self, response: ReleaseResponse) -> Result<(), String> {
+        send(&mut self.stream, response)
+    }
+
+    pub fn send_cache_fd(mut self, cache: &File) -> Result<(), String> {
+        match fdpass::send_fd(&mut self.stream, &[0], cache) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Failed to send the shared cache FD: {}", e)),
+        }
+    }
 }
@@ -17,28 +17,48 @@
 use bincode::rustc_serialize::{DecodingResult, EncodingResult};
 use getopts::Options;
 use jail::{Jail, JailFn, WORKDIR_PARENT};
-use jail::util::nest_path;
-use mnt::get_mount_writable;
+use jail::util::{canonicalize_beneath, PathFd};
+use libc::{getpid, pid_t};
+use config::profile::{exec_access, new_access};
+use mnt::{get_mount_writable, MntOps};
 use self::fsm_kage::KageFsm;
 use self::fsm_monitor::MonitorFsmInit;
+use self::shared::SharedCacheRequest;
 use std::collections::BTreeSet;
 use std::fmt;
 use std::fs;
 use std::io;
+use std::io::Read;
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::sync::mpsc::Sender;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
 use stemflow::{Action, SetAccess, FileAccess};
 use super::util;
 use unix_socket::UnixStream;
 
+pub use self::session::{SessionRequest, SessionResponse, ShimResponse, ShimSession};
+pub use self::shared::{SharedCache, SharedCacheWriter};
+
 mod fsm_kage;
 mod fsm_monitor;
+mod session;
+mod shared;
 
 #[derive(Debug, RustcDecodable, RustcEncodable)]
 pub enum ShimAction {
     Access(AccessRequest),
     List(ListRequest),
+    Release(ReleaseRequest),
+    /// Several accesses needing a single domain transition
+    AccessBatch(Vec<AccessData>),
+    /// Switch the connection to a `DomainEvent` stream
+    Subscribe,
+    /// Switch the connection to a session (cf. `ShimSession`)
+    Session,
+    /// Get the read-only file of the shared cache (cf. `SharedCache`)
+    SharedCache,
 }
 
 impl ShimAction {
@@ -68,6 +88,45 @@ impl ShimAction {
                     Err(e) => return Err(format!("Request error: {}", e)),
                 }
             }
+            ShimAction::Release(req) => {
+                match req.check() {
+                    Ok(_) => {
+                        let bundle = MonitorBundle {
+                            request: req,
+                            machine: Some(MonitorFsmInit::new(client)),
+                        };
+                        cmd_tx.send(Box::new(bundle))
+                    }
+                    Err(e) => return Err(format!("Request error: {}", e)),
+                }
+            }
+            ShimAction::AccessBatch(batch) => {
+                match check_batch(&batch) {
+                    Ok(_) => {
+                        let bundle = MonitorBundle {
+                            request: batch,
+                            machine: Some(MonitorFsmInit::new(client)),
+                        };
+                        cmd_tx.send(Box::new(bundle))
+                    }
+                    Err(e) => return Err(format!("Request error: {}", e)),
+                }
+            }
+            ShimAction::Subscribe => {
+                let (events_tx, events_rx) = channel();
+                // Not joined: stopped when the client or the jail is gone
+                let _ = thread::spawn(move || forward_events(events_rx, client));
+                cmd_tx.send(Box::new(SubscribeBundle { events: events_tx }))
+            }
+            ShimAction::SharedCache => {
+                let bundle = MonitorBundle {
+                    request: SharedCacheRequest,
+                    machine: Some(MonitorFsmInit::new(client)),
+                };
+                cmd_tx.send(Box::new(bundle))
+            }
+            // Handled by the monitor server to not hold a worker
+            ShimAction::Session => return Err("Unexpected shim session".to_string()),
         };
         match ret {
             Ok(_) => Ok(()),
@@ -76,65 +135,146 @@ impl ShimAction {
     }
 }
 
+/// Broadcasted by the monitor to the subscribed clients when the jail accesses change (e.g.
+/// domain transition, grant or release)
+#[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
+pub struct DomainEvent {
+    pub generation: u64,
+    /// All the current accesses
+    pub access: Vec<AccessData>,
+}
+impl_encdec!(DomainEvent);
+
+#[derive(Debug)]
+struct SubscribeBundle {
+    events: Sender<DomainEvent>,
+}
+
+impl JailFn for SubscribeBundle {
+    fn call(&mut self, jail: &mut Jail) {
+        jail.subscribe(self.events.clone());
+    }
+}
+
+fn forward_events(events: Receiver<DomainEvent>, mut client: UnixStream) {
+    for event in events.iter() {
+        match util::send(&mut client, event) {
+            Ok(()) => {}
+            Err(e) => {
+                debug!("Subscription closed: {}", e);
+                return;
+            }
+        }
+    }
+}
+
 #[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
 pub struct ListRequest {
     pub path: PathBuf,
+    /// Ask for the entries metadata
+    pub metadata: bool,
 }
 
 #[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
-pub struct ListResponse {
-    pub result: Vec<PathBuf>,
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+    Other,
 }
-impl_encdec!(ListResponse);
 
-pub struct MonitorBundle<T> {
-    pub request: T,
-    // TODO: Remove the Option (need to revamp the JailFn::call() use)
-    pub machine: Option<MonitorFsmInit>,
+#[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
+pub struct EntryMetadata {
+    pub kind: EntryKind,
+    pub size: u64,
+    /// Last modification time (seconds since the Epoch)
+    pub mtime: i64,
 }
 
-impl<T> fmt::Debug for MonitorBundle<T> where T: fmt::Debug {
-    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
-        write!(out, "MonitorBundle {{ request: {:?} }}", self.request)
+impl<'a> From<&'a fs::Metadata> for EntryMetadata {
+    fn from(other: &'a fs::Metadata) -> EntryMetadata {
+        let file_type = other.file_type();
+        EntryMetadata {
+            kind: if file_type.is_file() {
+                EntryKind::File
+            } else if file_type.is_dir() {
+                EntryKind::Directory
+            } else if file_type.is_symlink() {
+                EntryKind::Symlink
+            } else {
+                EntryKind::Other
+            },
+            size: other.len(),
+            mtime: other.mtime(),
+        }
     }
 }
 
+#[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
+pub struct ListResponse {
+    pub error: Option<String>,
+    pub result: Vec<PathBuf>,
+    /// Metadata of each `result` entry, if requested
+    pub metadata: Option<Vec<EntryMetadata>>,
+}
+impl_encdec!(ListResponse);
+
+pub type MonitorBundle<T> = super::MonitorBundle<T, MonitorFsmInit>;
+
 impl ListRequest {
     pub fn check(&self) -> Result<(), String> {
-        util::check_parent_path(&self.path)
+        util::check_request_path(&self.path)
     }
-}
 
-impl MonitorBundle<ListRequest> {
-    fn list<T>(&self, dir: T) -> io::Result<Vec<PathBuf>> where T: AsRef<Path> {
+    /// List the parent directory, without following any symlink, and only keep the entries
+    /// reachable from the current domain, or leading to a reachable path, if the jail is confined
+    fn list(&self, jail: &Jail) -> io::Result<Vec<(PathBuf, fs::Metadata)>> {
+        self.list_beneath(WORKDIR_PARENT, jail)
+    }
+
+    fn list_beneath<T>(&self, root: T, jail: &Jail) -> io::Result<Vec<(PathBuf, fs::Metadata)>>
+            where T: AsRef<Path> {
+        let dir = try!(PathFd::open_beneath(root, &self.path));
         let mut ret = vec!();
         for file in try!(fs::read_dir(&dir)) {
-            match try!(file).path().strip_prefix(&dir) {
-                Ok(d) => ret.push(d.to_path_buf()),
-                Err(e) => warn!("Failed to get relative path: {}", e),
+            let file = try!(file);
+            let name = PathBuf::from(file.file_name());
+            if jail.is_confined() && ! jail.leads_to_reachable(self.path.join(&name)) {
+                continue;
             }
+            ret.push((name, try!(file.metadata())));
         }
         Ok(ret)
     }
+
+    pub fn respond(&self, jail: &Jail) -> ListResponse {
+        let res = match util::check_parent_path(&self.path, jail.denied_paths()) {
+            Ok(()) => self.list(jail),
+            Err(e) => Err(io::Error::new(io::ErrorKind::PermissionDenied, e)),
+        };
+        let (res, error) = match res {
+            Ok(r) => (r, None),
+            Err(e) => {
+                warn!("Failed to read directory: {}", e);
+                (vec!(), Some(format!("Failed to read directory: {}", e)))
+            }
+        };
+        ListResponse {
+            error: error,
+            metadata: if self.metadata {
+                Some(res.iter().map(|&(_, ref x)| x.into()).collect())
+            } else {
+                None
+            },
+            result: res.into_iter().map(|(x, _)| x).collect(),
+        }
+    }
 }
 
 impl JailFn for MonitorBundle<ListRequest> {
     // TODO: Spawn a dedicated thread
     fn call(&mut self, jail: &mut Jail) {
-        if jail.is_confined() {
-            warn!("Unauthorized command");
-            return;
-        }
-        let res = self.list(nest_path(WORKDIR_PARENT, &self.request.path));
-        let res = ListResponse {
-            result: match res {
-                Ok(r) => r,
-                Err(e) => {
-                    warn!("Failed to read directory: {}", e);
-                    vec!()
-                }
-            }
-        };
+        let res = self.request.respond(jail);
         match self.machine.take() {
             Some(m) => {
                 match m.send_list_response(res) {
@@ -147,16 +287,62 @@ impl JailFn for MonitorBundle<ListRequest> {
     }
 }
 
-/// An `AccessData` always imply at least a read access
+/// An `AccessData` always imply at least a read access, except a write-only one
 #[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
 pub struct AccessData {
     pub path: PathBuf,
     pub write: bool,
+    pub exec: bool,
+    /// Drop directory (cf. BindMount::write_only): only a write access, never requested
+    pub write_only: bool,
+}
+
+impl AccessData {
+    pub fn check(&self) -> Result<(), String> {
+        util::check_request_path(&self.path)
+    }
+
+    /// Get the ACL to ask the jail for, if not denied
+    ///
+    /// The policy is evaluated on the canonical path, resolved from the parent root: a symlink
+    /// chain crossing a protected or denied path is refused.
+    fn acl(&self, jail: &Jail) -> Result<Vec<FileAccess>, AccessResult> {
+        if ! self.path.is_absolute() {
+            return Err(AccessResult::Malformed("The path is not absolute".to_string()));
+        }
+        if self.write_only {
+            return Err(AccessResult::Malformed("A write-only access can't be requested".to_string()));
+        }
+        let allow = |x: &Path| util::check_parent_path(x, jail.denied_paths()).is_ok();
+        let path = match canonicalize_beneath(WORKDIR_PARENT, &self.path, allow) {
+            Ok(p) => p,
+            Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                return Err(AccessResult::ProtectedPath(self.path.clone()));
+            }
+            Err(e) => {
+                return Err(AccessResult::Malformed(format!("Failed to resolve {}: {}",
+                                                           self.path.display(), e)));
+            }
+        };
+        if path != self.path {
+            debug!("Resolved {} to {}", self.path.display(), path.display());
+        }
+        new_access(&path, self.write, self.exec).map_err(|_| {
+            error!("Failed to create an ACL for {:?}", self);
+            AccessResult::Malformed("Invalid access".to_string())
+        })
+    }
 }
 
 impl Into<Vec<Arc<FileAccess>>> for AccessData {
     fn into(self) -> Vec<Arc<FileAccess>> {
         let path = Arc::new(self.path);
+        if self.write_only {
+            return vec!(Arc::new(FileAccess {
+                path: path,
+                action: Action::Write,
+            }));
+        }
         let mut ret = vec!(Arc::new(FileAccess {
             path: path.clone(),
             action: Action::Read,
@@ -167,6 +353,12 @@ impl Into<Vec<Arc<FileAccess>>> for AccessData {
                 action: Action::Write,
             }));
         }
+        if self.exec {
+            match exec_access(&*path) {
+                Ok(a) => ret.push(Arc::new(a)),
+                Err(()) => warn!("Failed to create an execute access for {}", path.display()),
+            }
+        }
         ret
     }
 }
@@ -178,16 +370,166 @@ impl Into<Vec<Arc<FileAccess>>> for AccessData {
 pub struct AccessCache {
     granted: BTreeSet<Arc<FileAccess>>,
     denied: BTreeSet<Arc<FileAccess>>,
+    /// Connection shared by the process, opened on the first request
+    session: Option<Arc<ShimSession>>,
+    /// Process owning `session`, a forked child must not share its connection
+    session_pid: Option<pid_t>,
+    /// The monitor doesn't accept a session: one connection per request
+    no_session: bool,
+    /// Last known jail generation, the cache is stale if it differs
+    generation: Option<u64>,
+    /// Domain changes pushed by the monitor (cf. `ShimKageCmd::subscribe()`)
+    events: Option<Receiver<DomainEvent>>,
+    /// Snapshot shared by all the jailed processes (cf. `SharedCache`)
+    shared: Option<Arc<SharedCache>>,
+}
+
+fn default_granted() -> BTreeSet<Arc<FileAccess>> {
+    let mut def_granted = BTreeSet::new();
+    def_granted.insert_dedup_all(["/dev", "/proc", "/tmp"].into_iter().flat_map(|p|
+        FileAccess::new_rw(p.into()).unwrap()).map(|x| Arc::new(x)));
+    def_granted
 }
 
 impl AccessCache {
     pub fn new() -> AccessCache {
-        let mut def_granted = BTreeSet::new();
-        def_granted.insert_dedup_all(["/dev", "/proc", "/tmp"].into_iter().flat_map(|p|
-            FileAccess::new_rw(p.into()).unwrap()).map(|x| Arc::new(x)));
         AccessCache {
-            granted: def_granted,
+            granted: default_granted(),
             denied: BTreeSet::new(),
+            session: None,
+            session_pid: None,
+            no_session: false,
+            generation: None,
+            events: None,
+            shared: None,
+        }
+    }
+
+    pub fn session(mut self, session: Arc<ShimSession>) -> AccessCache {
+        self.session = Some(session);
+        self.session_pid = Some(unsafe { getpid() });
+        self
+    }
+
+    /// Get the session of the current process, or connect a new one
+    fn get_session(&mut self) -> Option<Arc<ShimSession>> {
+        let pid = unsafe { getpid() };
+        if self.session_pid != Some(pid) {
+            self.session = None;
+        }
+        if self.session.is_none() && ! self.no_session {
+            match ShimSession::connect() {
+                Ok(s) => {
+                    self.session = Some(Arc::new(s));
+                    self.session_pid = Some(pid);
+                }
+                Err(e) => {
+                    warn!("Failed to open a shim session: {}", e);
+                    self.no_session = true;
+                }
+            }
+        }
+        self.session.clone()
+    }
+
+    pub fn subscribe(mut self, events: Receiver<DomainEvent>) -> AccessCache {
+        self.events = Some(events);
+        self
+    }
+
+    pub fn shared(mut self, shared: Arc<SharedCache>) -> AccessCache {
+        self.shared = Some(shared);
+        self
+    }
+
+    /// Drop the cached accesses if the jail changed since the last response or event, because
+    /// the denied ones may be reachable now and the granted ones may be released
+    fn check_generation(&mut self, generation: u64) {
+        if self.generation != Some(generation) {
+            self.granted = default_granted();
+            self.denied.clear();
+            self.generation = Some(generation);
+        }
+    }
+
+    /// Replace the granted accesses with those of a newer jail state
+    fn update(&mut self, event: DomainEvent) {
+        if self.generation.map(|x| x >= event.generation).unwrap_or(false) {
+            return;
+        }
+        self.check_generation(event.generation);
+        let mut granted = default_granted();
+        granted.insert_dedup_all(event.access.into_iter().flat_map(|x| {
+            let i: Vec<Arc<FileAccess>> = x.into();
+            i.into_iter()
+        }));
+        self.granted = granted;
+    }
+
+    /// Cache the monitor response to the `acl` request
+    fn update_response(&mut self, acl: Vec<Arc<FileAccess>>, response: AccessResponse)
+            -> Result<(), String> {
+        self.check_generation(response.generation);
+        match response.all_access {
+            Some(all) => {
+                let _ = self.granted.insert_dedup_all(all.into_iter().flat_map(|x| {
+                    let i: Vec<Arc<FileAccess>> = x.into();
+                    i.into_iter()
+                }));
+            }
+            None => {}
+        }
+        match response.result {
+            AccessResult::Granted(new_access) => {
+                let _ = self.granted.insert_dedup_all(new_access.into_iter().flat_map(|x| {
+                    let i: Vec<Arc<FileAccess>> = x.into();
+                    i.into_iter()
+                }));
+                Ok(())
+            }
+            AccessResult::AlreadyAllowed => {
+                let _ = self.granted.insert_dedup_all(acl.into_iter());
+                Ok(())
+            }
+            // Transient error, the request may succeed later
+            AccessResult::MountFailed(e) => Err(e),
+            AccessResult::Malformed(e) => {
+                for access in acl.into_iter() {
+                    let _ = self.denied.insert(access);
+                }
+                Err(e)
+            }
+            AccessResult::NoReachableDomain | AccessResult::ProtectedPath(_) => {
+                for access in acl.into_iter() {
+                    let _ = self.denied.insert(access);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Apply the pending domain events and the shared snapshot, if any
+    fn refresh(&mut self) {
+        let mut last = None;
+        match self.events {
+            Some(ref events) => {
+                while let Ok(event) = events.try_recv() {
+                    last = Some(event);
+                }
+            }
+            None => {}
+        }
+        match last {
+            Some(event) => self.update(event),
+            None => {}
+        }
+        let snapshot = match self.shared {
+            Some(ref shared) => shared.read(self.generation),
+            None => None,
+        };
+        match snapshot {
+            Some(event) => self.update(event),
+            None => {}
         }
     }
 }
@@ -200,61 +542,170 @@ pub struct AccessRequest {
 
 impl AccessRequest {
     pub fn check(&self) -> Result<(), String> {
-        util::check_parent_path(&self.data.path)
+        self.data.check()
     }
 
-    pub fn new<T>(path: T, write: bool) -> AccessRequest where T: AsRef<Path> {
+    pub fn new<T>(path: T, write: bool, exec: bool) -> AccessRequest where T: AsRef<Path> {
         AccessRequest {
             data: AccessData {
                 path: path.as_ref().to_path_buf(),
                 write: write,
+                exec: exec,
+                write_only: false,
             },
             get_all_access: false,
         }
     }
+
+    /// Transition the jail to a domain allowing this access, if any
+    pub fn gain_access(&self, jail: &mut Jail) -> AccessResult {
+        let result = match self.data.acl(jail) {
+            Ok(acl) => jail.gain_access(acl),
+            Err(e) => e,
+        };
+        debug!("{}: {}", self.data.path.display(), result);
+        result
+    }
+
+    pub fn respond(&self, jail: &mut Jail) -> AccessResponse {
+        AccessResponse {
+            result: self.gain_access(jail),
+            all_access: if self.get_all_access {
+                // TODO: Use FileAccess
+                Some(jail.current_access())
+            } else {
+                None
+            },
+            generation: jail.generation(),
+        }
+    }
+}
+
+/// Outcome of an access request
+#[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
+pub enum AccessResult {
+    /// Transition to a new domain, with the new accesses
+    Granted(Vec<AccessData>),
+    /// The current domain already allows the access
+    AlreadyAllowed,
+    /// No domain allowing the access is reachable from the current one
+    NoReachableDomain,
+    /// Denied, released or protected (e.g. /proc) path
+    ProtectedPath(PathBuf),
+    /// The domain transition was rolled back
+    MountFailed(String),
+    /// Invalid request (e.g. relative path)
+    Malformed(String),
+}
+
+impl AccessResult {
+    pub fn is_allowed(&self) -> bool {
+        match *self {
+            AccessResult::Granted(_) | AccessResult::AlreadyAllowed => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for AccessResult {
+    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AccessResult::Granted(ref access) => {
+                let paths: Vec<_> = access.iter().map(|x| x.path.to_string_lossy()).collect();
+                write!(out, "Access granted to {}", paths.join(", "))
+            }
+            AccessResult::AlreadyAllowed => write!(out, "Access already allowed"),
+            AccessResult::NoReachableDomain => write!(out, "No domain reachable"),
+            AccessResult::ProtectedPath(ref path) => {
+                write!(out, "Access to {} is denied", path.display())
+            }
+            AccessResult::MountFailed(ref e) => write!(out, "Domain transition failed: {}", e),
+            AccessResult::Malformed(ref e) => write!(out, "Invalid request: {}", e),
+        }
+    }
 }
 
 #[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
 pub struct AccessResponse {
-    pub new_access: Vec<AccessData>,
+    pub result: AccessResult,
+    /// All the current accesses, if requested
+    pub all_access: Option<Vec<AccessData>>,
+    /// Jail generation after this request (cf. `DomainEvent`)
+    pub generation: u64,
 }
 impl_encdec!(AccessResponse);
 
 impl JailFn for MonitorBundle<AccessRequest> {
     fn call(&mut self, jail: &mut Jail) {
-        let acl = if self.request.data.write {
-            FileAccess::new_rw(self.request.data.path.clone())
-        } else {
-            FileAccess::new_ro(self.request.data.path.clone())
-        };
-        let response = AccessResponse {
-            new_access: {
-                let ret = match acl {
-                    Ok(acl) => {
-                        match jail.gain_access(acl) {
-                            Ok(new_access) => {
-                                debug!("Access granted to {:?}", new_access);
-                                new_access
-                            }
-                            Err(()) => {
-                                debug!("Access denied");
-                                vec!()
-                            }
-                        }
-                    }
-                    Err(()) => {
-                        error!("Failed to create an ACL for {:?}", self.request.data);
-                        vec!()
-                    }
-                };
-                if self.request.get_all_access {
-                    // TODO: Use FileAccess
-                    jail.as_ref().binds.iter().map(|x| x.into()).collect()
-                } else {
-                    ret
+        let response = self.request.respond(jail);
+        match self.machine.take() {
+            Some(m) => {
+                match m.send_access_response(response) {
+                    Ok(()) => {}
+                    Err(e) => error!("Connection result: {:?}", e),
                 }
             }
-        };
+            None => error!("No connection possible"),
+        }
+    }
+}
+
+/// Parse a prefetch file, ignoring the blank lines and the comments (starting with '#')
+fn read_prefetch<T>(file: T, write: bool, exec: bool) -> Result<Vec<AccessData>, String>
+        where T: AsRef<Path> {
+    let mut content = String::new();
+    match fs::File::open(file.as_ref()).and_then(|mut f| f.read_to_string(&mut content)) {
+        Ok(_) => {}
+        Err(e) => return Err(format!("Failed to read {}: {}", file.as_ref().display(), e)),
+    }
+    Ok(content.lines().map(|x| x.trim())
+        .filter(|x| ! x.is_empty() && ! x.starts_with('#'))
+        .map(|x| AccessData {
+            path: PathBuf::from(x),
+            write: write,
+            exec: exec,
+            write_only: false,
+        }).collect())
+}
+
+pub fn check_batch(batch: &[AccessData]) -> Result<(), String> {
+    if batch.is_empty() {
+        return Err("Empty access batch".to_string());
+    }
+    for data in batch.iter() {
+        try!(data.check());
+    }
+    Ok(())
+}
+
+/// Transition the jail to a domain allowing all the `batch` accesses, or none of them
+pub fn respond_batch(batch: &[AccessData], jail: &mut Jail) -> AccessResponse {
+    let mut acl = vec!();
+    let mut result = None;
+    for data in batch.iter() {
+        match data.acl(jail) {
+            Ok(a) => acl.extend(a.into_iter()),
+            Err(e) => {
+                result = Some(e);
+                break;
+            }
+        }
+    }
+    let result = match result {
+        Some(r) => r,
+        None => jail.gain_access(acl),
+    };
+    debug!("Access batch: {}", result);
+    AccessResponse {
+        result: result,
+        all_access: None,
+        generation: jail.generation(),
+    }
+}
+
+impl JailFn for MonitorBundle<Vec<AccessData>> {
+    fn call(&mut self, jail: &mut Jail) {
+        let response = respond_batch(&self.request, jail);
         match self.machine.take() {
             Some(m) => {
                 match m.send_access_response(response) {
@@ -267,6 +718,53 @@ impl JailFn for MonitorBundle<AccessRequest> {
     }
 }
 
+#[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
+pub struct ReleaseRequest {
+    pub path: PathBuf,
+}
+
+impl ReleaseRequest {
+    pub fn check(&self) -> Result<(), String> {
+        util::check_request_path(&self.path)
+    }
+
+    // Giving up an access is allowed for confined jails too
+    pub fn respond(&self, jail: &mut Jail) -> ReleaseResponse {
+        match jail.release_access(&self.path) {
+            Ok(released) => ReleaseResponse {
+                released: released,
+                error: None,
+            },
+            Err(e) => ReleaseResponse {
+                released: vec!(),
+                error: Some(e),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
+pub struct ReleaseResponse {
+    pub released: Vec<AccessData>,
+    pub error: Option<String>,
+}
+impl_encdec!(ReleaseResponse);
+
+impl JailFn for MonitorBundle<ReleaseRequest> {
+    fn call(&mut self, jail: &mut Jail) {
+        let response = self.request.respond(jail);
+        match self.machine.take() {
+            Some(m) => {
+                match m.send_release_response(response) {
+                    Ok(()) => {}
+                    Err(e) => error!("Connection result: {:?}", e),
+                }
+            }
+            None => error!("No connection possible"),
+        }
+    }
+}
+
 
 pub struct ShimKageCmd {
     name: String,
@@ -278,38 +776,113 @@ impl ShimKageCmd {
         let mut opts = Options::new();
         opts.optflag("h", "help", "Print this message");
         opts.optopt("l", "list", "List a directory from the parent", "DIR");
+        opts.optflag("m", "metadata", "Show the type, size and modification time of the listed files");
         opts.optopt("a", "access", "Ask to access a path from the parent", "PATH");
+        opts.optopt("p", "prefetch", "Ask to access all the paths listed in a file", "FILE");
         opts.optflag("w", "write", "Ask for write access");
+        opts.optflag("x", "exec", "Ask for execute access");
+        opts.optopt("r", "release", "Give up the access to a bind mounted path", "PATH");
         ShimKageCmd {
             name: "shim".to_string(),
             opts: opts,
         }
     }
 
-    pub fn list_directory<T>(path: T) -> Result<(), String> where T: AsRef<Path> {
+    pub fn list_directory<T>(path: T, metadata: bool) -> Result<(), String> where T: AsRef<Path> {
         let req = ListRequest {
             path: path.as_ref().to_path_buf(),
+            metadata: metadata,
         };
         try!(req.check());
 
         let machine = try!(KageFsm::new());
         let machine = try!(machine.send_list_request(req));
-        let list = try!(machine.recv_list_response()).result;
+        let response = try!(machine.recv_list_response());
+        match response.error {
+            Some(e) => return Err(e),
+            None => {}
+        }
+        let list = response.result.into_iter().map(|x| x.to_string_lossy().into_owned());
         // TODO: Add an output Writer like do_dot()
-        println!("{}", list.into_iter().map(|x| x.to_string_lossy().into_owned()).collect::<Vec<_>>().connect("\n"));
+        let list: Vec<_> = match response.metadata {
+            Some(metadata) => list.zip(metadata.into_iter()).map(|(name, md)| {
+                format!("{:?}\t{}\t{}\t{}", md.kind, md.size, md.mtime, name)
+            }).collect(),
+            None => list.collect(),
+        };
+        println!("{}", list.connect("\n"));
         Ok(())
     }
 
-    /// @get_all_access: Get back a list of all current access
-    pub fn ask_access(request: AccessRequest) -> Result<Vec<AccessData>, String> {
+    /// Get the full response, including the jail generation
+    pub fn ask_access_response(request: AccessRequest) -> Result<AccessResponse, String> {
         try!(request.check());
         let machine = try!(KageFsm::new());
         let machine = try!(machine.send_access_request(request));
-        Ok(try!(machine.recv_access_response()).new_access)
+        machine.recv_access_response()
+    }
+
+    pub fn ask_access(request: AccessRequest) -> Result<AccessResult, String> {
+        let response = try!(ShimKageCmd::ask_access_response(request));
+        Ok(response.result)
+    }
+
+    /// Receive the domain changes of the jail, starting with its current state
+    pub fn subscribe() -> Result<Receiver<DomainEvent>, String> {
+        let machine = try!(KageFsm::new());
+        let mut machine = try!(machine.send_subscribe());
+        let (events_tx, events_rx) = channel();
+        // Not joined: stopped when the monitor or the receiver is gone
+        let _ = thread::spawn(move || {
+            loop {
+                match machine.recv_event() {
+                    Ok(event) => if events_tx.send(event).is_err() {
+                        return;
+                    },
+                    Err(e) => {
+                        debug!("Subscription closed: {}", e);
+                        return;
+                    }
+                }
+            }
+        });
+        Ok(events_rx)
+    }
+
+    /// Ask for all the `batch` accesses with a single domain transition
+    pub fn ask_access_batch(batch: Vec<AccessData>) -> Result<AccessResult, String> {
+        try!(check_batch(&batch));
+        let machine = try!(KageFsm::new());
+        let machine = try!(machine.send_access_batch(batch));
+        let response = try!(machine.recv_access_response());
+        Ok(response.result)
+    }
+
+    /// Read a list of paths (one per line) to access, e.g. to warm up a jail before running a
+    /// program
+    pub fn prefetch_access<T>(file: T, write: bool, exec: bool) -> Result<AccessResult, String>
+            where T: AsRef<Path> {
+        ShimKageCmd::ask_access_batch(try!(read_prefetch(file, write, exec)))
+    }
+
+    /// Detach a bind mount from the jail and forbid any future access to it
+    pub fn release_access<T>(path: T) -> Result<Vec<AccessData>, String> where T: AsRef<Path> {
+        let req = ReleaseRequest {
+            path: path.as_ref().to_path_buf(),
+        };
+        try!(req.check());
+        let machine = try!(KageFsm::new());
+        let machine = try!(machine.send_release_request(req));
+        let response = try!(machine.recv_release_response());
+        match response.error {
+            Some(e) => Err(e),
+            None => Ok(response.released),
+        }
     }
 
     pub fn cache_ask_access(access_data: AccessData, cache: &mut AccessCache)
             -> Result<(), String> {
+        cache.refresh();
         let acl: Vec<Arc<FileAccess>> = access_data.clone().into();
         // The denied cache must exactly match the request to not ignore a valid (nested) one
         if ! acl.iter().find(|&x| ! ( cache.granted.is_allowed(x) || cache.denied.contains(x) )).is_some() {
@@ -317,19 +890,20 @@ impl ShimKageCmd {
         }
         // TODO: Merge `found_mount` below
         let found_mount = match get_mount_writable(&access_data.path, access_data.write) {
+            // A noexec mount point doesn't fulfill an execute request
+            Some(ref m) if access_data.exec && m.mntops.iter().any(|x| match *x {
+                MntOps::Exec(false) => true,
+                _ => false,
+            }) => false,
             Some(m) => if m.file != Path::new("/") {
-                let new_access = if access_data.write {
-                    FileAccess::new_rw(m.file)
-                } else {
-                    FileAccess::new_ro(m.file)
-                };
-                match new_access {
-                    Ok(a) => {
-                        let _ = cache.granted.insert_dedup_all(a.into_iter().map(|x| Arc::new(x)));
-                        true
-                    }
-                    Err(_) => false,
-                }
+                let new_access: Vec<Arc<FileAccess>> = AccessData {
+                    path: m.file,
+                    write: access_data.write,
+                    exec: access_data.exec,
+                    write_only: false,
+                }.into();
+                let _ = cache.granted.insert_dedup_all(new_access.into_iter());
+                true
             } else {
                 false
             },
@@ -343,30 +917,19 @@ impl ShimKageCmd {
                 get_all_access: cache.granted.is_empty(),
             };
 
-            match ShimKageCmd::ask_access(req) {
-                Ok(new_access) => {
-                    if new_access.is_empty() {
-                        // Access denied
-                        for access in acl.into_iter() {
-                            let _ = cache.denied.insert(access);
-                        }
-                    } else {
-                        // New access
-                        let _ = cache.granted.insert_dedup_all(new_access.into_iter().flat_map(|x| {
-                            let i: Vec<Arc<FileAccess>> = x.into();
-                            i.into_iter()
-                        }));
+            let ret = match cache.get_session() {
+                Some(s) => {
+                    let ret = s.ask_access_response(req);
+                    if ret.is_err() {
+                        // Reconnect on the next request in case the session is broken
+                        cache.session = None;
                     }
-                    Ok(())
-                }
-                Err(e) => {
-                    // Cache the request to not replay it
-                    for access in acl.into_iter() {
-                        let _ = cache.denied.insert(access);
-                    }
-                    Err(e)
+                    ret
                 }
-            }
+                None => ShimKageCmd::ask_access_response(req),
+            };
+            // A transport error is transient (e.g. a busy monitor), the request may succeed later
+            ret.and_then(|response| cache.update_response(acl, response))
             // TODO: Cleanup included requests if needed (not a big deal because StemJail
             // hints help to get the big picture).
         }
@@ -396,7 +959,8 @@ impl super::KageCommand for ShimKageCmd {
         match matches.opt_str("list") {
             Some(path) => {
                 check_remaining!(matches);
-                return ShimKageCmd::list_directory(PathBuf::from(path));
+                let metadata = matches.opt_present("metadata");
+                return ShimKageCmd::list_directory(PathBuf::from(path), metadata);
             }
             None => {}
         }
@@ -405,9 +969,41 @@ impl super::KageCommand for ShimKageCmd {
             Some(path) => {
                 check_remaining!(matches);
                 return match ShimKageCmd::ask_access(
-                        AccessRequest::new(path, matches.opt_present("write"))) {
+                        AccessRequest::new(path, matches.opt_present("write"),
+                            matches.opt_present("exec"))) {
+                    Ok(ref r) if r.is_allowed() => {
+                        println!("{}", r);
+                        Ok(())
+                    }
+                    Ok(r) => Err(r.to_string()),
+                    Err(e) => Err(e),
+                }
+            }
+            None => {}
+        }
+
+        match matches.opt_str("prefetch") {
+            Some(file) => {
+                check_remaining!(matches);
+                return match ShimKageCmd::prefetch_access(PathBuf::from(file),
+                        matches.opt_present("write"), matches.opt_present("exec")) {
+                    Ok(ref r) if r.is_allowed() => {
+                        println!("{}", r);
+                        Ok(())
+                    }
+                    Ok(r) => Err(r.to_string()),
+                    Err(e) => Err(e),
+                }
+            }
+            None => {}
+        }
+
+        match matches.opt_str("release") {
+            Some(path) => {
+                check_remaining!(matches);
+                return match ShimKageCmd::release_access(PathBuf::from(path)) {
                     Ok(s) => {
-                        println!("Gain access: {:?}", s);
+                        println!("Release access: {:?}", s);
                         Ok(())
                     }
                     Err(e) => Err(e),
@@ -419,3 +1015,243 @@ impl super::KageCommand for ShimKageCmd {
         Err("No command".into())
     }
 }
+
+
+#[test]
+fn test_access_cache_refresh() {
+    let data = AccessData {
+        path: PathBuf::from("/home/user"),
+        write: false,
+        exec: false,
+        write_only: false,
+    };
+    let acl: Vec<Arc<FileAccess>> = data.clone().into();
+    let (events_tx, events_rx) = channel();
+    let mut cache = AccessCache::new().subscribe(events_rx);
+    cache.check_generation(1);
+    let _ = cache.denied.insert(acl[0].clone());
+
+    // Same generation
+    events_tx.send(DomainEvent { generation: 1, access: vec!() }).unwrap();
+    cache.refresh();
+    assert!(cache.denied.contains(&acl[0]));
+    assert!(! cache.granted.is_allowed(&acl[0]));
+
+    // Only the last event matters
+    events_tx.send(DomainEvent { generation: 2, access: vec!() }).unwrap();
+    events_tx.send(DomainEvent { generation: 3, access: vec!(data) }).unwrap();
+    cache.refresh();
+    assert!(cache.denied.is_empty());
+    assert!(cache.granted.is_allowed(&acl[0]));
+    assert_eq!(cache.generation, Some(3));
+
+    // A new response from another generation makes the cache stale
+    let _ = cache.denied.insert(acl[0].clone());
+    cache.check_generation(3);
+    assert!(! cache.denied.is_empty());
+    assert!(cache.granted.is_allowed(&acl[0]));
+    cache.check_generation(4);
+    assert!(cache.denied.is_empty());
+    assert!(! cache.granted.is_allowed(&acl[0]));
+    assert_eq!(cache.granted, default_granted());
+}
+
+#[test]
+fn test_access_cache_already_allowed() {
+    let data = AccessData {
+        path: PathBuf::from("/home/user"),
+        write: false,
+        exec: false,
+        write_only: false,
+    };
+    let acl: Vec<Arc<FileAccess>> = data.clone().into();
+    let mut cache = AccessCache::new();
+
+    let response = AccessResponse {
+        result: AccessResult::AlreadyAllowed,
+        all_access: None,
+        generation: 1,
+    };
+    assert!(cache.update_response(acl.clone(), response).is_ok());
+    assert!(cache.granted.is_allowed(&acl[0]));
+    assert!(cache.denied.is_empty());
+
+    // A failed transition is not cached
+    let data = AccessData {
+        path: PathBuf::from("/srv"),
+        write: false,
+        exec: false,
+        write_only: false,
+    };
+    let acl: Vec<Arc<FileAccess>> = data.into();
+    let response = AccessResponse {
+        result: AccessResult::MountFailed("busy".to_string()),
+        all_access: None,
+        generation: 1,
+    };
+    assert!(cache.update_response(acl.clone(), response).is_err());
+    assert!(! cache.granted.is_allowed(&acl[0]));
+    assert!(cache.denied.is_empty());
+
+    // A release in another process is only known by its generation
+    let home: Vec<Arc<FileAccess>> = AccessData {
+        path: PathBuf::from("/home/user"),
+        write: false,
+        exec: false,
+        write_only: false,
+    }.into();
+    assert!(cache.granted.is_allowed(&home[0]));
+    let response = AccessResponse {
+        result: AccessResult::NoReachableDomain,
+        all_access: None,
+        generation: 2,
+    };
+    assert!(cache.update_response(acl.clone(), response).is_ok());
+    assert!(! cache.granted.is_allowed(&home[0]));
+}
+
+#[test]
+fn test_access_cache_transport_error() {
+    use MONITOR_SOCKET_PATH;
+
+    // Without monitor, the request can't be sent
+    if Path::new(MONITOR_SOCKET_PATH).exists() {
+        return;
+    }
+    let data = AccessData {
+        path: PathBuf::from("/nonexistent/stemjail"),
+        write: false,
+        exec: false,
+        write_only: false,
+    };
+    let mut cache = AccessCache::new();
+    assert!(ShimKageCmd::cache_ask_access(data, &mut cache).is_err());
+    assert!(cache.denied.is_empty());
+}
+
+#[cfg(test)]
+fn test_list_jail(confined: bool) -> Jail<'static> {
+    use config::profile::JailDom;
+    use stemflow::ResPool;
+
+    let acl = FileAccess::new_ro(PathBuf::from("/a/b/c")).unwrap().into_iter()
+        .map(|x| Arc::new(x)).collect();
+    let dom = ResPool::new().new_dom("test".to_string(), acl).unwrap();
+    Jail::new(JailDom::new(dom).unwrap(), vec!(), confined)
+        .potential(vec!(PathBuf::from("/a/b/c")))
+        .deny(vec!(PathBuf::from("/secret")))
+}
+
+#[cfg(test)]
+fn test_list_names(request: &ListRequest, root: &Path, jail: &Jail) -> Vec<PathBuf> {
+    let mut names: Vec<_> = request.list_beneath(root, jail).unwrap().into_iter()
+        .map(|(x, _)| x).collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn test_list_reachable() {
+    use std::env;
+    use std::fs::{create_dir_all, remove_dir_all};
+    use libc::getpid;
+
+    let root = env::temp_dir().join(format!("stemjail-test-list-{}", unsafe { getpid() }));
+    for dir in ["a/b/c/d", "a/x", "y"].iter() {
+        create_dir_all(root.join(dir)).unwrap();
+    }
+    let request = |path: &str| ListRequest {
+        path: PathBuf::from(path),
+        metadata: false,
+    };
+    let name = |x: &str| PathBuf::from(x);
+
+    let jail = test_list_jail(true);
+    // The parent directories of a reachable path are listed, but not their siblings
+    assert_eq!(test_list_names(&request("/"), &root, &jail), vec!(name("a")));
+    assert_eq!(test_list_names(&request("/a"), &root, &jail), vec!(name("b")));
+    assert_eq!(test_list_names(&request("/a/b"), &root, &jail), vec!(name("c")));
+    assert_eq!(test_list_names(&request("/a/b/c"), &root, &jail), vec!(name("d")));
+    let unconfined = test_list_jail(false);
+    assert_eq!(test_list_names(&request("/"), &root, &unconfined), vec!(name("a"), name("y")));
+    assert!(request("/z").list_beneath(&root, &jail).is_err());
+
+    // A failure is reported, not hidden by an empty list
+    let response = request("/secret/a").respond(&jail);
+    assert!(response.error.is_some());
+    assert!(response.result.is_empty());
+    remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_prefetch_batch() {
+    use std::env;
+    use std::fs::{remove_file, File};
+    use std::io::Write;
+    use libc::getpid;
+
+    let file = env::temp_dir().join(format!("stemjail-test-prefetch-{}", unsafe { getpid() }));
+    File::create(&file).unwrap()
+        .write_all(b"# Comment\n\n  /a/b/c  \n\t\n  # Indented comment\nrelative/path\n/y\n").unwrap();
+    let batch = read_prefetch(&file, true, false).unwrap();
+    remove_file(&file).unwrap();
+    let paths: Vec<_> = batch.iter().map(|x| x.path.clone()).collect();
+    assert_eq!(paths, vec!(PathBuf::from("/a/b/c"), PathBuf::from("relative/path"),
+                           PathBuf::from("/y")));
+    assert!(batch.iter().all(|x| x.write && ! x.exec && ! x.write_only));
+    assert!(read_prefetch(&file, false, false).is_err());
+
+    // A relative path (or an empty batch) refuses the whole batch
+    assert!(check_batch(&batch).is_err());
+    assert!(check_batch(&[]).is_err());
+    let absolute: Vec<_> = batch.into_iter().filter(|x| x.path.is_absolute()).collect();
+    assert_eq!(check_batch(&absolute), Ok(()));
+}
+
+#[test]
+fn test_respond_batch() {
+    use jail::test_fork;
+    use std::env;
+    use std::fs::{create_dir_all, remove_dir_all};
+    use libc::getpid;
+
+    let root = env::temp_dir().join(format!("stemjail-test-batch-{}", unsafe { getpid() }));
+    for dir in ["a/b/c", "y"].iter() {
+        create_dir_all(root.join(WORKDIR_PARENT).join(dir)).unwrap();
+    }
+    let batch = |paths: &[&str]| -> Vec<AccessData> {
+        paths.iter().map(|x| AccessData {
+            path: PathBuf::from(x),
+            write: false,
+            exec: false,
+            write_only: false,
+        }).collect()
+    };
+    // The accesses are resolved beneath the current directory
+    let status = test_fork(|| {
+        env::set_current_dir(&root).unwrap();
+        let mut jail = test_list_jail(true);
+        let generation = jail.generation();
+        match respond_batch(&batch(&["/a/b/c"]), &mut jail).result {
+            AccessResult::AlreadyAllowed => {}
+            r => panic!("Bad batch result: {:?}", r),
+        }
+        // No partial access: one failure refuses the whole batch
+        match respond_batch(&batch(&["/a/b/c", "/y"]), &mut jail).result {
+            AccessResult::NoReachableDomain => {}
+            r => panic!("Bad unreachable batch result: {:?}", r),
+        }
+        match respond_batch(&batch(&["/a/b/c", "/secret/x"]), &mut jail).result {
+            AccessResult::ProtectedPath(ref p) if p == Path::new("/secret/x") => {}
+            r => panic!("Bad denied batch result: {:?}", r),
+        }
+        match respond_batch(&batch(&["/a/b/c", "a/b"]), &mut jail).result {
+            AccessResult::Malformed(..) => {}
+            r => panic!("Bad relative batch result: {:?}", r),
+        }
+        assert_eq!(jail.generation(), generation);
+        0
+    });
+    remove_dir_all(&root).unwrap();
+    assert_eq!(status, 0);
+}
@@ -0,0 +1,305 @@
+// Copyright (C) 2016 Mickaël Salaün
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 of the License.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+/// Shim session: one long-lived monitor connection per process, where the requests are tagged
+/// with an ID and their responses are sent as soon as ready (i.e. pipelined)
+
+use bincode::rustc_serialize::{DecodingResult, EncodingResult};
+use cmd::MonitorCall;
+use cmd::util::{handshake, recv, send};
+use jail::{Jail, JailFn};
+use MONITOR_SOCKET_PATH;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::sync::mpsc::{channel, Sender};
+use super::{AccessRequest, AccessResponse, AccessResult, ListResponse, ReleaseResponse};
+use super::ShimAction;
+use super::{check_batch, respond_batch};
+use unix_socket::UnixStream;
+use util::FEATURE_SESSION;
+
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+pub struct SessionRequest {
+    pub id: u64,
+    pub action: ShimAction,
+}
+impl_encdec!(SessionRequest);
+
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+pub enum ShimResponse {
+    Access(AccessResponse),
+    List(ListResponse),
+    Release(ReleaseResponse),
+    /// Invalid request
+    Error(String),
+}
+
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+pub struct SessionResponse {
+    pub id: u64,
+    pub response: ShimResponse,
+}
+impl_encdec!(SessionResponse);
+
+#[derive(Debug)]
+struct SessionBundle {
+    action: ShimAction,
+    response: Sender<ShimResponse>,
+}
+
+impl JailFn for SessionBundle {
+    fn call(&mut self, jail: &mut Jail) {
+        let response = match self.action {
+            ShimAction::Access(ref req) => ShimResponse::Access(req.respond(jail)),
+            ShimAction::List(ref req) => ShimResponse::List(req.respond(jail)),
+            ShimAction::Release(ref req) => ShimResponse::Release(req.respond(jail)),
+            ShimAction::AccessBatch(ref batch) => ShimResponse::Access(respond_batch(batch, jail)),
+            ShimAction::Subscribe | ShimAction::Session | ShimAction::SharedCache => {
+                ShimResponse::Error("Not allowed in a shim session".to_string())
+            }
+        };
+        let _ = self.response.send(response);
+    }
+}
+
+impl SessionRequest {
+    pub fn check(&self) -> Result<(), String> {
+        match self.action {
+            ShimAction::Access(ref req) => req.check(),
+            ShimAction::List(ref req) => req.check(),
+            ShimAction::Release(ref req) => req.check(),
+            ShimAction::AccessBatch(ref batch) => check_batch(batch),
+            ShimAction::Subscribe | ShimAction::Session | ShimAction::SharedCache => {
+                Err("Not allowed in a shim session".to_string())
+            }
+        }
+    }
+
+    /// Wait for the jail to handle the request and queue the tagged response to the session
+    /// writer, without waiting for the client
+    pub fn call(self, cmd_tx: &Sender<Box<JailFn>>, responses: &Sender<SessionResponse>)
+            -> Result<(), String> {
+        let id = self.id;
+        let response = match self.check() {
+            Ok(()) => {
+                let (response_tx, response_rx) = channel();
+                let bundle = SessionBundle {
+                    action: self.action,
+                    response: response_tx,
+                };
+                match cmd_tx.send(Box::new(bundle)) {
+                    Ok(()) => match response_rx.recv() {
+                        Ok(r) => r,
+                        Err(e) => ShimResponse::Error(format!("Failed to receive the response: {}", e)),
+                    },
+                    Err(e) => ShimResponse::Error(format!("Failed to send the request: {}", e)),
+                }
+            }
+            Err(e) => ShimResponse::Error(format!("Request error: {}", e)),
+        };
+        let response = SessionResponse {
+            id: id,
+            response: response,
+        };
+        responses.send(response).map_err(|_| "The session is closed".to_string())
+    }
+}
+
+struct SessionReader {
+    stream: UnixStream,
+    /// Responses received for other requests
+    pending: BTreeMap<u64, ShimResponse>,
+}
+
+struct SessionWriter {
+    stream: UnixStream,
+    next_id: u64,
+}
+
+/// Client side of a session, shareable between the threads of a process
+pub struct ShimSession {
+    writer: Mutex<SessionWriter>,
+    reader: Mutex<SessionReader>,
+}
+
+impl ShimSession {
+    pub fn connect() -> Result<ShimSession, String> {
+        let mut stream = match UnixStream::connect(MONITOR_SOCKET_PATH) {
+            Ok(s) => s,
+            Err(e) => return Err(format!("Failed to connect: {}", e)),
+        };
+        if ! try!(handshake(&mut stream)).contains(FEATURE_SESSION) {
+            return Err("The monitor doesn't support shim sessions".to_string());
+        }
+        try!(send(&mut stream, MonitorCall::Shim(ShimAction::Session)));
+        ShimSession::from_stream(stream)
+    }
+
+    /// Wrap a stream already switched to a session
+    fn from_stream(stream: UnixStream) -> Result<ShimSession, String> {
+        let reader = match stream.try_clone() {
+            Ok(s) => s,
+            Err(e) => return Err(format!("Failed to clone the session: {}", e)),
+        };
+        Ok(ShimSession {
+            writer: Mutex::new(SessionWriter {
+                stream: stream,
+                next_id: 0,
+            }),
+            reader: Mutex::new(SessionReader {
+                stream: reader,
+                pending: BTreeMap::new(),
+            }),
+        })
+    }
+
+    /// Send a request without waiting for its response, and return its ID
+    pub fn send(&self, action: ShimAction) -> Result<u64, String> {
+        let mut writer = match self.writer.lock() {
+            Ok(w) => w,
+            Err(_) => return Err("Failed to lock the session".to_string()),
+        };
+        let id = writer.next_id;
+        writer.next_id = id.wrapping_add(1);
+        let request = SessionRequest {
+            id: id,
+            action: action,
+        };
+        try!(send(&mut writer.stream, request));
+        Ok(id)
+    }
+
+    /// Wait for the response of the `id` request, the other received responses are kept for their
+    /// own callers
+    pub fn recv(&self, id: u64) -> Result<ShimResponse, String> {
+        let mut guard = match self.reader.lock() {
+            Ok(r) => r,
+            Err(_) => return Err("Failed to lock the session".to_string()),
+        };
+        let reader = &mut *guard;
+        loop {
+            match reader.pending.remove(&id) {
+                Some(r) => return Ok(r),
+                None => {}
+            }
+            let response: SessionResponse = try!(recv(&mut reader.stream));
+            if response.id == id {
+                return Ok(response.response);
+            }
+            let _ = reader.pending.insert(response.id, response.response);
+        }
+    }
+
+    /// Get the full response, including the jail generation
+    pub fn ask_access_response(&self, request: AccessRequest) -> Result<AccessResponse, String> {
+        try!(request.check());
+        let id = try!(self.send(ShimAction::Access(request)));
+        match try!(self.recv(id)) {
+            ShimResponse::Access(response) => Ok(response),
+            ShimResponse::Error(e) => Err(e),
+            r => Err(format!("Unexpected session response: {:?}", r)),
+        }
+    }
+
+    pub fn ask_access(&self, request: AccessRequest) -> Result<AccessResult, String> {
+        let response = try!(self.ask_access_response(request));
+        Ok(response.result)
+    }
+}
+
+#[test]
+fn test_session_call_queue() {
+    let (cmd_tx, _cmd_rx) = channel();
+    let (responses_tx, responses_rx) = channel();
+    let request = SessionRequest {
+        id: 7,
+        action: ShimAction::Session,
+    };
+    // Nobody reads the client side: the response must only be queued
+    assert_eq!(request.call(&cmd_tx, &responses_tx), Ok(()));
+    match responses_rx.try_recv() {
+        Ok(SessionResponse { id: 7, response: ShimResponse::Error(..) }) => {}
+        r => panic!("Bad session response: {:?}", r),
+    }
+}
+
+/// Fake monitor answering the `count` first requests of a session in reverse order
+#[cfg(test)]
+fn test_reverse_server(count: usize) -> (ShimSession, ::std::thread::JoinHandle<()>) {
+    use std::thread;
+
+    let (client, mut server) = UnixStream::pair().unwrap();
+    let handle = thread::spawn(move || {
+        let mut ids = vec!();
+        for _ in 0..count {
+            let request: SessionRequest = recv(&mut server).unwrap();
+            ids.push(request.id);
+        }
+        for id in ids.into_iter().rev() {
+            send(&mut server, SessionResponse {
+                id: id,
+                response: ShimResponse::Error(format!("{}", id)),
+            }).unwrap();
+        }
+    });
+    (ShimSession::from_stream(client).unwrap(), handle)
+}
+
+#[cfg(test)]
+fn test_session_id(session: &ShimSession, id: u64) {
+    match session.recv(id) {
+        Ok(ShimResponse::Error(ref e)) if *e == format!("{}", id) => {}
+        r => panic!("Bad response for request {}: {:?}", id, r),
+    }
+}
+
+#[test]
+fn test_session_recv_reorder() {
+    let (session, server) = test_reverse_server(3);
+    let ids: Vec<u64> = (0..3).map(|_| session.send(ShimAction::Session).unwrap()).collect();
+    assert_eq!(ids, vec!(0, 1, 2));
+    // The first response is the last one received, the others must be kept for later
+    test_session_id(&session, 0);
+    test_session_id(&session, 2);
+    test_session_id(&session, 1);
+    server.join().unwrap();
+}
+
+#[test]
+fn test_session_recv_threads() {
+    use std::sync::Arc;
+    use std::sync::mpsc::sync_channel;
+    use std::thread;
+
+    let count = 4;
+    let (session, server) = test_reverse_server(count);
+    let session = Arc::new(session);
+    let (sent_tx, sent_rx) = sync_channel(0);
+    let threads: Vec<_> = (0..count).map(|_| {
+        let session = session.clone();
+        let sent_tx = sent_tx.clone();
+        thread::spawn(move || {
+            let id = session.send(ShimAction::Session).unwrap();
+            sent_tx.send(()).unwrap();
+            test_session_id(&session, id);
+        })
+    }).collect();
+    for _ in 0..count {
+        sent_rx.recv().unwrap();
+    }
+    for thread in threads.into_iter() {
+        thread.join().unwrap();
+    }
+    server.join().unwrap();
+}
@@ -0,0 +1,228 @@
+// Copyright (C) 2016 Mickaël Salaün
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 of the License.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+/// Shared cache: snapshot of the jail accesses published by the monitor in a memory file, mapped
+/// read-only by the jailed processes to check their accesses without any request
+///
+/// A tampered snapshot can't give more access: the mount namespace still enforces the policy.
+
+use ffi::memfd::{memfd_create, seal_content, SharedMap};
+use jail::{Jail, JailFn};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::sync::atomic::{fence, AtomicUsize};
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use std::thread;
+use super::{DomainEvent, MonitorBundle};
+use super::fsm_kage::KageFsm;
+
+pub const SHARED_CACHE_SIZE: usize = 256 * 1024;
+
+// Header: sequence number (odd while writing), generation and snapshot size
+const SEQ_OFFSET: isize = 0;
+const GENERATION_OFFSET: isize = 8;
+const SIZE_OFFSET: isize = 16;
+const HEADER_SIZE: usize = 24;
+
+/// Maximum number of reads colliding with a write before giving up
+const READ_RETRY: usize = 16;
+
+fn seq(map: &SharedMap) -> &AtomicUsize {
+    unsafe { &*(map.as_ptr().offset(SEQ_OFFSET) as *const AtomicUsize) }
+}
+
+/// Monitor side: the only writer
+pub struct SharedCacheWriter {
+    map: SharedMap,
+    /// Read-only file handed to the clients
+    reader: File,
+}
+
+impl SharedCacheWriter {
+    pub fn new() -> io::Result<SharedCacheWriter> {
+        let file = try!(memfd_create("stemjail-cache"));
+        try!(file.set_len(SHARED_CACHE_SIZE as u64));
+        let map = try!(SharedMap::new(&file, SHARED_CACHE_SIZE, true));
+        // A client can reopen its file read-write (i.e. /proc/self/fd/N), only the seal forbids
+        // it to write or map it writable (no shared cache if the kernel doesn't support it)
+        try!(seal_content(&file));
+        let reader = try!(OpenOptions::new().read(true)
+                          .open(format!("/proc/self/fd/{}", file.as_raw_fd())));
+        Ok(SharedCacheWriter {
+            map: map,
+            reader: reader,
+        })
+    }
+
+    pub fn reader(&self) -> &File {
+        &self.reader
+    }
+
+    /// Replace the snapshot, or empty it if `event` doesn't fit (i.e. the clients fall back to
+    /// the requests)
+    pub fn publish(&self, event: &DomainEvent) {
+        let encoded = match event.encode() {
+            Ok(e) => e,
+            Err(e) => {
+                warn!("Failed to encode the shared cache: {}", e);
+                vec!()
+            }
+        };
+        let size = if encoded.len() <= self.map.len() - HEADER_SIZE {
+            encoded.len()
+        } else {
+            debug!("Shared cache too small for generation {}", event.generation);
+            0
+        };
+        let seq = seq(&self.map);
+        let cur = seq.load(Relaxed);
+        seq.store(cur.wrapping_add(1), Relaxed);
+        fence(Release);
+        unsafe {
+            let base = self.map.as_ptr();
+            ptr::write_volatile(base.offset(GENERATION_OFFSET) as *mut u64, event.generation);
+            ptr::write_volatile(base.offset(SIZE_OFFSET) as *mut u64, size as u64);
+            ptr::copy_nonoverlapping(encoded.as_ptr(), base.offset(HEADER_SIZE as isize), size);
+        }
+        seq.store(cur.wrapping_add(2), Release);
+    }
+}
+
+/// Ask for the read-only shared cache file
+#[derive(Debug)]
+pub struct SharedCacheRequest;
+
+impl JailFn for MonitorBundle<SharedCacheRequest> {
+    fn call(&mut self, jail: &mut Jail) {
+        let machine = match self.machine.take() {
+            Some(m) => m,
+            None => {
+                warn!("No machine");
+                return;
+            }
+        };
+        // The client gets an error if the connection is closed without FD
+        match jail.shared_cache() {
+            Some(cache) => match machine.send_cache_fd(cache.reader()) {
+                Ok(()) => {}
+                Err(e) => warn!("Failed to send the shared cache: {}", e),
+            },
+            None => debug!("No shared cache"),
+        }
+    }
+}
+
+/// Client side: read-only mapping of the monitor snapshot, shareable between the threads of a
+/// process
+pub struct SharedCache {
+    map: SharedMap,
+}
+
+impl SharedCache {
+    /// Map the shared cache of the current jail
+    pub fn open() -> Result<SharedCache, String> {
+        let machine = try!(KageFsm::new());
+        let machine = try!(machine.send_shared_cache_request());
+        let fd = try!(machine.recv_cache_fd());
+        SharedCache::map(&fd)
+    }
+
+    fn map<T>(file: &T) -> Result<SharedCache, String> where T: AsRawFd {
+        match SharedMap::new(file, SHARED_CACHE_SIZE, false) {
+            Ok(m) => Ok(SharedCache {
+                map: m,
+            }),
+            Err(e) => Err(format!("Failed to map the shared cache: {}", e)),
+        }
+    }
+
+    /// Get the last consistent snapshot if its generation differs from `known`
+    pub fn read(&self, known: Option<u64>) -> Option<DomainEvent> {
+        let seq = seq(&self.map);
+        let base = self.map.as_ptr();
+        for _ in 0..READ_RETRY {
+            let before = seq.load(Acquire);
+            if before & 1 == 1 {
+                thread::yield_now();
+                continue;
+            }
+            let (generation, size) = unsafe {
+                (ptr::read_volatile(base.offset(GENERATION_OFFSET) as *const u64),
+                 ptr::read_volatile(base.offset(SIZE_OFFSET) as *const u64) as usize)
+            };
+            let data = if before == 0 || known == Some(generation) ||
+                    size > self.map.len() - HEADER_SIZE {
+                None
+            } else {
+                let mut data = vec![0u8; size];
+                unsafe {
+                    ptr::copy_nonoverlapping(base.offset(HEADER_SIZE as isize),
+                                             data.as_mut_ptr(), size);
+                }
+                Some(data)
+            };
+            fence(Acquire);
+            if seq.load(Relaxed) != before {
+                continue;
+            }
+            return match data {
+                // An empty snapshot is not usable
+                Some(ref d) if ! d.is_empty() => DomainEvent::decode(d).ok(),
+                _ => None,
+            };
+        }
+        None
+    }
+}
+
+#[test]
+fn test_shared_cache_read() {
+    use std::path::PathBuf;
+    use super::AccessData;
+
+    let writer = SharedCacheWriter::new().unwrap();
+    let reader = SharedCache::map(writer.reader()).unwrap();
+    // Nothing published yet
+    assert!(reader.read(None).is_none());
+
+    let access = AccessData {
+        path: PathBuf::from("/home/user"),
+        write: true,
+        exec: false,
+        write_only: false,
+    };
+    writer.publish(&DomainEvent { generation: 2, access: vec!(access) });
+    let event = reader.read(None).unwrap();
+    assert_eq!(event.generation, 2);
+    assert_eq!(event.access[0].path, PathBuf::from("/home/user"));
+    // Already known
+    assert!(reader.read(Some(2)).is_none());
+}
+
+#[test]
+fn test_shared_cache_sealed() {
+    use std::io::Write;
+
+    let writer = SharedCacheWriter::new().unwrap();
+    let path = format!("/proc/self/fd/{}", writer.reader().as_raw_fd());
+    let mut file = OpenOptions::new().read(true).write(true).open(path).unwrap();
+    assert!(file.write(b"x").is_err());
+    assert!(SharedMap::new(&file, SHARED_CACHE_SIZE, true).is_err());
+    assert!(file.set_len(0).is_err());
+    // The monitor can still publish
+    writer.publish(&DomainEvent { generation: 1, access: vec!() });
+    assert_eq!(SharedCache::map(&file).unwrap().read(None).unwrap().generation, 1);
+}
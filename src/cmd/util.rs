@@ -12,9 +12,10 @@
 // You should have received a copy of the GNU Lesser General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
-use std::path::Path;
+use config::profile::is_exec_path;
+use std::path::{Path, PathBuf};
 
-pub use ::util::{recv, send};
+pub use ::util::{handshake, recv, send};
 
 // TODO: Replace with generic trait
 macro_rules! impl_encdec {
@@ -52,8 +53,9 @@ macro_rules! check_remaining {
     }
 }
 
-/// Forbid use of "." (i.e. the parent domain root directory)
-pub fn check_parent_path<T>(path: T) -> Result<(), String> where T: AsRef<Path> {
+/// Forbid use of "." (i.e. the parent domain root directory) and of the `deny` paths (only known
+/// by the portal and the monitors)
+pub fn check_parent_path<T>(path: T, deny: &[PathBuf]) -> Result<(), String> where T: AsRef<Path> {
     let path = path.as_ref();
     if ! path.is_absolute() {
         return Err("The path is not absolute".to_string());
@@ -62,5 +64,29 @@ pub fn check_parent_path<T>(path: T) -> Result<(), String> where T: AsRef<Path>
     if path.starts_with("/proc") {
         return Err("Access denied to /proc".to_string());
     }
-    Ok(())
+    // Reserved for the execute accesses in the policy (cf. `JailAccess`)
+    if is_exec_path(path) {
+        return Err(format!("Access denied to {}", path.display()));
+    }
+    match deny.iter().find(|x| path.starts_with(x)) {
+        Some(d) => Err(format!("Access denied to {}", d.display())),
+        None => Ok(()),
+    }
+}
+
+/// Early check of a request path, by kage or before the request reaches the jail: the deny list
+/// is only known by the monitor, which checks it when handling the request (cf. `AccessData::acl`,
+/// `ListRequest::respond` and `Jail::stage_bind`), and releasing a denied path is harmless
+pub fn check_request_path<T>(path: T) -> Result<(), String> where T: AsRef<Path> {
+    check_parent_path(path, &[])
+}
+
+#[test]
+fn test_check_request_path() {
+    assert!(check_request_path("/usr/bin").is_ok());
+    assert!(check_request_path("/.executable").is_ok());
+    assert!(check_request_path("usr").is_err());
+    assert!(check_request_path("/proc/self").is_err());
+    assert!(check_request_path("/.exec").is_err());
+    assert!(check_request_path("/.exec/usr/bin").is_err());
 }
@@ -0,0 +1,83 @@
+// Copyright (C) 2016 Mickaël Salaün
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 of the License.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use super::ConfigError;
+
+pub fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+/// Manifest of the profile hashes, with the same format as the `sha256sum` output
+pub struct ProfilesLock {
+    hashes: BTreeMap<String, String>,
+}
+
+impl ProfilesLock {
+    pub fn parse(content: &str) -> Result<ProfilesLock, ConfigError> {
+        let mut hashes = BTreeMap::new();
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.splitn(2, char::is_whitespace);
+            let (hash, name) = match (fields.next(), fields.next()) {
+                // The binary mode marker of sha256sum is ignored
+                (Some(h), Some(n)) => (h, n.trim_left().trim_left_matches('*')),
+                _ => return Err(ConfigError::new(format!("Invalid lock line {}", i + 1))),
+            };
+            if hash.len() != 64 || ! hash.chars().all(|x| x.is_digit(16)) || name.is_empty() {
+                return Err(ConfigError::new(format!("Invalid lock line {}", i + 1)));
+            }
+            if hashes.insert(name.to_string(), hash.to_lowercase()).is_some() {
+                return Err(ConfigError::new(format!("Duplicate lock entry for {}", name)));
+            }
+        }
+        Ok(ProfilesLock {
+            hashes: hashes,
+        })
+    }
+
+    /// Check that the `name` profile is listed with the hash of `content`
+    pub fn check(&self, name: &str, content: &[u8]) -> Result<(), ConfigError> {
+        match self.hashes.get(name) {
+            Some(hash) => if *hash == to_hex(&Sha256::digest(content)) {
+                Ok(())
+            } else {
+                Err(ConfigError::new(format!("Hash mismatch for {}", name)))
+            },
+            None => Err(ConfigError::new(format!("No lock entry for {}", name))),
+        }
+    }
+
+    pub fn names(&self) -> Vec<&String> {
+        self.hashes.keys().collect()
+    }
+}
+
+
+#[test]
+fn test_profiles_lock() {
+    let abc = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+    let lock = ProfilesLock::parse(&format!("# comment\n{}  a.toml\n{} *b.toml\n", abc, abc)).unwrap();
+    assert_eq!(lock.names(), vec!("a.toml", "b.toml"));
+    assert!(lock.check("a.toml", b"abc").is_ok());
+    assert!(lock.check("b.toml", b"abc").is_ok());
+    assert!(lock.check("a.toml", b"abd").is_err());
+    assert!(lock.check("c.toml", b"abc").is_err());
+    assert!(ProfilesLock::parse("abc a.toml").is_err());
+    assert!(ProfilesLock::parse(&format!("{} a.toml\n{} a.toml", abc, abc)).is_err());
+}
@@ -12,9 +12,12 @@
 // You should have received a copy of the GNU Lesser General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
+use libc::getuid;
 use rustc_serialize::Decodable;
+use self::lock::ProfilesLock;
 use std::fs;
 use std::io::Read;
+use std::os::unix::fs::MetadataExt;
 use std::path::Path;
 use std::sync::Arc;
 use stemflow::{Domain, FileAccess};
@@ -24,16 +27,53 @@ pub use self::error::ConfigError;
 
 mod error;
 
+pub mod lock;
 pub mod portal;
 pub mod profile;
 
 pub type ArcDomain = Arc<Domain<Arc<FileAccess>>>;
 
+/// Optional manifest of the profile hashes, in the profile directory
+pub static PROFILES_LOCK: &'static str = "profiles.lock";
+
+/// Refuse a file (or directory) writable by someone else than the current user
+fn check_owner(metadata: &fs::Metadata) -> Result<(), ConfigError> {
+    if metadata.uid() != unsafe { getuid() } {
+        return Err(ConfigError::new("Owned by another user".to_string()));
+    }
+    if metadata.mode() & 0o022 != 0 {
+        return Err(ConfigError::new("Group or world writable".to_string()));
+    }
+    Ok(())
+}
+
+/// Read a configuration file, after checking its permissions if `owned`
+fn read_config<T>(config_file: T, owned: bool) -> Result<String, ConfigError> where T: AsRef<Path> {
+    let mut file = try!(fs::File::open(config_file));
+    if owned {
+        try!(check_owner(&try!(file.metadata())));
+    }
+    let mut contents = String::new();
+    let _ = try!(file.read_to_string(&mut contents));
+    Ok(contents)
+}
+
 // TODO: Check for absolute path only
 pub fn get_config<T, U>(config_file: T) -> Result<U, ConfigError>
         where T: AsRef<Path>, U: Decodable {
-    let mut contents = String::new();
-    let _ = try!(fs::File::open(config_file)).read_to_string(&mut contents);
+    let contents = try!(read_config(config_file, false));
+    decode_config(&contents)
+}
+
+/// Same as get_config() but refuse a file writable by someone else than the current user (e.g.
+/// the portal configuration)
+pub fn get_owned_config<T, U>(config_file: T) -> Result<U, ConfigError>
+        where T: AsRef<Path>, U: Decodable {
+    let contents = try!(read_config(config_file, true));
+    decode_config(&contents)
+}
+
+fn decode_config<U>(contents: &str) -> Result<U, ConfigError> where U: Decodable {
     let mut parser = toml::Parser::new(contents.as_ref());
     let toml = match parser.parse() {
         Some(r) => toml::Value::Table(r),
@@ -44,23 +84,61 @@ pub fn get_config<T, U>(config_file: T) -> Result<U, ConfigError>
     Ok(config)
 }
 
+/// Load all the profiles from `profile_dir`, which must only be writable by the current user, and
+/// verify their hashes if a lock file exists
 pub fn get_configs<T, U>(profile_dir: T) -> Result<Vec<U>, ConfigError>
         where T: AsRef<Path>, U: Decodable {
+    let profile_dir = profile_dir.as_ref();
+    match check_owner(&try!(fs::metadata(profile_dir))) {
+        Ok(()) => {}
+        Err(e) => return Err(ConfigError::new(format!("(directory `{}`) {}",
+                             profile_dir.display(), e))),
+    }
+    let lock_file = profile_dir.join(PROFILES_LOCK);
+    let lock = if lock_file.exists() {
+        match read_config(&lock_file, true).and_then(|x| ProfilesLock::parse(&x)) {
+            Ok(l) => Some(l),
+            Err(e) => return Err(ConfigError::new(format!("(file `{}`) {}",
+                                 lock_file.display(), e))),
+        }
+    } else {
+        None
+    };
+    let mut names = vec!();
     let mut ret = vec!();
     for file in try!(fs::read_dir(profile_dir)) {
         let file = try!(file).path();
         match file.extension() {
             Some(ext) => {
                 if ext == "toml" {
-                    match get_config(&file) {
+                    let name = file.file_name().map(|x| x.to_string_lossy().into_owned())
+                        .unwrap_or_default();
+                    let config = read_config(&file, true).and_then(|contents| {
+                        match lock {
+                            Some(ref l) => try!(l.check(&name, contents.as_bytes())),
+                            None => {}
+                        }
+                        decode_config(&contents)
+                    });
+                    match config {
                         Ok(c) => ret.push(c),
                         Err(e) => return Err(ConfigError::new(format!("(file `{}`) {}",
                                              file.display(), e))),
                     };
+                    names.push(name);
                 }
             },
             None => {}
         }
     }
+    // A locked profile can't be silently removed
+    match lock {
+        Some(ref l) => match l.names().into_iter().find(|x| ! names.contains(x)) {
+            Some(name) => return Err(ConfigError::new(format!("(file `{}`) Missing locked profile",
+                                     profile_dir.join(name).display()))),
+            None => {}
+        },
+        None => {}
+    }
     Ok(ret)
 }
@@ -13,33 +13,267 @@
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
 use dot;
+use jail::util::nest_path;
+use std::collections::BTreeSet;
+use std::env;
 use std::fmt;
+use std::fs;
 use std::io;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use stemflow::{FileAccess, ResPool};
-use super::ArcDomain;
-use super::profile::ProfileConfig;
+use super::{ArcDomain, ConfigError};
+use super::profile::{is_exec_path, AccessRight, JailAccess, ProfileConfig};
+use util::{MESSAGE_SIZE_MAX, REQUEST_SIZE_MAX};
+use {PORTAL_CONFIG_PATH, PORTAL_PROFILES_PATH, PORTAL_PROTECTED_PATHS, PORTAL_SOCKET_PATH};
+
+/// Default user paths never visible in any jail (cf. `PortalConfig::deny`)
+pub static DENY_DEFAULT: [&'static str; 2] = [
+    "~/.gnupg",
+    "~/.ssh",
+];
+
+#[derive(Clone, Debug, Default, RustcDecodable, PartialEq)]
+pub struct PortalConfig {
+    /// Paths never visible in any jail, in addition to the portal configuration and socket
+    /// (default list if not set)
+    pub deny: Option<Vec<String>>,
+    /// Maximum size (in bytes) of a request read by the portal and the monitors (default
+    /// `REQUEST_SIZE_MAX`, at most `MESSAGE_SIZE_MAX`)
+    pub request_size_max: Option<usize>,
+}
+
+impl PortalConfig {
+    pub fn request_size_max(&self) -> usize {
+        ::std::cmp::min(self.request_size_max.unwrap_or(REQUEST_SIZE_MAX), MESSAGE_SIZE_MAX)
+    }
+
+    /// Absolute denied paths, with a leading "~" replaced by the home directory
+    pub fn deny_paths(&self) -> Vec<PathBuf> {
+        // TODO: Remove unwrap
+        let cwd = env::current_dir().unwrap();
+        let home = env::var("HOME").ok();
+        let user: Vec<&str> = match self.deny {
+            Some(ref deny) => deny.iter().map(|x| x.as_ref()).collect(),
+            None => DENY_DEFAULT.iter().map(|x| *x).collect(),
+        };
+        // A jail must never be able to rewrite the profiles that govern it
+        let portal = vec!(PORTAL_CONFIG_PATH, PORTAL_PROFILES_PATH, PORTAL_SOCKET_PATH);
+        portal.into_iter().chain(user.into_iter()).filter_map(|path| {
+            let path = Path::new(path);
+            match path.strip_prefix("~") {
+                Ok(rel) => match home {
+                    Some(ref h) => Some(nest_path(h, rel)),
+                    None => {
+                        warn!("Ignoring denied path {}: no home directory", path.display());
+                        None
+                    }
+                },
+                Err(_) => Some(cwd.join(path)),
+            }
+        }).collect()
+    }
+}
+
+/// Absolute portal paths never writable from a jail (cf. `PORTAL_PROTECTED_PATHS`), resolved once
+/// because the monitors don't share the portal working directory
+pub fn protected_paths() -> Result<Vec<PathBuf>, ConfigError> {
+    PORTAL_PROTECTED_PATHS.iter().map(|path| {
+        let path = Path::new(path);
+        // A missing file (e.g. the optional portal configuration) could still be created
+        let real = match path.canonicalize() {
+            Ok(p) => Ok(p),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => match (path.parent(), path.file_name()) {
+                (Some(parent), Some(name)) => parent.canonicalize().map(|x| x.join(name)),
+                _ => Err(io::Error::new(io::ErrorKind::NotFound, "No parent directory")),
+            },
+            Err(e) => Err(e),
+        };
+        real.map_err(|e| ConfigError::new(format!("Failed to resolve the protected path {}: {}",
+                                                  path.display(), e)))
+    }).collect()
+}
+
+/// Check if `access` could give access to a denied path or to one of its children
+pub fn is_denied(access: &FileAccess, deny: &Vec<PathBuf>) -> bool {
+    let path = JailAccess::from(access).path;
+    deny.iter().any(|x| path.starts_with(x))
+}
+
+/// Domains graph with the real paths of the execute accesses, and the denied paths as an
+/// unreachable node
+struct PolicyGraph<'a, G> where G: 'a {
+    pool: &'a G,
+    deny: &'a [PathBuf],
+}
+
+#[derive(Clone)]
+enum PolicyNode<N> {
+    Domain(N),
+    Deny,
+}
+
+fn decode_label(label: dot::LabelText) -> dot::LabelText {
+    dot::LabelText::EscStr(JailAccess::decode_text(&label.pre_escaped_content()).into())
+}
+
+impl<'a, N, E, G> dot::Labeller<'a, PolicyNode<N>, E> for PolicyGraph<'a, G>
+        where G: dot::Labeller<'a, N, E> {
+    fn graph_id(&'a self) -> dot::Id<'a> {
+        self.pool.graph_id()
+    }
+
+    fn node_id(&'a self, node: &PolicyNode<N>) -> dot::Id<'a> {
+        match *node {
+            PolicyNode::Domain(ref n) => self.pool.node_id(n),
+            PolicyNode::Deny => dot::Id::new("deny").unwrap(),
+        }
+    }
+
+    fn node_shape(&'a self, node: &PolicyNode<N>) -> Option<dot::LabelText<'a>> {
+        match *node {
+            PolicyNode::Domain(ref n) => self.pool.node_shape(n),
+            PolicyNode::Deny => Some(dot::LabelText::LabelStr("box".into())),
+        }
+    }
+
+    fn node_label(&'a self, node: &PolicyNode<N>) -> dot::LabelText<'a> {
+        match *node {
+            PolicyNode::Domain(ref n) => decode_label(self.pool.node_label(n)),
+            PolicyNode::Deny => {
+                // The backslashes are kept as escape sequences
+                let paths: Vec<String> = self.deny.iter()
+                    .map(|x| x.display().to_string().replace("\\", "\\\\")).collect();
+                dot::LabelText::EscStr(format!("deny\n{}", paths.join("\n")).into())
+            }
+        }
+    }
+
+    fn edge_label(&'a self, edge: &E) -> dot::LabelText<'a> {
+        decode_label(self.pool.edge_label(edge))
+    }
+}
+
+impl<'a, N, E, G> dot::GraphWalk<'a, PolicyNode<N>, E> for PolicyGraph<'a, G>
+        where N: Clone, E: Clone, G: dot::GraphWalk<'a, N, E> {
+    fn nodes(&'a self) -> dot::Nodes<'a, PolicyNode<N>> {
+        let mut nodes: Vec<PolicyNode<N>> = self.pool.nodes().iter().cloned()
+            .map(PolicyNode::Domain).collect();
+        nodes.push(PolicyNode::Deny);
+        nodes.into()
+    }
+
+    fn edges(&'a self) -> dot::Edges<'a, E> {
+        self.pool.edges()
+    }
+
+    fn source(&'a self, edge: &E) -> PolicyNode<N> {
+        PolicyNode::Domain(self.pool.source(edge))
+    }
+
+    fn target(&'a self, edge: &E) -> PolicyNode<N> {
+        PolicyNode::Domain(self.pool.target(edge))
+    }
+}
 
 pub struct Portal {
     configs: Vec<ProfileConfig>,
     pool: ResPool<Arc<FileAccess>>,
     confined: bool,
+    deny: Vec<PathBuf>,
+    /// All the paths a jail may read, from any domain
+    potential: Vec<PathBuf>,
+    /// Symlinks resolved in the profile paths, as (link, target)
+    links: Vec<(PathBuf, PathBuf)>,
+    /// Absolute paths never writable from a jail (cf. `protected_paths`)
+    protected: Vec<PathBuf>,
+    request_size_max: usize,
 }
 
 impl Portal {
-    pub fn new(configs: Vec<ProfileConfig>, confined: bool) -> Portal {
+    pub fn new(mut configs: Vec<ProfileConfig>, config: PortalConfig, protected: Vec<PathBuf>,
+               confined: bool) -> Portal {
+        let deny = config.deny_paths();
+        let request_size_max = config.request_size_max();
+        // TODO: Remove unwrap
+        let cwd = env::current_dir().unwrap();
         let mut pool = ResPool::new();
-        for config in configs.iter() {
+        let mut potential = BTreeSet::new();
+        let mut links = BTreeSet::new();
+        for config in configs.iter_mut() {
+            // The bind mounts never follow symlinks (cf. PathFd::open_beneath), the trusted profile
+            // paths are then resolved once here and their symlinks recreated in the jails (e.g.
+            // /bin to /usr/bin with a merged /usr)
+            for bind in config.fs.bind.iter_mut().flat_map(|x| x.iter_mut()) {
+                let path: PathBuf = cwd.join(&bind.path).components().collect();
+                match fs::canonicalize(&path) {
+                    Ok(ref real) if *real != path => match real.to_str() {
+                        Some(s) => {
+                            debug!("Profile {}: resolved {} to {}", config.name, bind.path, s);
+                            let _ = links.insert((path.clone(), real.clone()));
+                            bind.path = s.to_string();
+                        }
+                        None => warn!("Profile {}: {} resolves to a non UTF-8 path",
+                                      config.name, bind.path),
+                    },
+                    _ => {}
+                }
+            }
+            if let Some(ref mut binds) = config.fs.bind {
+                let name = &config.name;
+                binds.retain(|bind| {
+                    let path: PathBuf = cwd.join(&bind.path).components().collect();
+                    if is_exec_path(&path) {
+                        warn!("Profile {}: ignoring the reserved path {}", name, path.display());
+                        false
+                    } else {
+                        true
+                    }
+                });
+            }
             // TODO: Reference the config into the corresponding domain
-            let _ = pool.new_dom(config.name.clone(), config.clone().into());
+            let acl = filter_denied(config.clone().into(), &deny);
+            potential.extend(acl.iter().map(|x| JailAccess::from(&**x))
+                             .filter(|x| x.right == AccessRight::Read).map(|x| cwd.join(x.path)));
+            let _ = pool.new_dom(config.name.clone(), acl);
         }
         Portal {
             configs: configs,
             pool: pool,
             confined: confined,
+            deny: deny,
+            potential: potential.into_iter().collect(),
+            links: links.into_iter().collect(),
+            protected: protected,
+            request_size_max: request_size_max,
         }
     }
 
+    /// Paths never writable from a jail, even through a grant
+    pub fn protected(&self) -> &Vec<PathBuf> {
+        &self.protected
+    }
+
+    /// Symlinks to recreate in the jails, the profile paths being resolved
+    pub fn links(&self) -> &Vec<(PathBuf, PathBuf)> {
+        &self.links
+    }
+
+    /// Maximum size of a client request (cf. `PortalConfig::request_size_max`)
+    pub fn request_size_max(&self) -> usize {
+        self.request_size_max
+    }
+
+    /// Paths never visible in any jail
+    pub fn deny(&self) -> &Vec<PathBuf> {
+        &self.deny
+    }
+
+    /// Paths readable through some domain (the jails check their own reachability)
+    pub fn potential(&self) -> &Vec<PathBuf> {
+        &self.potential
+    }
+
     pub fn profile<T>(&self, name: T) -> Option<&ProfileConfig> where T: AsRef<str> {
         self.configs.iter().find(|c| AsRef::<str>::as_ref(&c.name) == name.as_ref())
     }
@@ -53,7 +287,7 @@ impl Portal {
             Some(&Some(ref bind)) => {
                 let acl = bind.iter().map(|x| Into::<Vec<Arc<FileAccess>>>::into(x))
                     .flat_map(|x| x.into_iter()).collect();
-                Some(acl)
+                Some(filter_denied(acl, &self.deny))
             }
             _ => None,
         };
@@ -66,8 +300,13 @@ impl Portal {
         }
     }
 
+    /// Render the domains graph, with the denied paths as an unreachable node
     pub fn render<T>(&self, out: &mut T) -> io::Result<()> where T: io::Write {
-        dot::render(&self.pool, out)
+        let graph = PolicyGraph {
+            pool: &self.pool,
+            deny: &self.deny,
+        };
+        dot::render(&graph, out)
     }
 
     pub fn is_confined(&self) -> bool {
@@ -75,8 +314,147 @@ impl Portal {
     }
 }
 
+fn filter_denied(acl: Vec<Arc<FileAccess>>, deny: &Vec<PathBuf>) -> Vec<Arc<FileAccess>> {
+    acl.into_iter().filter(|x| {
+        if is_denied(x, deny) {
+            warn!("Ignoring denied access to {}", x.path.display());
+            false
+        } else {
+            true
+        }
+    }).collect()
+}
+
 impl fmt::Display for Portal {
     fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
         write!(out, "profiles: {:?}", self.configs.iter().map(|x| &x.name ).collect::<Vec<_>>())
     }
 }
+
+#[test]
+fn test_get_portal_config() {
+    let c1: PortalConfig = match super::get_config("./config/portal.toml") {
+        Ok(c) => c,
+        Err(e) => panic!("{}", e),
+    };
+    let c2 = PortalConfig {
+        deny: Some(vec!("~/.gnupg".to_string(), "~/.ssh".to_string())),
+        request_size_max: None,
+    };
+    assert_eq!(c1, c2);
+    assert_eq!(c1.request_size_max(), REQUEST_SIZE_MAX);
+    let c3 = PortalConfig {
+        deny: None,
+        request_size_max: Some(::std::usize::MAX),
+    };
+    assert_eq!(c3.request_size_max(), MESSAGE_SIZE_MAX);
+}
+
+#[test]
+fn test_is_denied() {
+    use stemflow::Action;
+    use super::profile::exec_access;
+
+    let deny = vec!(PathBuf::from("/home/user/.ssh"));
+    let access = |path: &str| FileAccess::new(PathBuf::from(path), Action::Read).unwrap();
+    assert!(is_denied(&access("/home/user/.ssh"), &deny));
+    assert!(is_denied(&access("/home/user/.ssh/id_rsa"), &deny));
+    assert!(is_denied(&exec_access("/home/user/.ssh/rc").unwrap(), &deny));
+    // The parent directories stay allowed but the denied paths are masked in the jail
+    assert!(! is_denied(&access("/home/user"), &deny));
+    assert!(! is_denied(&access("/home/user/.sshrc"), &deny));
+}
+
+#[test]
+fn test_render_exec() {
+    let profile = match super::get_config("./config/profiles/test/example1.toml") {
+        Ok(c) => c,
+        Err(e) => panic!("{}", e),
+    };
+    let portal = Portal::new(vec!(profile), PortalConfig::default(), vec!(), false);
+    let mut graph = vec!();
+    portal.render(&mut graph).unwrap();
+    let graph = String::from_utf8(graph).unwrap();
+    assert!(graph.starts_with("digraph"));
+    assert!(! graph.contains("/.exec"));
+}
+
+#[test]
+fn test_render_deny() {
+    let config = PortalConfig {
+        deny: Some(vec!("/secret\\dir".to_string())),
+        request_size_max: None,
+    };
+    let portal = Portal::new(vec!(), config, vec!(), false);
+    let mut graph = vec!();
+    portal.render(&mut graph).unwrap();
+    let graph = String::from_utf8(graph).unwrap();
+    let deny = graph.lines().find(|x| x.trim_left().starts_with("deny")).expect("No deny node");
+    assert!(deny.contains("/secret\\\\dir"));
+    assert!(deny.contains("box"));
+    assert_eq!(graph.lines().last(), Some("}"));
+}
+
+#[test]
+fn test_portal_links() {
+    use libc::getpid;
+    use std::fs::{create_dir_all, remove_dir_all};
+    use std::os::unix::fs::symlink;
+    use super::profile::{BindConfig, FsConfig, RunConfig};
+
+    let root = env::temp_dir().canonicalize().unwrap()
+        .join(format!("stemjail-test-links-{}", unsafe { getpid() }));
+    create_dir_all(root.join("usr/bin")).unwrap();
+    symlink("usr/bin", root.join("bin")).unwrap();
+    let bind = |path: PathBuf| BindConfig {
+        path: path.to_str().unwrap().to_string(),
+        write: None,
+        access: None,
+        exec: None,
+        watch: None,
+    };
+    let profile = ProfileConfig {
+        name: "links".to_string(),
+        fs: FsConfig {
+            bind: Some(vec!(bind(root.join("bin")), bind(root.join("usr")))),
+            procfs: None,
+            sysfs: None,
+        },
+        run: RunConfig {
+            cmd: vec!("/bin/sh".to_string()),
+        },
+    };
+    let portal = Portal::new(vec!(profile), PortalConfig::default(), vec!(), true);
+    remove_dir_all(&root).unwrap();
+    assert_eq!(portal.links(), &vec!((root.join("bin"), root.join("usr/bin"))));
+    assert!(portal.potential().contains(&root.join("usr/bin")));
+    assert!(! portal.potential().contains(&root.join("bin")));
+}
+
+#[test]
+fn test_portal_exec_root() {
+    use super::profile::{BindConfig, FsConfig, RunConfig};
+
+    let bind = |path: &str| BindConfig {
+        path: path.to_string(),
+        write: None,
+        access: None,
+        exec: None,
+        watch: None,
+    };
+    let profile = ProfileConfig {
+        name: "exec".to_string(),
+        fs: FsConfig {
+            bind: Some(vec!(bind("/.exec/usr/bin"), bind("/.exec"), bind("/usr"))),
+            procfs: None,
+            sysfs: None,
+        },
+        run: RunConfig {
+            cmd: vec!("/bin/sh".to_string()),
+        },
+    };
+    let portal = Portal::new(vec!(profile), PortalConfig::default(), vec!(), true);
+    let binds = portal.configs[0].fs.bind.as_ref().unwrap();
+    assert_eq!(binds.len(), 1);
+    assert!(! is_exec_path(&binds[0].path));
+}
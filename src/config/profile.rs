@@ -13,11 +13,91 @@
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
 use jail::BindMount;
+use jail::util::nest_path;
 use std::env;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use stemflow::{Action, FileAccess, RefDom, SetAccess};
-use super::ArcDomain;
+use super::{ArcDomain, ConfigError};
+use super::portal::Portal;
+
+/// Virtual directory where a policy read access means an execute access to the nested path (only
+/// handled by `JailAccess`)
+static EXEC_ROOT: &'static str = "/.exec";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessRight {
+    Read,
+    Write,
+    Exec,
+}
+
+/// Access to a real path: the policy only knows about read and write, so an execute access is
+/// stored as a read access to a virtual path (cf. `EXEC_ROOT`)
+#[derive(Clone, Debug, PartialEq)]
+pub struct JailAccess {
+    pub path: PathBuf,
+    pub right: AccessRight,
+}
+
+impl JailAccess {
+    pub fn new<T>(path: T, right: AccessRight) -> JailAccess where T: AsRef<Path> {
+        JailAccess {
+            path: path.as_ref().to_path_buf(),
+            right: right,
+        }
+    }
+
+    /// Access for the policy engine
+    pub fn to_policy(&self) -> Result<FileAccess, ()> {
+        match self.right {
+            AccessRight::Read => FileAccess::new(self.path.clone(), Action::Read),
+            AccessRight::Write => FileAccess::new(self.path.clone(), Action::Write),
+            AccessRight::Exec => FileAccess::new(nest_path(EXEC_ROOT, &self.path), Action::Read),
+        }
+    }
+
+    /// Replace the virtual execute paths in a policy description (e.g. a domain label)
+    pub fn decode_text(text: &str) -> String {
+        text.replace(&format!("{}/", EXEC_ROOT), "exec:/")
+    }
+}
+
+impl<'a> From<&'a FileAccess> for JailAccess {
+    fn from(access: &FileAccess) -> JailAccess {
+        if access.action == Action::Write {
+            return JailAccess::new(&*access.path, AccessRight::Write);
+        }
+        match access.path.strip_prefix(EXEC_ROOT) {
+            Ok(rel) => JailAccess::new(Path::new("/").join(rel), AccessRight::Exec),
+            Err(_) => JailAccess::new(&*access.path, AccessRight::Read),
+        }
+    }
+}
+
+/// Check if `path` is in the virtual execute directory, which must never be a real path
+pub fn is_exec_path<T>(path: T) -> bool where T: AsRef<Path> {
+    path.as_ref().starts_with(EXEC_ROOT)
+}
+
+pub fn exec_access<T>(path: T) -> Result<FileAccess, ()> where T: AsRef<Path> {
+    JailAccess::new(path, AccessRight::Exec).to_policy()
+}
+
+/// Read access to `path`, with the write and execute accesses if requested
+pub fn new_access<T>(path: T, write: bool, exec: bool) -> Result<Vec<FileAccess>, ()>
+        where T: AsRef<Path> {
+    let path = path.as_ref().to_path_buf();
+    let mut acl = if write {
+        try!(FileAccess::new_rw(path.clone()))
+    } else {
+        try!(FileAccess::new_ro(path.clone()))
+    };
+    if exec {
+        acl.push(try!(exec_access(&path)));
+    }
+    Ok(acl)
+}
 
 #[derive(Clone, Debug, RustcDecodable, PartialEq)]
 pub struct ProfileConfig {
@@ -29,6 +109,9 @@ pub struct ProfileConfig {
 #[derive(Clone, Debug, RustcDecodable, PartialEq)]
 pub struct FsConfig {
     pub bind: Option<Vec<BindConfig>>,
+    pub procfs: Option<ProcConfig>,
+    /// Mount a read-only sysfs
+    pub sysfs: Option<bool>,
 }
 
 #[derive(Clone, Debug, RustcDecodable, PartialEq)]
@@ -36,6 +119,26 @@ pub struct BindConfig {
     // TODO: Force absolute path
     pub path: String,
     pub write: Option<bool>,
+    /// Set to "write-only" for a drop directory: new files can be created but nothing can be
+    /// listed nor read (the `write` option is then ignored)
+    pub access: Option<String>,
+    /// Allow to execute files from this path, which implies a read access (default: true)
+    pub exec: Option<bool>,
+    /// Propagate the host mounts added later under this path (e.g. removable media)
+    pub watch: Option<bool>,
+}
+
+/// Hardening of the jail procfs
+#[derive(Clone, Debug, Default, RustcDecodable, PartialEq)]
+pub struct ProcConfig {
+    /// Value of the `hidepid` mount option
+    pub hidepid: Option<u8>,
+    /// Only show the processes (i.e. `subset=pid`), if supported by the kernel
+    pub subset_pid: Option<bool>,
+    /// Entries replaced with an empty file or directory (default list if not set)
+    pub hide: Option<Vec<String>>,
+    /// Entries remounted read-only (default list if not set)
+    pub read_only: Option<Vec<String>>,
 }
 
 #[derive(Clone, Debug, RustcDecodable, PartialEq)]
@@ -50,10 +153,13 @@ impl<'a> Into<Vec<Arc<FileAccess>>> for &'a BindConfig {
         // TODO: Map between outside/src and inside/dst
         let path = PathBuf::from(self.path.clone());
         // TODO: Put the default policy in unique place
-        let file_access = if self.write.unwrap_or(false) {
-            FileAccess::new_rw(path)
-        } else {
-            FileAccess::new_ro(path)
+        let file_access = match self.access {
+            Some(ref a) if a == "write-only" => FileAccess::new(path, Action::Write).map(|x| vec!(x)),
+            Some(ref a) => {
+                warn!("Ignoring {}: unknown access \"{}\"", self.path, a);
+                Err(())
+            }
+            None => new_access(path, self.write.unwrap_or(false), self.exec.unwrap_or(true)),
         };
         match file_access {
             Ok(fa) => fa.into_iter().map(|x| Arc::new(x)).collect(),
@@ -72,32 +178,132 @@ impl Into<Vec<Arc<FileAccess>>> for ProfileConfig {
     }
 }
 
+/// Refuse a writable access to `path` if it could modify one of the absolute `protected` paths
+/// (i.e. the jail could widen its own next launch, cf. `protected_paths`)
+pub fn check_writable_path<T>(path: T, protected: &[PathBuf]) -> Result<(), String>
+        where T: AsRef<Path> {
+    // A missing path can't cover a protected one
+    let path = match path.as_ref().canonicalize() {
+        Ok(p) => p,
+        Err(_) => return Ok(()),
+    };
+    for protected in protected.iter() {
+        if protected.starts_with(&path) || path.starts_with(protected) {
+            return Err(format!("Writable access to {} covers {}", path.display(),
+                               protected.display()));
+        }
+    }
+    Ok(())
+}
+
+/// Refuse the profiles if any writable bind could modify the `protected` paths (e.g. the profiles
+/// and the portal configuration)
+pub fn check_writable_binds(configs: &Vec<ProfileConfig>, protected: &[PathBuf])
+        -> Result<(), ConfigError> {
+    for config in configs.iter() {
+        let binds = match config.fs.bind {
+            Some(ref b) => b,
+            None => continue,
+        };
+        for bind in binds.iter().filter(|x| x.write.unwrap_or(false) || x.access.is_some()) {
+            match check_writable_path(&bind.path, protected) {
+                Ok(()) => {}
+                Err(e) => return Err(ConfigError::new(format!("(profile `{}`) {}", config.name, e))),
+            }
+        }
+    }
+    Ok(())
+}
+
 pub struct ProfileDom {
     pub cmd: Vec<String>,
     pub jdom: JailDom,
+    pub procfs: ProcConfig,
+    pub sysfs: bool,
+    pub watch: Vec<PathBuf>,
+    pub deny: Vec<PathBuf>,
+    pub potential: Vec<PathBuf>,
+    pub links: Vec<(PathBuf, PathBuf)>,
+    pub protected: Vec<PathBuf>,
+    pub request_size_max: usize,
+}
+
+impl ProfileDom {
+    /// Use the jail options from `config` if any, or the default ones otherwise, and the global
+    /// options from `portal`
+    pub fn new(cmd: Vec<String>, dom: ArcDomain, config: Option<&ProfileConfig>, portal: &Portal)
+            -> Result<ProfileDom, String> {
+        let (procfs, sysfs) = match config {
+            Some(c) => (c.fs.procfs.clone().unwrap_or_default(), c.fs.sysfs.unwrap_or(false)),
+            None => (ProcConfig::default(), false),
+        };
+        let watch = match config.map(|x| &x.fs.bind) {
+            Some(&Some(ref bind)) => {
+                let cwd = try!(current_dir());
+                bind.iter().filter(|x| x.watch.unwrap_or(false)).map(|x| cwd.join(&x.path)).collect()
+            }
+            _ => vec!(),
+        };
+        Ok(ProfileDom {
+            cmd: cmd,
+            jdom: try!(JailDom::new(dom)),
+            procfs: procfs,
+            sysfs: sysfs,
+            watch: watch,
+            deny: portal.deny().clone(),
+            potential: portal.potential().clone(),
+            links: portal.links().clone(),
+            protected: portal.protected().clone(),
+            request_size_max: portal.request_size_max(),
+        })
+    }
 }
 
 #[derive(Clone)]
 pub struct JailDom {
     pub binds: Vec<BindMount>,
     pub dom: ArcDomain,
+    /// Host paths voluntarily released by the jail, which can't be accessed anymore
+    pub released: Vec<PathBuf>,
+    /// Bind mounts granted by the portal, independently of the domain
+    pub grants: Vec<BindMount>,
+}
+
+/// The relative profile paths are resolved from the current directory
+fn current_dir() -> Result<PathBuf, String> {
+    env::current_dir().map_err(|e| format!("Failed to get the current directory: {}", e))
 }
 
-impl From<ArcDomain> for JailDom {
-    /// Loosely conversion: merge read and write into read-write, ignore write-only)
-    fn from(other: ArcDomain) -> JailDom {
-        // TODO: Remove unwrap
-        let cwd = env::current_dir().unwrap();
-        // For each read access, if the path match a write access, then RW, else RO
-        let binds = other.acl.range_read().map(|access_read| {
-            let access_write = FileAccess::new(access_read.path.clone(), Action::Write).unwrap();
-            let path = cwd.join(access_read.as_ref());
+impl JailDom {
+    /// Loosely conversion: merge read and write into read-write, write-only to a drop directory
+    pub fn new(other: ArcDomain) -> Result<JailDom, String> {
+        let cwd = try!(current_dir());
+        // For each read access, if the path match a write access, then RW, else RO, and if the path
+        // match an execute access, then exec, else noexec
+        let mut binds: Vec<BindMount> = other.acl.range_read().map(|x| JailAccess::from(&**x))
+                .filter(|x| x.right == AccessRight::Read).map(|access_read| {
+            let access_write = JailAccess::new(&access_read.path, AccessRight::Write).to_policy()
+                .unwrap();
+            let access_exec = exec_access(&access_read.path).unwrap();
+            let path = cwd.join(&access_read.path);
             BindMount::new(path.clone(), path).writable(other.is_allowed(&Arc::new(access_write)))
+                .executable(other.is_allowed(&Arc::new(access_exec)))
         }).collect();
-        JailDom {
+        // For each write access without a read access, then WO
+        let write_only: Vec<BindMount> = other.acl.iter().filter(|access| {
+            access.action == Action::Write && ! other.is_allowed(&Arc::new(
+                FileAccess::new(access.path.clone(), Action::Read).unwrap()))
+        }).map(|access_write| {
+            let path = cwd.join(access_write.as_ref());
+            BindMount::new(path.clone(), path).write_only(true)
+        }).collect();
+        binds.extend(write_only);
+        Ok(JailDom {
             binds: binds,
             dom: other,
-        }
+            released: vec!(),
+            grants: vec!(),
+        })
     }
 }
 
@@ -116,8 +322,13 @@ fn test_get_config_example1() {
                 BindConfig {
                     path: "/home".to_string(),
                     write: None,
+                    access: None,
+                    exec: None,
+                    watch: None,
                 },
             )),
+            procfs: None,
+            sysfs: None,
         },
         run: RunConfig {
             cmd: vec!("/bin/sh".to_string(), "-c".to_string(), "id".to_string()),
@@ -140,12 +351,20 @@ fn test_get_config_example2() {
                 BindConfig {
                     path: "/run".to_string(),
                     write: Some(true),
+                    access: None,
+                    exec: None,
+                    watch: None,
                 },
                 BindConfig {
                     path: "/home".to_string(),
                     write: None,
+                    access: None,
+                    exec: None,
+                    watch: None,
                 },
             )),
+            procfs: None,
+            sysfs: None,
         },
         run: RunConfig {
             cmd: vec!("/usr/bin/setsid".to_string(), "-c".to_string(), "/bin/sh".to_string()),
@@ -153,3 +372,97 @@ fn test_get_config_example2() {
     };
     assert_eq!(c1, c2);
 }
+
+#[test]
+fn test_get_config_example3() {
+    // TODO: Use absolute configuration path
+    let c1: ProfileConfig = match super::get_config("./config/profiles/test/example3.toml") {
+        Ok(c) => c,
+        Err(e) => panic!("{}", e),
+    };
+    let c2 = ProfileConfig {
+        name: "example3".to_string(),
+        fs: FsConfig {
+            bind: Some(vec!(
+                BindConfig {
+                    path: "/usr".to_string(),
+                    write: None,
+                    access: None,
+                    exec: None,
+                    watch: None,
+                },
+                BindConfig {
+                    path: "/media".to_string(),
+                    write: None,
+                    access: None,
+                    exec: None,
+                    watch: Some(true),
+                },
+                BindConfig {
+                    path: "/var/spool/outbox".to_string(),
+                    write: None,
+                    access: Some("write-only".to_string()),
+                    exec: None,
+                    watch: None,
+                },
+            )),
+            procfs: Some(ProcConfig {
+                hidepid: Some(2),
+                subset_pid: Some(true),
+                hide: Some(vec!("/proc/kcore".to_string())),
+                read_only: None,
+            }),
+            sysfs: Some(true),
+        },
+        run: RunConfig {
+            cmd: vec!("/bin/sh".to_string()),
+        },
+    };
+    assert_eq!(c1, c2);
+}
+
+#[test]
+fn test_bind_config_exec() {
+    let mut bind = BindConfig {
+        path: "/opt/tools".to_string(),
+        write: None,
+        access: None,
+        exec: None,
+        watch: None,
+    };
+    let acl: Vec<Arc<FileAccess>> = (&bind).into();
+    assert_eq!(acl.len(), 2);
+    let exec = JailAccess::new("/opt/tools", AccessRight::Exec);
+    assert!(acl.iter().any(|x| JailAccess::from(&**x) == exec));
+
+    bind.exec = Some(false);
+    let acl: Vec<Arc<FileAccess>> = (&bind).into();
+    assert_eq!(acl.len(), 1);
+    assert!(! acl.iter().any(|x| JailAccess::from(&**x).right == AccessRight::Exec));
+}
+
+#[test]
+fn test_jail_access_policy() {
+    for right in [AccessRight::Read, AccessRight::Write, AccessRight::Exec].iter() {
+        let access = JailAccess::new("/usr/bin", *right);
+        assert_eq!(JailAccess::from(&access.to_policy().unwrap()), access);
+    }
+    let exec = JailAccess::new("/usr/bin", AccessRight::Exec).to_policy().unwrap();
+    assert!(*exec.path != Path::new("/usr/bin"));
+    let label = format!("Read {}", exec.path.display());
+    assert_eq!(JailAccess::decode_text(&label), "Read exec:/usr/bin");
+}
+
+#[test]
+fn test_check_writable_path() {
+    use super::portal::protected_paths;
+
+    let protected = protected_paths().unwrap();
+    assert!(protected.iter().all(|x| x.is_absolute()));
+    assert!(check_writable_path("/", &protected).is_err());
+    assert!(check_writable_path("./config", &protected).is_err());
+    assert!(check_writable_path("./config/portal.toml", &protected).is_err());
+    assert!(check_writable_path("./config/profiles/test", &protected).is_err());
+    assert!(check_writable_path("./src", &protected).is_ok());
+    assert!(check_writable_path("./config/nonexistent", &protected).is_ok());
+}
@@ -0,0 +1,102 @@
+// Copyright (C) 2016 Mickaël Salaün
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 of the License.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use libc::{c_int, c_void, fcntl, mmap, munmap, MAP_FAILED, MAP_SHARED, PROT_READ, PROT_WRITE};
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::ptr;
+
+pub mod raw {
+    use libc::{c_int, c_uint};
+
+    pub use ffi::ns::raw::scv;
+    pub use libc::SYS_memfd_create;
+
+    pub const MFD_CLOEXEC: c_uint = 1;
+    pub const MFD_ALLOW_SEALING: c_uint = 2;
+
+    pub const F_ADD_SEALS: c_int = 1033;
+
+    pub const F_SEAL_SEAL: c_int = 1;
+    pub const F_SEAL_SHRINK: c_int = 2;
+    pub const F_SEAL_GROW: c_int = 4;
+    // Since Linux 5.1
+    pub const F_SEAL_FUTURE_WRITE: c_int = 16;
+}
+
+/// Create an anonymous (sealable) memory file
+pub fn memfd_create(name: &str) -> io::Result<File> {
+    let name = try!(CString::new(name));
+    let flags = raw::MFD_CLOEXEC | raw::MFD_ALLOW_SEALING;
+    match unsafe { raw::scv::syscall(raw::SYS_memfd_create, name.as_ptr(), flags) } {
+        -1 => Err(io::Error::last_os_error()),
+        fd => Ok(unsafe { File::from_raw_fd(fd as c_int) }),
+    }
+}
+
+/// Forbid any size change, e.g. to not crash a process mapping the file (SIGBUS), and any new
+/// write, even through a file reopened read-write (i.e. only the existing writable mappings can
+/// change the content)
+pub fn seal_content(file: &File) -> io::Result<()> {
+    let seals = raw::F_SEAL_SHRINK | raw::F_SEAL_GROW | raw::F_SEAL_FUTURE_WRITE |
+        raw::F_SEAL_SEAL;
+    match unsafe { fcntl(file.as_raw_fd(), raw::F_ADD_SEALS, seals) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Shared memory mapping of a file, unmapped when dropped
+pub struct SharedMap {
+    addr: *mut u8,
+    len: usize,
+}
+
+// The mapping is only a memory area, its users must synchronize their accesses
+unsafe impl Send for SharedMap {}
+unsafe impl Sync for SharedMap {}
+
+impl SharedMap {
+    pub fn new<T>(file: &T, len: usize, writable: bool) -> io::Result<SharedMap>
+            where T: AsRawFd {
+        let prot = if writable {
+            PROT_READ | PROT_WRITE
+        } else {
+            PROT_READ
+        };
+        match unsafe { mmap(ptr::null_mut(), len, prot, MAP_SHARED, file.as_raw_fd(), 0) } {
+            MAP_FAILED => Err(io::Error::last_os_error()),
+            addr => Ok(SharedMap {
+                addr: addr as *mut u8,
+                len: len,
+            }),
+        }
+    }
+
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.addr
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+}
+
+impl Drop for SharedMap {
+    fn drop(&mut self) {
+        let _ = unsafe { munmap(self.addr as *mut c_void, self.len) };
+    }
+}
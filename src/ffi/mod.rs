@@ -18,4 +18,11 @@ macro_rules! path2bytes {
     }
 }
 
+pub mod memfd;
 pub mod ns;
+// The syscall registers are only known for this architecture
+#[cfg(target_arch="x86_64")]
+pub mod ptrace;
+// The syscall numbers are only known for this architecture
+#[cfg(target_arch="x86_64")]
+pub mod seccomp;
@@ -12,9 +12,13 @@
 // You should have received a copy of the GNU Lesser General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
+use libc::{c_int, c_uint, mode_t, EPERM, ENOSYS};
 use std::env;
 use std::ffi::CString;
+use std::fs::File;
 use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::path::Path;
 use std::ptr;
 
@@ -26,16 +30,19 @@ pub mod fs;
 pub mod fs0;
 
 pub mod raw {
-    use libc::{c_char, c_int, c_uint, c_ulong, pid_t, size_t};
+    use libc::{c_char, c_int, c_uint, c_ulong, mode_t, pid_t, size_t};
 
     extern {
         pub fn chroot(path: *const c_char) -> c_int;
+        pub fn mkdirat(dirfd: c_int, pathname: *const c_char, mode: mode_t) -> c_int;
         pub fn mount(source: *const c_char, target: *const c_char,
                      filesystemtype: *const c_char, mountflags: c_ulong,
                      data: *const c_char) -> c_int;
+        pub fn openat(dirfd: c_int, pathname: *const c_char, flags: c_int, ...) -> c_int;
         pub fn pivot_root(new_root: *const c_char, put_old: *const c_char) -> c_int;
         pub fn sethostname(name: *const c_char, len: size_t) -> c_int;
         pub fn umount2(target: *const c_char, flags: c_uint) -> c_int;
+        pub fn unlinkat(dirfd: c_int, pathname: *const c_char, flags: c_int) -> c_int;
         pub fn unshare(flags: c_uint) -> c_int;
         pub fn waitpid(pid: pid_t, status: *mut c_int, options: c_int) -> pid_t;
     }
@@ -49,12 +56,73 @@ pub mod raw {
         }
     }
 
+    // Syscall with arguments
+    pub mod scv {
+        use libc::c_long;
+
+        extern {
+            pub fn syscall(number: c_long, ...) -> c_long;
+        }
+    }
+
     // Syscall numbers from x86_64-linux-gnu/asm/unistd_64.h
     #[allow(dead_code)]
     #[cfg(target_arch="x86_64")]
     pub fn gettid() -> pid_t {
         unsafe { sc0::syscall(186) as pid_t }
     }
+
+    pub use libc::{SYS_mount_setattr, SYS_move_mount, SYS_open_tree, SYS_openat2, SYS_renameat2};
+
+    pub const O_PATH: c_int = 0o10000000;
+
+    pub const RESOLVE_NO_MAGICLINKS: u64 = 0x02;
+    pub const RESOLVE_NO_SYMLINKS: u64 = 0x04;
+    pub const RESOLVE_BENEATH: u64 = 0x08;
+
+    pub const RENAME_NOREPLACE: c_uint = 1;
+
+    pub const AT_FDCWD: c_int = -100;
+    pub const AT_EMPTY_PATH: c_uint = 0x1000;
+    pub const AT_RECURSIVE: c_uint = 0x8000;
+
+    pub const OPEN_TREE_CLONE: c_uint = 1;
+    pub const OPEN_TREE_CLOEXEC: c_uint = 0o2000000;
+
+    pub const MOVE_MOUNT_F_EMPTY_PATH: c_uint = 0x00000004;
+
+    #[allow(non_camel_case_types)]
+    #[repr(C)]
+    pub struct open_how {
+        pub flags: u64,
+        pub mode: u64,
+        pub resolve: u64,
+    }
+
+    #[allow(non_camel_case_types)]
+    #[repr(C)]
+    pub struct mount_attr {
+        pub attr_set: u64,
+        pub attr_clr: u64,
+        pub propagation: u64,
+        pub userns_fd: u64,
+    }
+}
+
+bitflags! {
+    pub flags MountAttr: u64 {
+        /** Mount read-only */
+        const MOUNT_ATTR_RDONLY = 0x00000001,
+
+        /** Ignore suid and sgid bits */
+        const MOUNT_ATTR_NOSUID = 0x00000002,
+
+        /** Disallow access to device special files */
+        const MOUNT_ATTR_NODEV = 0x00000004,
+
+        /** Disallow program execution */
+        const MOUNT_ATTR_NOEXEC = 0x00000008
+    }
 }
 
 // TODO: use the sys::cvt familly
@@ -122,3 +190,113 @@ pub fn unshare(flags: sched::CloneFlags) -> io::Result<()> {
         _ => Err(io::Error::last_os_error()),
     }
 }
+
+/// Open `path` relative to the `dir` directory
+pub fn openat<T>(dir: &File, path: T, flags: c_int) -> io::Result<File> where T: AsRef<Path> {
+    let path = try!(CString::new(path2bytes!(&path)));
+    match unsafe { raw::openat(dir.as_raw_fd(), path.as_ptr(), flags, 0o600 as mode_t) } {
+        -1 => Err(io::Error::last_os_error()),
+        fd => Ok(unsafe { File::from_raw_fd(fd) }),
+    }
+}
+
+/// Open `path` relative to the `dir` directory with the `resolve` restrictions (cf. openat2(2))
+pub fn openat2<T>(dir: &File, path: T, flags: c_int, resolve: u64) -> io::Result<File>
+        where T: AsRef<Path> {
+    let path = try!(CString::new(path2bytes!(&path)));
+    let mut how = raw::open_how {
+        flags: flags as u64,
+        mode: 0,
+        resolve: resolve,
+    };
+    match unsafe { raw::scv::syscall(raw::SYS_openat2, dir.as_raw_fd(), path.as_ptr(),
+                                     &mut how as *mut raw::open_how,
+                                     mem::size_of::<raw::open_how>()) } {
+        -1 => Err(io::Error::last_os_error()),
+        fd => Ok(unsafe { File::from_raw_fd(fd as c_int) }),
+    }
+}
+
+pub fn mkdirat<T>(dir: &File, path: T, mode: mode_t) -> io::Result<()> where T: AsRef<Path> {
+    let path = try!(CString::new(path2bytes!(&path)));
+    match unsafe { raw::mkdirat(dir.as_raw_fd(), path.as_ptr(), mode) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Remove the file `path` relative to the `dir` directory
+pub fn unlinkat<T>(dir: &File, path: T) -> io::Result<()> where T: AsRef<Path> {
+    let path = try!(CString::new(path2bytes!(&path)));
+    match unsafe { raw::unlinkat(dir.as_raw_fd(), path.as_ptr(), 0) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Rename `old` to `new`, both relative to the `dir` directory, without replacing an existing
+/// `new` file
+pub fn renameat_noreplace<T, U>(dir: &File, old: T, new: U) -> io::Result<()>
+        where T: AsRef<Path>, U: AsRef<Path> {
+    let old = try!(CString::new(path2bytes!(&old)));
+    let new = try!(CString::new(path2bytes!(&new)));
+    match unsafe { raw::scv::syscall(raw::SYS_renameat2, dir.as_raw_fd(), old.as_ptr(), dir.as_raw_fd(),
+                                     new.as_ptr(), raw::RENAME_NOREPLACE) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Clone the whole mount tree under `path` in a detached mount
+pub fn open_tree<T>(path: T) -> io::Result<File> where T: AsRef<Path> {
+    let path = try!(CString::new(path2bytes!(&path)));
+    let flags = raw::OPEN_TREE_CLONE | raw::OPEN_TREE_CLOEXEC | raw::AT_RECURSIVE;
+    match unsafe { raw::scv::syscall(raw::SYS_open_tree, raw::AT_FDCWD, path.as_ptr(), flags) } {
+        -1 => Err(io::Error::last_os_error()),
+        fd => Ok(unsafe { File::from_raw_fd(fd as c_int) }),
+    }
+}
+
+/// Recursively set the attributes and the propagation type of a detached mount tree
+pub fn mount_setattr(tree: &File, attr: &MountAttr, propagation: &fs::MsFlags) -> io::Result<()> {
+    let empty = try!(CString::new(""));
+    let mut mattr = raw::mount_attr {
+        attr_set: attr.bits(),
+        attr_clr: 0,
+        propagation: propagation.bits() as u64,
+        userns_fd: 0,
+    };
+    let flags = raw::AT_EMPTY_PATH | raw::AT_RECURSIVE;
+    match unsafe { raw::scv::syscall(raw::SYS_mount_setattr, tree.as_raw_fd(), empty.as_ptr(), flags,
+                                     &mut mattr as *mut raw::mount_attr,
+                                     mem::size_of::<raw::mount_attr>()) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Attach a detached mount tree to `target`
+pub fn move_mount<T>(tree: &File, target: T) -> io::Result<()> where T: AsRef<Path> {
+    let empty = try!(CString::new(""));
+    let target = try!(CString::new(path2bytes!(&target)));
+    match unsafe { raw::scv::syscall(raw::SYS_move_mount, tree.as_raw_fd(), empty.as_ptr(), raw::AT_FDCWD,
+                                     target.as_ptr(), raw::MOVE_MOUNT_F_EMPTY_PATH) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Check if the kernel supports the new mount API (including `mount_setattr(2)`)
+pub fn has_mount_api() -> bool {
+    // An invalid attribute size make the syscall fail before any other check
+    let ret = unsafe { raw::scv::syscall(raw::SYS_mount_setattr, -1 as c_int, ptr::null::<u8>(), 0 as c_uint,
+                                         ptr::null::<u8>(), 0usize) };
+    if ret == 0 {
+        return true;
+    }
+    match io::Error::last_os_error().raw_os_error() {
+        // A seccomp filter may deny unknown syscalls with EPERM
+        Some(ENOSYS) | Some(EPERM) => false,
+        _ => true,
+    }
+}
@@ -0,0 +1,146 @@
+// Copyright (C) 2016 Mickaël Salaün
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 of the License.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use libc::{c_int, c_void, pid_t, ptrace, waitpid, WUNTRACED};
+use std::io;
+use std::mem;
+use std::ptr;
+
+pub mod raw {
+    use libc::{c_int, c_uint};
+
+    pub const PTRACE_GETREGS: c_uint = 12;
+    pub const PTRACE_SYSCALL: c_uint = 24;
+    pub const PTRACE_SEIZE: c_uint = 0x4206;
+    pub const PTRACE_LISTEN: c_uint = 0x4208;
+
+    pub const PTRACE_O_TRACESYSGOOD: c_int = 1;
+    pub const PTRACE_O_TRACEFORK: c_int = 1 << 1;
+    pub const PTRACE_O_TRACEVFORK: c_int = 1 << 2;
+    pub const PTRACE_O_TRACECLONE: c_int = 1 << 3;
+    pub const PTRACE_O_TRACEEXEC: c_int = 1 << 4;
+    pub const PTRACE_O_EXITKILL: c_int = 1 << 20;
+
+    pub const PTRACE_EVENT_STOP: c_int = 128;
+
+    pub const __WNOTHREAD: c_int = 0x20000000;
+    pub const __WALL: c_int = 0x40000000;
+
+    // From x86_64-linux-gnu/sys/user.h
+    #[allow(non_camel_case_types)]
+    #[derive(Clone, Copy, Debug)]
+    #[repr(C)]
+    pub struct user_regs_struct {
+        pub r15: u64,
+        pub r14: u64,
+        pub r13: u64,
+        pub r12: u64,
+        pub rbp: u64,
+        pub rbx: u64,
+        pub r11: u64,
+        pub r10: u64,
+        pub r9: u64,
+        pub r8: u64,
+        pub rax: u64,
+        pub rcx: u64,
+        pub rdx: u64,
+        pub rsi: u64,
+        pub rdi: u64,
+        pub orig_rax: u64,
+        pub rip: u64,
+        pub cs: u64,
+        pub eflags: u64,
+        pub rsp: u64,
+        pub ss: u64,
+        pub fs_base: u64,
+        pub gs_base: u64,
+        pub ds: u64,
+        pub es: u64,
+        pub fs: u64,
+        pub gs: u64,
+    }
+}
+
+/// Syscall number and arguments of a tracee stopped at a syscall
+#[derive(Debug)]
+pub struct SyscallRegs {
+    pub nr: i64,
+    pub args: [u64; 6],
+}
+
+impl From<raw::user_regs_struct> for SyscallRegs {
+    fn from(regs: raw::user_regs_struct) -> SyscallRegs {
+        SyscallRegs {
+            nr: regs.orig_rax as i64,
+            args: [regs.rdi, regs.rsi, regs.rdx, regs.r10, regs.r8, regs.r9],
+        }
+    }
+}
+
+/// Trace `pid` from the current thread, without stopping it, with the `options`
+pub fn seize(pid: pid_t, options: c_int) -> io::Result<()> {
+    match unsafe { ptrace(raw::PTRACE_SEIZE, pid, ptr::null_mut::<c_void>(),
+                          options as usize as *mut c_void) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Let a tracee in group-stop wait for a SIGCONT, while reporting it to the tracer
+pub fn listen(pid: pid_t) -> io::Result<()> {
+    let null = ptr::null_mut::<c_void>();
+    match unsafe { ptrace(raw::PTRACE_LISTEN, pid, null, null) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Resume the tracee until the next syscall entry or exit, and deliver `signal` if not 0
+pub fn resume_syscall(pid: pid_t, signal: c_int) -> io::Result<()> {
+    match unsafe { ptrace(raw::PTRACE_SYSCALL, pid, ptr::null_mut::<c_void>(),
+                          signal as usize as *mut c_void) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+pub fn get_syscall(pid: pid_t) -> io::Result<SyscallRegs> {
+    let mut regs: raw::user_regs_struct = unsafe { mem::zeroed() };
+    match unsafe { ptrace(raw::PTRACE_GETREGS, pid, ptr::null_mut::<c_void>(),
+                          &mut regs as *mut raw::user_regs_struct as *mut c_void) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(regs.into()),
+    }
+}
+
+/// Wait for the `pid` child (not traced yet) to stop
+pub fn wait_stopped(pid: pid_t) -> io::Result<()> {
+    let mut status = 0;
+    match unsafe { waitpid(pid, &mut status, WUNTRACED) } {
+        -1 => Err(io::Error::last_os_error()),
+        // WIFSTOPPED
+        _ if status & 0xff == 0x7f => Ok(()),
+        _ => Err(io::Error::new(io::ErrorKind::Other, "The child didn't stop")),
+    }
+}
+
+/// Wait for any tracee of the current thread (including the threads) and return its PID and
+/// status, without reaping the children of the other threads
+pub fn wait_tracees() -> io::Result<(pid_t, c_int)> {
+    let mut status = 0;
+    match unsafe { waitpid(-1, &mut status, raw::__WALL | raw::__WNOTHREAD) } {
+        -1 => Err(io::Error::last_os_error()),
+        pid => Ok((pid, status)),
+    }
+}
@@ -0,0 +1,182 @@
+// Copyright (C) 2016 Mickaël Salaün
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 of the License.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use libc::{c_int, c_long, c_ulong, ioctl, prctl};
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+
+pub mod raw {
+    use libc::{c_long, c_ulong};
+
+    pub use ffi::ns::raw::scv;
+
+    pub const PR_SET_NO_NEW_PRIVS: i32 = 38;
+
+    pub const SECCOMP_SET_MODE_FILTER: c_long = 1;
+    pub const SECCOMP_FILTER_FLAG_NEW_LISTENER: c_long = 1 << 3;
+
+    pub const SECCOMP_RET_ALLOW: u32 = 0x7fff0000;
+    pub const SECCOMP_RET_USER_NOTIF: u32 = 0x7fc00000;
+
+    pub const SECCOMP_USER_NOTIF_FLAG_CONTINUE: u32 = 1;
+
+    // _IOWR('!', 0, struct seccomp_notif)
+    pub const SECCOMP_IOCTL_NOTIF_RECV: c_ulong = 0xc0502100;
+    // _IOWR('!', 1, struct seccomp_notif_resp)
+    pub const SECCOMP_IOCTL_NOTIF_SEND: c_ulong = 0xc0182101;
+    // _IOW('!', 2, __u64)
+    pub const SECCOMP_IOCTL_NOTIF_ID_VALID: c_ulong = 0x40082102;
+
+    pub const BPF_LD_W_ABS: u16 = 0x20;
+    pub const BPF_JMP_JEQ_K: u16 = 0x15;
+    pub const BPF_RET_K: u16 = 0x06;
+
+    // Offsets in struct seccomp_data
+    pub const SECCOMP_DATA_NR: u32 = 0;
+    pub const SECCOMP_DATA_ARCH: u32 = 4;
+
+    pub const AUDIT_ARCH: u32 = 0xc000003e;
+    pub const SYS_SECCOMP: c_long = 317;
+
+    // Syscall numbers from x86_64-linux-gnu/asm/unistd_64.h
+    pub mod nr {
+        use libc::c_long;
+
+        pub const OPEN: c_long = 2;
+        pub const STAT: c_long = 4;
+        pub const LSTAT: c_long = 6;
+        pub const ACCESS: c_long = 21;
+        pub const EXECVE: c_long = 59;
+        pub const CREAT: c_long = 85;
+        pub const OPENAT: c_long = 257;
+        pub const NEWFSTATAT: c_long = 262;
+        pub const FACCESSAT: c_long = 269;
+        pub const EXECVEAT: c_long = 322;
+        pub const STATX: c_long = 332;
+        pub const OPENAT2: c_long = 437;
+        pub const FACCESSAT2: c_long = 439;
+    }
+
+    #[allow(non_camel_case_types)]
+    #[repr(C)]
+    pub struct sock_filter {
+        pub code: u16,
+        pub jt: u8,
+        pub jf: u8,
+        pub k: u32,
+    }
+
+    #[allow(non_camel_case_types)]
+    #[repr(C)]
+    pub struct sock_fprog {
+        pub len: u16,
+        pub filter: *const sock_filter,
+    }
+
+    #[allow(non_camel_case_types)]
+    #[derive(Clone, Copy, Debug)]
+    #[repr(C)]
+    pub struct seccomp_data {
+        pub nr: i32,
+        pub arch: u32,
+        pub instruction_pointer: u64,
+        pub args: [u64; 6],
+    }
+
+    #[allow(non_camel_case_types)]
+    #[derive(Clone, Copy, Debug)]
+    #[repr(C)]
+    pub struct seccomp_notif {
+        pub id: u64,
+        pub pid: u32,
+        pub flags: u32,
+        pub data: seccomp_data,
+    }
+
+    #[allow(non_camel_case_types)]
+    #[repr(C)]
+    pub struct seccomp_notif_resp {
+        pub id: u64,
+        pub val: i64,
+        pub error: i32,
+        pub flags: u32,
+    }
+}
+
+pub use self::raw::seccomp_notif as Notif;
+
+/// Ask the monitor for every `syscalls` (of the native architecture) of the current thread and
+/// its future children, and return the notification listener
+///
+/// The current thread can't then create a new listener.
+pub fn notify_syscalls(syscalls: &[c_long]) -> io::Result<File> {
+    let mut filter = vec!(
+        raw::sock_filter { code: raw::BPF_LD_W_ABS, jt: 0, jf: 0, k: raw::SECCOMP_DATA_ARCH },
+        // The other architectures are not watched (i.e. only used to detect accesses)
+        raw::sock_filter { code: raw::BPF_JMP_JEQ_K, jt: 1, jf: 0, k: raw::AUDIT_ARCH },
+        raw::sock_filter { code: raw::BPF_RET_K, jt: 0, jf: 0, k: raw::SECCOMP_RET_ALLOW },
+        raw::sock_filter { code: raw::BPF_LD_W_ABS, jt: 0, jf: 0, k: raw::SECCOMP_DATA_NR },
+    );
+    for nr in syscalls.iter() {
+        filter.push(raw::sock_filter { code: raw::BPF_JMP_JEQ_K, jt: 0, jf: 1, k: *nr as u32 });
+        filter.push(raw::sock_filter { code: raw::BPF_RET_K, jt: 0, jf: 0,
+                                       k: raw::SECCOMP_RET_USER_NOTIF });
+    }
+    filter.push(raw::sock_filter { code: raw::BPF_RET_K, jt: 0, jf: 0, k: raw::SECCOMP_RET_ALLOW });
+    let prog = raw::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_ptr(),
+    };
+    // Required without CAP_SYS_ADMIN, the jailed processes can't gain privileges anyway
+    if unsafe { prctl(raw::PR_SET_NO_NEW_PRIVS, 1 as c_ulong, 0 as c_ulong, 0 as c_ulong,
+                      0 as c_ulong) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    match unsafe { raw::scv::syscall(raw::SYS_SECCOMP, raw::SECCOMP_SET_MODE_FILTER,
+                                     raw::SECCOMP_FILTER_FLAG_NEW_LISTENER, &prog) } {
+        -1 => Err(io::Error::last_os_error()),
+        fd => Ok(unsafe { File::from_raw_fd(fd as c_int) }),
+    }
+}
+
+/// Wait for the next notification
+pub fn notif_recv(listener: &File) -> io::Result<Notif> {
+    let mut notif: Notif = unsafe { mem::zeroed() };
+    match unsafe { ioctl(listener.as_raw_fd(), raw::SECCOMP_IOCTL_NOTIF_RECV, &mut notif) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(notif),
+    }
+}
+
+/// Check if the notified syscall is still waiting (e.g. the process was not killed or the memory
+/// was not reused), which must be checked after reading the process memory
+pub fn notif_id_valid(listener: &File, id: u64) -> bool {
+    unsafe { ioctl(listener.as_raw_fd(), raw::SECCOMP_IOCTL_NOTIF_ID_VALID, &id) == 0 }
+}
+
+/// Let the kernel execute the notified syscall
+pub fn notif_continue(listener: &File, id: u64) -> io::Result<()> {
+    let mut resp = raw::seccomp_notif_resp {
+        id: id,
+        val: 0,
+        error: 0,
+        flags: raw::SECCOMP_USER_NOTIF_FLAG_CONTINUE,
+    };
+    match unsafe { ioctl(listener.as_raw_fd(), raw::SECCOMP_IOCTL_NOTIF_SEND, &mut resp) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
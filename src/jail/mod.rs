@@ -15,42 +15,86 @@
 #![allow(deprecated)]
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use cmd::shim::AccessData;
-use config::profile::JailDom;
+use cmd::shim::{AccessData, AccessResult, DomainEvent, SharedCacheWriter};
+use config::profile::{new_access, JailAccess, JailDom, ProcConfig};
 use fd::Pipe;
-use ffi::ns::{fs, raw, sched};
-use ffi::ns::{mount, pivot_root, unshare, sethostname};
-use libc::{c_int, exit, fork, pid_t, getpid, setsid, getgid, getuid};
+use ffi::ns::{fs, fs0, raw, sched};
+use ffi::ns::{mount, openat, pivot_root, renameat_noreplace, umount, unlinkat, unshare, sethostname};
+use ffi::ns::{has_mount_api, mount_setattr, move_mount, open_tree, MountAttr};
+use ffi::ns::{MOUNT_ATTR_NODEV, MOUNT_ATTR_NOEXEC, MOUNT_ATTR_NOSUID, MOUNT_ATTR_RDONLY};
+use libc::{c_int, exit, fork, pid_t, getpid, setsid, getgid, getuid, poll, pollfd, EINVAL};
+use libc::{fcntl, FD_CLOEXEC, F_SETFD, POLLERR, POLLPRI};
+use libc::{time, O_CLOEXEC, O_CREAT, O_EXCL, O_NOFOLLOW, O_NONBLOCK, O_RDONLY, O_WRONLY};
 use mnt::{get_mount, get_submounts, MntOps, VecMountEntry};
 use MONITOR_SOCKET_PATH;
 use self::util::*;
 use srv;
-use std::borrow::Cow::{Borrowed, Owned};
+use std::collections::{BTreeMap, BTreeSet};
 use std::env;
+use std::ffi::OsString;
 use std::fmt::Debug;
-use std::fs::{OpenOptions, create_dir, soft_link};
+use std::fs::{File, OpenOptions, create_dir, read_dir, remove_dir, remove_file, soft_link};
 use std::io;
 use std::io::{ErrorKind, Error, Read, Write};
+use std::os::unix::fs::MetadataExt;
 use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::process::CommandExt;
 use std::path::{Path, PathBuf};
+use std::ptr;
 use std::process::{Command, Stdio};
 use std::sync::{Arc, RwLock};
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::Relaxed;
-use std::sync::mpsc::{channel, Receiver, Select};
+use std::sync::mpsc::{channel, Receiver, Select, Sender};
 use std::thread;
+use std::time::Duration;
 use stemflow::{FileAccess, RefDom};
 use unix_socket::UnixStream;
+use util::REQUEST_SIZE_MAX;
 
 pub use self::session::SessionIo;
 
+#[cfg(target_arch="x86_64")]
+mod seccomp;
 mod session;
+#[cfg(target_arch="x86_64")]
+mod trace;
 
 pub mod util;
 
 pub static WORKDIR_PARENT: &'static str = "./parent";
 pub static ENV_WHITELIST: [&'static str; 2] = ["HOME", "TERM"];
 
+/// Minimum age (in seconds) of a spooled file before moving it to its drop directory
+static SPOOL_DELAY: u64 = 2;
+
+/// Size and inode limits of a spool, which lives in memory until flushed
+static SPOOL_OPTIONS: &'static str = "mode=0300,size=64m,nr_inodes=4096";
+
+/// Prefix of a spooled file while it is copied to its drop directory
+static SPOOL_TMP_PREFIX: &'static str = ".stemjail-spool-";
+
+/// Default procfs entries replaced with an empty file or directory
+pub static PROC_HIDE: [&'static str; 8] = [
+    "/proc/acpi",
+    "/proc/kallsyms",
+    "/proc/kcore",
+    "/proc/keys",
+    "/proc/latency_stats",
+    "/proc/sched_debug",
+    "/proc/scsi",
+    "/proc/timer_list",
+];
+
+/// Default procfs entries remounted read-only
+pub static PROC_READ_ONLY: [&'static str; 5] = [
+    "/proc/bus",
+    "/proc/fs",
+    "/proc/irq",
+    "/proc/sys",
+    "/proc/sysrq-trigger",
+];
+
 pub trait JailFn: Send + Debug {
     fn call(&mut self, &mut Jail);
 }
@@ -62,7 +106,13 @@ pub struct BindMount {
     src: PathBuf,
     dst: PathBuf,
     writable: bool,
+    /// Allow to execute files, otherwise mounted noexec
+    exec: bool,
     from_parent: bool,
+    /// Drop directory: `dst` is a spool whose new files are moved to `src` (cf. flush_spools)
+    write_only: bool,
+    /// Sub mounts (relative to `src`) to detach from a recursive mount tree (cf. add_bind_tree)
+    excludes: Vec<PathBuf>,
 }
 
 impl BindMount {
@@ -71,7 +121,10 @@ impl BindMount {
             src: source,
             dst: destination,
             writable: false,
+            exec: true,
             from_parent: false,
+            write_only: false,
+            excludes: vec!(),
         }
     }
 
@@ -80,17 +133,29 @@ impl BindMount {
         self
     }
 
+    pub fn executable(mut self, exec: bool) -> BindMount {
+        self.exec = exec;
+        self
+    }
+
     pub fn from_parent(mut self, from_parent: bool) -> BindMount {
         self.from_parent = from_parent;
         self
     }
+
+    pub fn write_only(mut self, write_only: bool) -> BindMount {
+        self.write_only = write_only;
+        self
+    }
 }
 
 impl Into<AccessData> for BindMount {
     fn into(self) -> AccessData {
         AccessData {
             path: self.dst,
-            write: self.writable,
+            write: self.writable || self.write_only,
+            exec: self.exec && ! self.write_only,
+            write_only: self.write_only,
         }
     }
 }
@@ -99,7 +164,9 @@ impl<'a> Into<AccessData> for &'a BindMount {
     fn into(self) -> AccessData {
         AccessData {
             path: self.dst.clone(),
-            write: self.writable,
+            write: self.writable || self.write_only,
+            exec: self.exec && ! self.write_only,
+            write_only: self.write_only,
         }
     }
 }
@@ -143,6 +210,44 @@ pub struct Jail<'a> {
     end_event: Option<Receiver<Result<(), ()>>>,
     workdir: Option<PathBuf>,
     confined: bool,
+    procfs: ProcConfig,
+    sysfs: bool,
+    /// Use the new mount API (i.e. recursive bind mount attributes)
+    mount_api: bool,
+    /// Bind sources where the new host mounts are propagated
+    watch: Vec<PathBuf>,
+    /// Host mount points already seen under the watched sources
+    watch_seen: BTreeSet<PathBuf>,
+    /// Host mount points propagated into the jail, with their destination
+    watch_imported: BTreeMap<PathBuf, PathBuf>,
+    /// Privileged channel from the portal to the monitor
+    grant_channel: Option<UnixStream>,
+    /// Host paths never visible in the jail, masked if under a bind mount
+    deny: Vec<PathBuf>,
+    /// Host paths readable through some domain, with a placeholder if reachable
+    potential: Vec<PathBuf>,
+    /// Absolute host paths never writable, even through a grant (cf. `protected_paths`)
+    protected: Vec<PathBuf>,
+    /// Symlinks of the host resolved in the profile paths, as (link, target)
+    links: Vec<(PathBuf, PathBuf)>,
+    /// Placeholder directories created in the jail root, parents first (cf. add_placeholders)
+    placeholders: Vec<PathBuf>,
+    /// Detect the accesses with the seccomp user notifications instead of the shim
+    seccomp: bool,
+    /// Detect the accesses with ptrace, for the kernels without seccomp user notifications
+    trace: bool,
+    /// Maximum size of a request read by the monitor (cf. `PortalConfig::request_size_max`)
+    request_size_max: usize,
+    /// Incremented at each change of the jail accesses
+    generation: u64,
+    /// Shim clients notified of the access changes
+    subscribers: Vec<Sender<DomainEvent>>,
+    /// Current accesses readable by all the jailed processes
+    shared_cache: Option<SharedCacheWriter>,
+    /// Monitor channel for the spool ticker (cf. update_spool_ticker)
+    spool_tx: Option<Sender<Box<JailFn>>>,
+    /// Stop flag of the running spool ticker, if any
+    spool_quit: Option<Arc<AtomicBool>>,
 }
 
 impl<'a> AsRef<JailDom> for Jail<'a> {
@@ -167,6 +272,155 @@ impl<'a> Jail<'a> {
             end_event: None,
             workdir: None,
             confined: confined,
+            procfs: ProcConfig::default(),
+            sysfs: false,
+            mount_api: false,
+            watch: vec!(),
+            watch_seen: BTreeSet::new(),
+            watch_imported: BTreeMap::new(),
+            grant_channel: None,
+            deny: vec!(),
+            potential: vec!(),
+            protected: vec!(),
+            links: vec!(),
+            placeholders: vec!(),
+            seccomp: false,
+            trace: false,
+            request_size_max: REQUEST_SIZE_MAX,
+            generation: 0,
+            subscribers: vec!(),
+            shared_cache: None,
+            spool_tx: None,
+            spool_quit: None,
+        }
+    }
+
+    pub fn procfs(mut self, procfs: ProcConfig) -> Jail<'a> {
+        self.procfs = procfs;
+        self
+    }
+
+    pub fn sysfs(mut self, sysfs: bool) -> Jail<'a> {
+        self.sysfs = sysfs;
+        self
+    }
+
+    pub fn watch(mut self, watch: Vec<PathBuf>) -> Jail<'a> {
+        self.watch = watch;
+        self
+    }
+
+    pub fn deny(mut self, deny: Vec<PathBuf>) -> Jail<'a> {
+        self.deny = deny;
+        self
+    }
+
+    pub fn potential(mut self, potential: Vec<PathBuf>) -> Jail<'a> {
+        self.potential = potential;
+        self
+    }
+
+    pub fn protected(mut self, protected: Vec<PathBuf>) -> Jail<'a> {
+        self.protected = protected;
+        self
+    }
+
+    pub fn links(mut self, links: Vec<(PathBuf, PathBuf)>) -> Jail<'a> {
+        self.links = links;
+        self
+    }
+
+    pub fn seccomp(mut self, seccomp: bool) -> Jail<'a> {
+        self.seccomp = seccomp;
+        self
+    }
+
+    pub fn trace(mut self, trace: bool) -> Jail<'a> {
+        self.trace = trace;
+        self
+    }
+
+    pub fn request_size_max(mut self, request_size_max: usize) -> Jail<'a> {
+        self.request_size_max = request_size_max;
+        self
+    }
+
+    pub fn denied_paths(&self) -> &Vec<PathBuf> {
+        &self.deny
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// All the accesses currently mounted in the jail
+    pub fn current_access(&self) -> Vec<AccessData> {
+        self.jdom.binds.iter().chain(self.jdom.grants.iter()).map(|x| x.into()).collect()
+    }
+
+    fn current_event(&self) -> DomainEvent {
+        DomainEvent {
+            generation: self.generation,
+            access: self.current_access(),
+        }
+    }
+
+    /// Send the current accesses to `events`, and then all their changes
+    pub fn subscribe(&mut self, events: Sender<DomainEvent>) {
+        if events.send(self.current_event()).is_ok() {
+            self.subscribers.push(events);
+        }
+    }
+
+    pub fn shared_cache(&self) -> Option<&SharedCacheWriter> {
+        self.shared_cache.as_ref()
+    }
+
+    /// Publish the current accesses for all the jailed processes (from the monitor)
+    fn init_shared_cache(&mut self) {
+        match SharedCacheWriter::new() {
+            Ok(cache) => {
+                cache.publish(&self.current_event());
+                self.shared_cache = Some(cache);
+            }
+            Err(e) => warn!("Failed to create the shared cache: {}", e),
+        }
+    }
+
+    fn access_changed(&mut self) {
+        self.generation += 1;
+        let event = self.current_event();
+        match self.shared_cache {
+            Some(ref cache) => cache.publish(&event),
+            None => {}
+        }
+        // Forget the gone subscribers
+        self.subscribers.retain(|x| x.send(event.clone()).is_ok());
+        self.update_spool_ticker();
+    }
+
+    /// Only flush the spools regularly while there is a drop directory
+    fn update_spool_ticker(&mut self) {
+        let spooling = self.jdom.binds.iter().any(|x| x.write_only);
+        if spooling && self.spool_quit.is_none() {
+            match self.spool_tx {
+                Some(ref tx) => {
+                    let quit = Arc::new(AtomicBool::new(false));
+                    let spool_tx = tx.clone();
+                    let spool_quit = quit.clone();
+                    // Not joined: stopped with the monitor
+                    let _ = thread::spawn(move || {
+                        spool_ticker(spool_tx, spool_quit);
+                    });
+                    self.spool_quit = Some(quit);
+                }
+                None => {}
+            }
+        } else if ! spooling {
+            match self.spool_quit.take() {
+                Some(quit) => quit.store(true, Relaxed),
+                None => {}
+            }
         }
     }
 
@@ -174,43 +428,375 @@ impl<'a> Jail<'a> {
         self.confined
     }
 
+    /// Get the portal side of the privileged channel to the monitor, once the jail is running
+    pub fn take_grant_channel(&mut self) -> Option<UnixStream> {
+        self.grant_channel.take()
+    }
+
+    /// Transition to a domain allowing `acl`: all the new bind mounts are staged in the workdir
+    /// and then moved into the jail, or none of them if any error occurred
+    pub fn gain_access(&mut self, acl: Vec<FileAccess>) -> AccessResult {
+        self.transition(acl, None)
+    }
+
+    /// Same as gain_access() but also import `extra` with the same rollback, and keep it as a
+    /// grant
     // FIXME: Exclude /dev and /proc in the configurations
-    pub fn gain_access(&mut self, acl: Vec<FileAccess>) -> Result<Vec<AccessData>, ()> {
+    fn transition(&mut self, acl: Vec<FileAccess>, extra: Option<&BindMount>) -> AccessResult {
+        // The execute accesses are checked against their real path
+        let paths: Vec<PathBuf> = acl.iter().map(|x| JailAccess::from(x).path).collect();
+        match paths.iter().find(|x| self.jdom.released.iter().any(|y| x.starts_with(y))) {
+            Some(path) => {
+                debug!("Access to {} was released", path.display());
+                return AccessResult::ProtectedPath(path.clone());
+            }
+            None => {}
+        }
+        match paths.iter().find(|x| self.deny.iter().any(|y| x.starts_with(y))) {
+            Some(path) => {
+                warn!("Access denied to {}", path.display());
+                return AccessResult::ProtectedPath(path.clone());
+            }
+            None => {}
+        }
         let acl = acl.into_iter().map(|x| Arc::new(x)).collect();
-        match self.jdom.dom.reachable(&acl) {
+        let next: Option<JailDom> = match self.jdom.dom.reachable(&acl) {
             Some(dom) => {
                 // TODO: Compare the reference
                 if dom == self.jdom.dom {
                     debug!("Current domain already allow this access");
-                    return Ok(vec!());
-                }
-                let prev = self.jdom.clone();
-                self.jdom = dom.into();
-                // TODO: Optimize with intersection
-                let binds = self.jdom.binds.iter().filter(|&x|
-                    prev.binds.iter().find(|&y| *y == *x).is_none()
-                ).map(|x| {
-                    let mut b = x.clone();
-                    b.from_parent = true;
-                    b
-                });
-                let mut new_access = vec!();
-                for bind in binds {
-                    // FIXME: Check transition result and restore to previous state if any error
-                    // FIXME: Do all mounts in the workdir and if all OK, move them in the jail
-                    let _ = self._import_bind(&bind, true);
-                    // Record the bind mount even if it failed (cache purpose)
-                    new_access.push(bind.into());
-                }
-                debug!("Domain transition: {} -> {}", prev.dom.name, self.jdom.dom.name);
-                self.update_hostname();
-                Ok(new_access)
+                    if extra.is_none() {
+                        return AccessResult::AlreadyAllowed;
+                    }
+                    None
+                } else {
+                    match JailDom::new(dom) {
+                        Ok(d) => Some(d),
+                        Err(e) => {
+                            error!("Failed to load the reachable domain: {}", e);
+                            return AccessResult::MountFailed(e);
+                        }
+                    }
+                }
             }
             None => {
                 debug!("No domain reachable");
-                Err(())
+                return AccessResult::NoReachableDomain;
+            }
+        };
+        let next = next.map(|mut next| {
+            // The released paths stay out of the jail
+            let released = self.jdom.released.clone();
+            next.binds = next.binds.into_iter().filter(|x|
+                ! released.iter().any(|y| x.src.starts_with(y))
+            ).collect();
+            next.released = released;
+            next.grants = self.jdom.grants.clone();
+            next
+        });
+        // TODO: Optimize with intersection
+        let mut binds: Vec<BindMount> = match next {
+            Some(ref next) => next.binds.iter().filter(|&x|
+                self.jdom.binds.iter().find(|&y| *y == *x).is_none()
+            ).map(|x| {
+                let mut b = x.clone();
+                b.from_parent = true;
+                b
+            }).collect(),
+            None => vec!(),
+        };
+        // Mounted last, on top of the domain ones
+        binds.extend(extra.cloned());
+
+        // Stage all the bind mounts, the staging directories are unmounted if dropped
+        let mut staged = vec!();
+        for bind in binds.iter() {
+            match self.stage_bind(bind) {
+                Ok(tmp_dir) => staged.push(tmp_dir),
+                Err(e) => {
+                    warn!("Domain transition aborted: {}", e);
+                    return AccessResult::MountFailed(format!("Failed to prepare {}: {}",
+                                                             bind.dst.display(), e));
+                }
             }
         }
+
+        // The placeholders of the current domain could hide or be in the way of the new binds
+        self.remove_placeholders();
+
+        // Commit all the bind mounts or rollback the already moved ones
+        {
+            let mut committed: Vec<&BindMount> = vec!();
+            for (bind, tmp_dir) in binds.iter().zip(staged.into_iter()) {
+                match self.commit_bind(bind, tmp_dir, true) {
+                    Ok(()) => committed.push(bind),
+                    Err(e) => {
+                        warn!("Domain transition aborted: {}", e);
+                        for prev_bind in committed.iter().rev() {
+                            match umount(&prev_bind.dst, &fs0::MNT_DETACH) {
+                                Ok(..) => {}
+                                Err(e) => warn!("Failed to unmount {}: {}", prev_bind.dst.display(), e),
+                            }
+                        }
+                        self.add_placeholders("/", WORKDIR_PARENT);
+                        return AccessResult::MountFailed(format!("Failed to mount {}: {}",
+                                                                 bind.dst.display(), e));
+                    }
+                }
+            }
+        }
+
+        match next {
+            Some(next) => {
+                debug!("Domain transition: {} -> {}", self.jdom.dom.name, next.dom.name);
+                self.jdom = next;
+                self.update_hostname();
+                // Restore the granted bind mounts hidden by the new ones
+                for grant in self.jdom.grants.iter()
+                        .filter(|x| binds.iter().any(|y| x.dst.starts_with(&y.dst))) {
+                    match self._import_bind(grant, true) {
+                        Ok(()) => {}
+                        Err(e) => warn!("Failed to restore the grant {}: {}", grant.dst.display(), e),
+                    }
+                }
+            }
+            None => {}
+        }
+        match extra {
+            Some(bind) => {
+                info!("Bind mounted {} on {}", bind.src.display(), bind.dst.display());
+                self.jdom.released = self.jdom.released.iter()
+                    .filter(|x| ! x.starts_with(&bind.src)).cloned().collect();
+                self.jdom.grants.push(bind.clone());
+            }
+            None => {}
+        }
+        // After the grants, to not create placeholders where they are mounted
+        self.add_placeholders("/", WORKDIR_PARENT);
+        self.access_changed();
+        AccessResult::Granted(binds.into_iter().map(|x| x.into()).collect())
+    }
+
+    /// Recreate in `root` (i.e. the jail root) the host symlinks of the profile paths (e.g. /bin to
+    /// /usr/bin), unless something is already there
+    fn add_links<T>(&self, root: T) where T: AsRef<Path> {
+        let root = root.as_ref();
+        for &(ref link, ref target) in self.links.iter() {
+            let dst = nest_path(root, link);
+            if dst.symlink_metadata().is_ok() {
+                continue;
+            }
+            let parent = match link.parent() {
+                Some(p) => PathFd::create_dirs_beneath(root, p, 0o755).map(|_| ()),
+                None => Ok(()),
+            };
+            match parent.and_then(|_| soft_link(target, &dst)) {
+                Ok(()) => debug!("Linked {} to {}", link.display(), target.display()),
+                Err(e) => warn!("Failed to link {} to {}: {}", link.display(), target.display(), e),
+            }
+        }
+    }
+
+    /// Create empty and read-only directories in `root` (i.e. the jail root) for the paths
+    /// reachable from the current domain but not yet bind mounted, to let the jailed processes try
+    /// to access them (and then transition)
+    ///
+    /// The jailed processes own the root tmpfs, the topmost created directories are then read-only
+    /// bind mounted on themselves to forbid any change of the placeholders (e.g. chmod).
+    fn add_placeholders<T, U>(&mut self, root: T, host: U) where T: AsRef<Path>, U: AsRef<Path> {
+        let root = root.as_ref();
+        let mut created: Vec<PathBuf> = vec!();
+        for path in self.potential.iter() {
+            // Never create anything in a bind mount (i.e. the host) nor in the jail mounts
+            let mut binds = self.jdom.binds.iter().chain(self.jdom.grants.iter());
+            if binds.any(|x| path.starts_with(&x.dst)) ||
+                    self.protected_paths().iter().any(|x| path.starts_with(x)) {
+                continue;
+            }
+            if ! self.is_reachable(path) {
+                continue;
+            }
+            // Only directories can be placeholders, files are created by the bind mounts
+            let dir = match PathFd::open_beneath(&host, path).and_then(|x| x.as_file().metadata()) {
+                Ok(ref md) if md.is_dir() => Some(path.as_path()),
+                Ok(..) => path.parent(),
+                Err(_) => None,
+            };
+            let dir = match dir {
+                Some(d) => d,
+                None => continue,
+            };
+            let mut missing = vec!();
+            let mut cur = PathBuf::from("/");
+            for comp in dir.components().skip(1) {
+                cur.push(comp);
+                if PathFd::open_beneath(root, &cur).is_err() {
+                    missing.push(cur.clone());
+                }
+            }
+            match PathFd::create_dirs_beneath(root, dir, 0o555) {
+                Ok(..) => {
+                    debug!("Placeholder for {}", path.display());
+                    created.extend(missing);
+                }
+                Err(e) => debug!("Failed to create a placeholder for {}: {}", path.display(), e),
+            }
+        }
+        // All the placeholders must be created before sealing their parents
+        let flags = fs::MS_BIND | fs::MS_REMOUNT | fs::MS_RDONLY | fs::MS_NOSUID | fs::MS_NODEV |
+            fs::MS_NOEXEC;
+        for dir in top_dirs(&created) {
+            let full = nest_path(root, dir);
+            match mount(&full, &full, "none", &fs::MS_BIND, &None)
+                    .and_then(|_| mount("none", &full, "", &flags, &None)) {
+                Ok(()) => {}
+                Err(e) => warn!("Failed to seal the placeholder {}: {}", dir.display(), e),
+            }
+        }
+        self.placeholders.extend(created);
+    }
+
+    /// Remove the placeholders, including the ones not reachable anymore (e.g. before a domain
+    /// transition), relative to the current root
+    fn remove_placeholders(&mut self) {
+        let placeholders: Vec<PathBuf> = self.placeholders.drain(..).collect();
+        for dir in top_dirs(&placeholders) {
+            match umount(dir, &fs0::MNT_DETACH) {
+                Ok(..) => {}
+                Err(e) => warn!("Failed to unmount the placeholder {}: {}", dir.display(), e),
+            }
+        }
+        // A directory used as a mount point or not empty is not a placeholder anymore
+        for dir in placeholders.iter().rev() {
+            let _ = remove_dir(dir);
+        }
+    }
+
+    /// Check if a read access to `path` is allowed by the current domain or by a domain
+    /// transition, without transitioning
+    pub fn is_reachable<T>(&self, path: T) -> bool where T: AsRef<Path> {
+        let path = path.as_ref();
+        if self.jdom.released.iter().chain(self.deny.iter()).any(|x| path.starts_with(x)) {
+            return false;
+        }
+        match new_access(path, false, false) {
+            Ok(acl) => {
+                let acl = acl.into_iter().map(|x| Arc::new(x)).collect();
+                self.jdom.dom.reachable(&acl).is_some()
+            }
+            Err(()) => false,
+        }
+    }
+
+    /// Check if `path` is reachable or is a parent directory of a reachable path
+    pub fn leads_to_reachable<T>(&self, path: T) -> bool where T: AsRef<Path> {
+        let path = path.as_ref();
+        self.is_reachable(path) ||
+            self.potential.iter().any(|x| x.starts_with(path) && self.is_reachable(x))
+    }
+
+    /// Check if `path` is already accessible in the jail (i.e. no transition needed)
+    pub fn is_accessible<T>(&self, path: T, write: bool, exec: bool) -> bool where T: AsRef<Path> {
+        let path = path.as_ref();
+        if self.protected_paths().iter().any(|x| path.starts_with(x)) {
+            return true;
+        }
+        // The deepest bind mount hides the others
+        let bind = self.jdom.binds.iter().chain(self.jdom.grants.iter())
+            .filter(|x| ! x.write_only && path.starts_with(&x.dst))
+            .max_by_key(|x| x.dst.components().count());
+        match bind {
+            Some(b) => (b.writable || ! write) && (b.exec || ! exec),
+            None => false,
+        }
+    }
+
+    /// Import a bind mount granted by the portal, even if no domain allows it
+    pub fn grant_access(&mut self, path: PathBuf, write: bool) -> Result<(), String> {
+        let bind = BindMount::new(path.clone(), path).writable(write).from_parent(true);
+        match self._import_bind(&bind, true) {
+            Ok(()) => {}
+            Err(e) => return Err(format!("Failed to mount {}: {}", bind.dst.display(), e)),
+        }
+        info!("Granted {} access to {}", if write { "read-write" } else { "read-only" },
+              bind.dst.display());
+        // A trusted grant overrides a previous release
+        self.jdom.released = self.jdom.released.iter().filter(|x| ! x.starts_with(&bind.src))
+            .cloned().collect();
+        self.jdom.grants.push(bind);
+        self.access_changed();
+        Ok(())
+    }
+
+    /// Host path of `path` in the jail, from the deepest bind mount exposing it
+    fn host_path(&self, path: &Path) -> Option<PathBuf> {
+        self.jdom.binds.iter().chain(self.jdom.grants.iter())
+            .filter(|x| ! x.write_only && path.starts_with(&x.dst))
+            .max_by_key(|x| x.dst.components().count())
+            .and_then(|x| path.strip_prefix(&x.dst).ok().map(|rel| nest_path(&x.src, rel)))
+    }
+
+    /// Import `bind` if its source access is allowed by the current domain or by a reachable one
+    /// (cf. gain_access), and keep it as a grant to be released later
+    pub fn gain_bind(&mut self, bind: &BindMount) -> Result<(), String> {
+        // The policy only knows about the host paths, the destination is only checked against the
+        // protected paths (cf. stage_bind)
+        let src = if bind.from_parent {
+            bind.src.clone()
+        } else {
+            match self.host_path(&bind.src) {
+                Some(p) => p,
+                None => return Err(format!("No host path for {}", bind.src.display())),
+            }
+        };
+        let acl = match new_access(&src, bind.writable, bind.exec) {
+            Ok(a) => a,
+            Err(()) => return Err(format!("Invalid access to {}", src.display())),
+        };
+        // The domain bind mounts already expose the source at the same place
+        if src == bind.dst {
+            let result = self.gain_access(acl);
+            return if result.is_allowed() { Ok(()) } else { Err(result.to_string()) };
+        }
+        let grant = BindMount::new(src, bind.dst.clone()).writable(bind.writable)
+            .executable(bind.exec).from_parent(true);
+        let result = self.transition(acl, Some(&grant));
+        if result.is_allowed() { Ok(()) } else { Err(result.to_string()) }
+    }
+
+    /// Detach the bind mount on `path`, including its nested bind mounts, and forbid any future
+    /// access to it
+    pub fn release_access<T>(&mut self, path: T) -> Result<Vec<AccessData>, String>
+            where T: AsRef<Path> {
+        let path = path.as_ref();
+        if self.jdom.binds.iter().chain(self.jdom.grants.iter()).find(|x| x.dst == path).is_none() {
+            return Err(format!("No bind mount on {}", path.display()));
+        }
+        match umount(path, &fs0::MNT_DETACH) {
+            Ok(..) => {}
+            Err(e) => {
+                warn!("Failed to unmount {}: {}", path.display(), e);
+                return Err(format!("Failed to unmount {}: {}", path.display(), e));
+            }
+        }
+        let (mut released, binds): (Vec<BindMount>, Vec<BindMount>) = self.jdom.binds.clone()
+            .into_iter().partition(|x| x.dst.starts_with(path));
+        let (released_grants, grants): (Vec<BindMount>, Vec<BindMount>) = self.jdom.grants.clone()
+            .into_iter().partition(|x| x.dst.starts_with(path));
+        released.extend(released_grants);
+        self.jdom.binds = binds;
+        self.jdom.grants = grants;
+        // The access requests are checked against the host paths
+        self.jdom.released.extend(released.iter().map(|x| x.src.clone()));
+        // The propagated mounts were detached as well
+        let imported: Vec<PathBuf> = self.watch_imported.iter()
+            .filter(|&(_, dst)| dst.starts_with(path)).map(|(host, _)| host.clone()).collect();
+        for host in imported.iter() {
+            let _ = self.watch_imported.remove(host);
+        }
+        info!("Released access to {}", path.display());
+        self.access_changed();
+        Ok(released.into_iter().map(|x| x.into()).collect())
     }
 
     /// Map the current user to himself
@@ -248,22 +834,11 @@ impl<'a> Jail<'a> {
             "full",
             "urandom",
             ];
-        let mut devs: Vec<BindMount> = devs.iter().map(|dev| {
+        let devs: Vec<BindMount> = devs.iter().map(|dev| {
             let src = devdir.join(dev);
             BindMount::new(src.clone(), src).writable(true)
         }).collect();
 
-        // Add current TTY
-        // TODO: Add dynamic TTY list reload
-        match self.stdio {
-            // FIXME: Assume `s` begin with "/dev/"
-            Some(ref s) => {
-                let p = s.as_ref();
-                devs.push(BindMount::new(p.to_path_buf(), p.to_path_buf()).writable(true));
-            }
-            None => {}
-        }
-
         for dev in devs.iter() {
             debug!("Creating {}", dev.dst.display());
             let bind = BindMount::new(dev.src.clone(), nest_path(&self.root, &dev.dst))
@@ -272,6 +847,7 @@ impl<'a> Jail<'a> {
         }
         let links = &[
             ("fd", "/proc/self/fd"),
+            ("ptmx", "pts/ptmx"),
             ("random", "urandom")
             ];
         for &(dst, src) in links.iter() {
@@ -279,6 +855,7 @@ impl<'a> Jail<'a> {
             try!(soft_link(src, dst));
         }
         try!(self.add_tmpfs(&TmpfsMount::new(devdir.join("shm")).name("shm")));
+        try!(self.add_devpts(&devdir_full.join("pts")));
 
         // Seal /dev
         // TODO: Drop the root user to realy seal something…
@@ -288,6 +865,19 @@ impl<'a> Jail<'a> {
         Ok(())
     }
 
+    /// Mount a private devpts instance to let the jailed processes allocate their own TTYs
+    /// (e.g. terminal multiplexers, script, ssh -t)
+    fn add_devpts<T>(&self, ptsdir: T) -> io::Result<()> where T: AsRef<Path> {
+        let ptsdir = ptsdir.as_ref();
+        debug!("Creating devpts in {}", ptsdir.display());
+        try!(mkdir_if_not(ptsdir));
+        // The gid option is not used because the tty group may not be mapped in the user namespace
+        let opt = "newinstance,ptmxmode=0666,mode=0620";
+        let flags = fs::MS_NOSUID | fs::MS_NOEXEC;
+        try!(mount("devpts", ptsdir, "devpts", &flags, &Some(opt)));
+        Ok(())
+    }
+
     pub fn import_bind(&self, bind: &BindMount) -> io::Result<()> {
         // Do not create destination mount point
         self._import_bind(bind, false)
@@ -295,12 +885,18 @@ impl<'a> Jail<'a> {
 
     // FIXME: Handle non-directory mount
     fn _import_bind(&self, bind: &BindMount, create_dst: bool) -> io::Result<()> {
+        let tmp_dir = try!(self.stage_bind(bind));
+        self.commit_bind(bind, tmp_dir, create_dst)
+    }
+
+    /// Mount the `bind` tree in a temporary directory not reachable from the jail
+    fn stage_bind(&self, bind: &BindMount) -> io::Result<TmpWorkDir> {
         let workdir = match self.workdir {
             Some(ref w) => w,
             // TODO: Create a new error or a FSM for self.workdir
             None => return Err(io::Error::new(ErrorKind::Other, "No workdir")),
         };
-        // FIXME: Verify path traversal sanitization (e.g. no "..")
+        // The source and destination paths are resolved once, without following any symlink
         let parent = workdir.join(WORKDIR_PARENT);
         let (excludes, tmp_bind) = if bind.from_parent {
             // Protect parent process and dev listing
@@ -313,6 +909,13 @@ impl<'a> Jail<'a> {
                 }
                 None => {}
             }
+            match self.deny.iter().find(|x| bind.src.starts_with(x)) {
+                Some(d) => {
+                    warn!("Access denied to parent {}", d.display());
+                    return Err(io::Error::new(ErrorKind::PermissionDenied, "Access denied"));
+                }
+                None => {}
+            }
             // Relative path for src
             let mut tmp_bind = bind.clone();
             // Virtual source path to check sub mounts
@@ -336,7 +939,7 @@ impl<'a> Jail<'a> {
         let submounts = try!(self.expand_binds(vec!(tmp_bind), &excludes.iter().collect()));
         for mount in submounts.iter() {
             let mut mount = mount.clone();
-            if mount.from_parent {
+            let src = if mount.from_parent {
                 let rel_src = mount.src.clone();
                 let rel_src = match rel_src.strip_prefix(&parent) {
                     Ok(p) => p,
@@ -346,7 +949,17 @@ impl<'a> Jail<'a> {
                     }
                 };
                 mount.src = nest_path(&WORKDIR_PARENT, rel_src);
-            }
+                PathFd::open_beneath(&WORKDIR_PARENT, rel_src)
+            } else {
+                PathFd::open_beneath("/", &mount.src)
+            };
+            let src = match src {
+                Ok(s) => s,
+                Err(e) => {
+                    warn!("Failed to resolve the bind mount source {}: {}", mount.src.display(), e);
+                    return Err(e);
+                }
+            };
             let rel_dst = mount.dst.clone();
             let rel_dst = match rel_dst.strip_prefix(&bind.dst) {
                 Ok(p) => p,
@@ -356,7 +969,11 @@ impl<'a> Jail<'a> {
                 }
             };
             mount.dst = nest_path(&tmp_dir, rel_dst);
-            match self.add_bind(&mount, true) {
+            // The temporary directory is not reachable from the jail
+            let ret = create_same_type(&src, &mount.dst).and_then(|_| {
+                self.mount_bind(&mount, &src, &mount.dst)
+            });
+            match ret {
                 Ok(..) => {
                     // Unmount all previous mounts if an error occured
                     tmp_dir.unmount(true);
@@ -367,12 +984,31 @@ impl<'a> Jail<'a> {
                 }
             }
         }
+        if bind.from_parent {
+            try!(self.mask_denied(&bind.src, &tmp_dir));
+        }
 
+        Ok(tmp_dir)
+    }
+
+    /// Move a staged bind mount tree to its destination in the jail
+    fn commit_bind(&self, bind: &BindMount, mut tmp_dir: TmpWorkDir, create_dst: bool)
+            -> io::Result<()> {
         debug!("Moving bind mount from {} to {}", tmp_dir.as_ref().display(), bind.dst.display());
-        if create_dst {
-            try!(create_same_type(&tmp_dir, &bind.dst));
-        }
-        match mount(&tmp_dir, &bind.dst, "none", &fs::MS_MOVE, &None) {
+        let dst = if create_dst {
+            let is_dir = try!(tmp_dir.as_ref().metadata()).is_dir();
+            PathFd::create_beneath("/", &bind.dst, is_dir)
+        } else {
+            PathFd::open_beneath("/", &bind.dst)
+        };
+        let dst = match dst {
+            Ok(d) => d,
+            Err(e) => {
+                warn!("Failed to resolve the bind mount destination {}: {}", bind.dst.display(), e);
+                return Err(e);
+            }
+        };
+        match mount(&tmp_dir, &dst, "none", &fs::MS_MOVE, &None) {
             Ok(..) => tmp_dir.unmount(false),
             Err(e) => {
                 warn!("Failed to move the temporary mount point: {}", e);
@@ -382,26 +1018,153 @@ impl<'a> Jail<'a> {
         Ok(())
     }
 
-    // XXX: Impossible to keep a consistent read-only mount tree if a new mount is added after our
-    // bind mount. Will need to watch all the sources.
+    /// Jail path of a host sub mount, from the nearest bind mount (i.e. the longest source)
+    /// strictly containing it
+    fn watched_dst(&self, host: &Path) -> Option<(&BindMount, PathBuf)> {
+        let bind = match self.jdom.binds.iter()
+                .filter(|x| host.starts_with(&x.src) && host != x.src && !x.write_only)
+                .max_by_key(|x| x.src.components().count()) {
+            Some(b) => b,
+            None => return None,
+        };
+        match host.strip_prefix(&bind.src) {
+            Ok(rel_dst) => Some((bind, nest_path(&bind.dst, rel_dst))),
+            Err(_) => None,
+        }
+    }
+
+    /// Propagate the host mounts added (or removed) under the watched bind sources since the last
+    /// call, with the same properties as the enclosing bind mount
+    pub fn sync_watched_mounts(&mut self) {
+        let parent = match self.workdir {
+            Some(ref w) => w.join(WORKDIR_PARENT),
+            None => return,
+        };
+        let host_mounts: BTreeSet<PathBuf> = match get_submounts("/") {
+            Ok(list) => list.into_iter().filter_map(|mount| {
+                match mount.file.strip_prefix(&parent) {
+                    Ok(p) => Some(Path::new("/").join(p)),
+                    Err(_) => None,
+                }
+            }).filter(|x| self.watch.iter().any(|w| x.starts_with(w))).collect(),
+            Err(e) => {
+                warn!("Failed to get mount points: {}", e);
+                return;
+            }
+        };
+
+        // Detach the mounts removed from the host (e.g. unplugged device), either propagated or
+        // cloned with a recursive bind mount
+        let removed: Vec<PathBuf> = self.watch_seen.difference(&host_mounts).cloned().collect();
+        for host in removed.iter() {
+            let dst = match self.watch_imported.remove(host) {
+                Some(dst) => dst,
+                None => match self.watched_dst(host) {
+                    Some((_, dst)) => match get_mount(&dst) {
+                        Ok(Some(ref m)) if m.file == dst => dst,
+                        _ => continue,
+                    },
+                    None => continue,
+                },
+            };
+            debug!("Unmounting removed host mount {}", host.display());
+            match umount(&dst, &fs0::MNT_DETACH) {
+                Ok(..) => {}
+                // A mount inherited from the host is locked (cf. add_bind_tree)
+                Err(e) => warn!("Failed to unmount {}: {}", dst.display(), e),
+            }
+        }
+
+        // The mount points are ordered by path, so parents come first
+        for host in host_mounts.difference(&self.watch_seen) {
+            let (bind, dst) = match self.watched_dst(host) {
+                Some(x) => x,
+                // Not reachable from the current domain, imported with a future transition if any
+                None => continue,
+            };
+            // Already there (e.g. recursive bind mount or domain transition)
+            match get_mount(&dst) {
+                Ok(Some(ref m)) if m.file == dst => continue,
+                _ => {}
+            }
+            info!("Propagating host mount {}", host.display());
+            let new_bind = BindMount::new(host.clone(), dst.clone())
+                .writable(bind.writable).executable(bind.exec).from_parent(true);
+            match self._import_bind(&new_bind, true) {
+                Ok(..) => {
+                    let _ = self.watch_imported.insert(host.clone(), dst);
+                }
+                Err(e) => warn!("Failed to propagate {}: {}", host.display(), e),
+            }
+        }
+        self.watch_seen = host_mounts;
+    }
+
+    // XXX: Without the new mount API, impossible to keep a consistent read-only mount tree if a new
+    // mount is added after our bind mount, unless the source is watched (cf. sync_watched_mounts).
     // TODO: Try to not bind remount already read-only mounts
     fn add_bind(&self, bind: &BindMount, is_absolute: bool) -> io::Result<()> {
-        let dst = if is_absolute {
-            Borrowed(&bind.dst)
+        // The relative paths are resolved from the current directory
+        let beneath = |path: &Path| if path.is_relative() { Path::new(".") } else { Path::new("/") };
+        let dst_root = if is_absolute {
+            beneath(&bind.dst)
         } else {
-            Owned(nest_path(&self.root, &bind.dst))
+            self.root.as_path()
         };
-        let dst = &*dst;
-        let src = &bind.src;
 
         // TODO: Add better log (cf. parent)
-        debug!("Bind mounting from {}", src.display());
-        debug!("Bind mounting to {}", dst.display());
+        debug!("Bind mounting from {}", bind.src.display());
+        debug!("Bind mounting to {}", nest_path(dst_root, &bind.dst).display());
+
+        // The source and destination paths are resolved once, without following any symlink (cf.
+        // _import_bind), and the missing destination directories or file are created
+        let src = match PathFd::open_beneath(beneath(&bind.src), &bind.src) {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("Failed to resolve the bind mount source {}: {}", bind.src.display(), e);
+                return Err(e);
+            }
+        };
+        let is_dir = try!(src.as_file().metadata()).is_dir();
+        let dst = match PathFd::create_beneath(dst_root, &bind.dst, is_dir) {
+            Ok(d) => d,
+            Err(e) => {
+                warn!("Failed to resolve the bind mount destination {}: {}", bind.dst.display(), e);
+                return Err(e);
+            }
+        };
 
-        // Create needed directorie(s) and/or file
-        // XXX: This should be allowed for clients too
-        // FIXME: dst is a temporary destination!
-        try!(create_same_type(src, dst));
+        // A mount on a resolved path can't be changed afterwards (i.e. the path is then under the
+        // new mount), so it is prepared in a temporary directory first (cf. stage_bind)
+        let tmp_dir = try!(TmpWorkDir::new("bind"));
+        let tmp = tmp_dir.as_ref().join("mnt");
+        try!(create_same_type(&src, &tmp));
+        let ret = self.mount_bind(bind, &src, &tmp).and_then(|_| {
+            mount(&tmp, &dst, "none", &fs::MS_MOVE, &None)
+        });
+        if ret.is_err() {
+            let _ = umount(&tmp, &fs0::MNT_DETACH);
+        }
+        let _ = if is_dir {
+            remove_dir(&tmp)
+        } else {
+            remove_file(&tmp)
+        };
+        ret
+    }
+
+    /// Bind mount `src` to `dst` according to the `bind` properties, `bind.src` is only used to
+    /// get the source mount flags (e.g. when `src` is a resolved file descriptor path)
+    fn mount_bind<T, U>(&self, bind: &BindMount, src: T, dst: U) -> io::Result<()>
+            where T: AsRef<Path>, U: AsRef<Path> {
+        let src = src.as_ref();
+        let dst = dst.as_ref();
+        if bind.write_only {
+            return self.add_spool(src, dst);
+        }
+        if self.mount_api {
+            return self.add_bind_tree(bind, src, dst);
+        }
 
         let none_str = "none";
         // The fs/namespace.c:clone_mnt kernel function forbid unprivileged users (i.e.
@@ -409,17 +1172,17 @@ impl<'a> Jail<'a> {
         let bind_flags = fs::MS_BIND | fs::MS_REC;
         try!(mount(src, dst, none_str, &bind_flags, &None));
 
-        if ! bind.writable {
-            // When write action is forbiden we must not use the MS_REC to avoid unattended
-            // read/write files during the jail life.
+        if ! bind.writable || ! bind.exec {
+            // When write (or execute) action is forbiden we must not use the MS_REC to avoid
+            // unattended read/write (or executable) files during the jail life.
             let none_path = "none";
-            // Seal the vfsmount: good to not receive new mounts but block unmount as well
-            // TODO: Add a "unshare <path>" command to remove a to-be-unmounted path
+            // Seal the vfsmount: good to not receive new mounts but block unmount as well (cf.
+            // release_access)
             let bind_flags = fs::MS_PRIVATE | fs::MS_REC;
             try!(mount(&none_path, dst, none_str, &bind_flags, &None));
 
             // Take the same mount flags as the source
-            let flags = match get_mount(&src) {
+            let flags = match get_mount(&bind.src) {
                 Ok(Some(mount)) => mount.mntops.iter().filter_map(|x| {
                     // Cf. linux/fs/namespace.c:do_remount
                     match *x {
@@ -438,15 +1201,165 @@ impl<'a> Jail<'a> {
             // Remount read-only, even if the source is already read-only, to be sure to control
             // the destination mount point properties during all its life (e.g. the parent
             // namespace can remount the source read-write).
-            let bind_flags = fs::MS_BIND | fs::MS_REMOUNT | fs::MS_RDONLY | flags;
+            let mut bind_flags = fs::MS_BIND | fs::MS_REMOUNT | flags;
+            if ! bind.writable {
+                bind_flags = bind_flags | fs::MS_RDONLY;
+            }
+            if ! bind.exec {
+                bind_flags = bind_flags | fs::MS_NOEXEC;
+            }
             try!(mount(&none_path, dst, none_str, &bind_flags, &None));
         }
         Ok(())
     }
 
+    /// Bind mount with the new mount API: the read-only, noexec and source attributes are
+    /// atomically applied to the whole mount tree
+    fn add_bind_tree<T, U>(&self, bind: &BindMount, src: T, dst: U) -> io::Result<()>
+            where T: AsRef<Path>, U: AsRef<Path> {
+        let dst = dst.as_ref();
+        let tree = try!(open_tree(src));
+        // Take the same mount flags as the source (cf. mount_bind)
+        let mut attr = match get_mount(&bind.src) {
+            Ok(Some(mount)) => mount.mntops.iter().filter_map(|x| {
+                match *x {
+                    MntOps::Dev(false) => Some(MOUNT_ATTR_NODEV),
+                    MntOps::Exec(false) => Some(MOUNT_ATTR_NOEXEC),
+                    MntOps::Suid(false) => Some(MOUNT_ATTR_NOSUID),
+                    MntOps::Write(false) => Some(MOUNT_ATTR_RDONLY),
+                    _ => None,
+                }
+            }).fold(MountAttr::empty(), |x, y| x | y),
+            _ => MountAttr::empty(),
+        };
+        if ! bind.writable {
+            attr = attr | MOUNT_ATTR_RDONLY;
+        }
+        if ! bind.exec {
+            attr = attr | MOUNT_ATTR_NOEXEC;
+        }
+        if ! attr.is_empty() {
+            // Seal the mount tree (cf. add_bind)
+            try!(mount_setattr(&tree, &attr, &fs::MS_PRIVATE));
+        }
+        try!(move_mount(&tree, dst));
+
+        // A detached tree can't be partially unmounted, the children are detached first
+        for rel in bind.excludes.iter().rev() {
+            let path = nest_path(dst, rel);
+            match umount(&path, &fs0::MNT_DETACH) {
+                Ok(()) => debug!("Detached {}", path.display()),
+                // The mounts inherited from the parent namespace are locked to the tree, but they
+                // are then only under protected paths (cf. protected_paths)
+                Err(ref e) if e.raw_os_error() == Some(EINVAL) => {
+                    debug!("Keeping the locked mount {}", path.display());
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Mount an empty spool on `dst` for the `src` drop directory, the host directory content is
+    /// then never exposed to the jail
+    fn add_spool<T, U>(&self, src: T, dst: U) -> io::Result<()>
+            where T: AsRef<Path>, U: AsRef<Path> {
+        if ! try!(src.as_ref().metadata()).is_dir() {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "A drop directory must be a directory"));
+        }
+        // The jailed processes own the spool and can change its mode, but they can only list
+        // their own files anyway
+        let flags = fs::MS_NOSUID | fs::MS_NODEV | fs::MS_NOEXEC;
+        mount("spool", dst, "tmpfs", &flags, &Some(SPOOL_OPTIONS))
+    }
+
+    /// Move the spooled files to their drop directories, except the recently modified ones
+    /// unless `all` is set (e.g. when the jail exits)
+    pub fn flush_spools(&self, all: bool) {
+        for bind in self.jdom.binds.iter().filter(|x| x.write_only) {
+            match self.flush_spool(bind, all) {
+                Ok(0) => {}
+                Ok(n) => debug!("Moved {} file(s) to {}", n, bind.src.display()),
+                Err(e) => warn!("Failed to flush the spool {}: {}", bind.dst.display(), e),
+            }
+        }
+    }
+
+    fn flush_spool(&self, bind: &BindMount, all: bool) -> io::Result<usize> {
+        // The drop directory is never exposed to the jail, so it is resolved from the parent root
+        let host = try!(PathFd::open_beneath(&WORKDIR_PARENT, &bind.src));
+        let spool = try!(PathFd::open_beneath("/", &bind.dst));
+        let now = unsafe { time(ptr::null_mut()) } as i64;
+        let mut count = 0;
+        for entry in try!(read_dir(&spool)) {
+            let name = try!(entry).file_name();
+            // Only the regular files are moved, the jail can't follow a symlink on the host, and
+            // opening a FIFO must not block the monitor
+            let flags = O_RDONLY | O_NONBLOCK | O_NOFOLLOW | O_CLOEXEC;
+            let mut src = match openat(spool.as_file(), &name, flags) {
+                Ok(f) => f,
+                Err(e) => {
+                    debug!("Ignoring the spooled file {:?}: {}", name, e);
+                    continue;
+                }
+            };
+            let meta = try!(src.metadata());
+            if ! meta.is_file() {
+                debug!("Ignoring spooled non-regular file {:?}", name);
+                continue;
+            }
+            // The file may still be written
+            if ! all && now - meta.mtime() < SPOOL_DELAY as i64 {
+                continue;
+            }
+            if self.deny.iter().any(|x| nest_path(&bind.src, &name).starts_with(x)) {
+                warn!("Keeping the spooled file {:?}: denied in {}", name, bind.src.display());
+                continue;
+            }
+            // Copy to a temporary name first to never expose a partial file
+            let mut tmp_name = OsString::from(SPOOL_TMP_PREFIX);
+            tmp_name.push(&name);
+            let flags = O_WRONLY | O_CREAT | O_EXCL | O_NOFOLLOW | O_CLOEXEC;
+            let mut dst = match openat(host.as_file(), &tmp_name, flags) {
+                Ok(f) => f,
+                Err(ref e) if e.kind() == ErrorKind::AlreadyExists => {
+                    warn!("Keeping the spooled file {:?}: {:?} already in {}", name, tmp_name,
+                          bind.src.display());
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let moved = io::copy(&mut src, &mut dst).and_then(|_| {
+                // Never overwrite an existing host file
+                renameat_noreplace(host.as_file(), &tmp_name, &name)
+            });
+            match moved {
+                Ok(()) => {}
+                Err(e) => {
+                    let _ = unlinkat(host.as_file(), &tmp_name);
+                    if e.kind() == ErrorKind::AlreadyExists {
+                        warn!("Keeping the spooled file {:?}: already in {}", name, bind.src.display());
+                        continue;
+                    }
+                    return Err(e);
+                }
+            }
+            // The jail may have replaced the spooled file since it was opened
+            let spooled = try!(openat(spool.as_file(), &name, raw::O_PATH | O_NOFOLLOW | O_CLOEXEC));
+            let spooled = try!(spooled.metadata());
+            if spooled.dev() != meta.dev() || spooled.ino() != meta.ino() {
+                warn!("Keeping the spooled file {:?}: replaced while moved", name);
+                continue;
+            }
+            try!(unlinkat(spool.as_file(), &name));
+            count += 1;
+        }
+        Ok(count)
+    }
+
     fn expand_binds<T>(&self, binds: Vec<BindMount>, excludes: &Vec<T>)
             -> io::Result<Vec<BindMount>> where T: AsRef<Path> {
-        let host_mounts: Vec<_> = match get_submounts("/") {
+        let (excluded_mounts, host_mounts): (Vec<_>, Vec<_>) = match get_submounts("/") {
             Ok(list) => {
                 let proc_path = "/proc";
                 // Exclude workdir from overlaps detection because workdir/parent contains moved
@@ -456,12 +1369,8 @@ impl<'a> Jail<'a> {
                     Some(ref w) => vec!(&proc_path, w),
                 };
                 // FIXME: Verify remove_overlaps() implementation for missed mount points
-                list.remove_overlaps(&excludes_overlaps).into_iter().filter(
-                    |mount| {
-                        excludes.iter().skip_while(
-                            |path| !mount.file.starts_with(path)
-                        ).next().is_none()
-                    }).collect()
+                list.remove_overlaps(&excludes_overlaps).into_iter().partition(
+                    |mount| excludes.iter().any(|path| mount.file.starts_with(path)))
             },
             Err(e) => {
                 // TODO: Add FromError impl to io::Result
@@ -473,11 +1382,25 @@ impl<'a> Jail<'a> {
         // Need to keep the mount points order and prioritize the last (i.e. user) mount points
         let mut all_binds: Vec<BindMount> = vec!();
         for bind in binds.into_iter() {
-            // FIXME: Extend the full path (like "readlink -f") to not recursively mount
-            let sub_binds = if bind.writable {
+            // Symlinks are not followed to get the sub mounts, but add_bind() and _import_bind()
+            // refuse them anyway
+            let sub_binds = if self.mount_api && ! bind.write_only {
+                // The new mount API handle recursive read-only and noexec bind mounts, but the
+                // whole tree is cloned, including the excluded mounts
+                let mut tree = bind.clone();
+                tree.excludes = excluded_mounts.iter().filter_map(|mount| {
+                    match mount.file.strip_prefix(&bind.src) {
+                        Ok(p) if p != Path::new("") => Some(p.to_path_buf()),
+                        _ => None,
+                    }
+                }).collect();
+                tree.excludes.sort();
+                vec!(tree)
+            } else if (bind.writable && bind.exec) || bind.write_only {
                 vec!(bind.clone())
             } else {
-                // Complete with all child mount points if needed (i.e. read-only mount tree)
+                // Complete with all child mount points if needed (i.e. read-only or noexec mount
+                // tree)
                 let mut sub_binds = vec!(bind.clone());
                 // Take bind sub mounts
                 for mount in host_mounts.iter() {
@@ -492,7 +1415,8 @@ impl<'a> Jail<'a> {
                         };
                         // Extend bind with same attributes
                         let new_bind = BindMount::new(sub_src, nest_path(&bind.dst, &rel_dst))
-                            .writable(bind.writable).from_parent(bind.from_parent);
+                            .writable(bind.writable).executable(bind.exec)
+                            .from_parent(bind.from_parent);
                         sub_binds.push(new_bind);
                     }
                 }
@@ -532,15 +1456,107 @@ impl<'a> Jail<'a> {
 
     fn protected_paths(&self) -> Vec<&Path> {
         // Protect custom procfs, devices and monitor socket directories
-        vec!(
+        let mut paths = vec!(
             Path::new("/dev"),
             Path::new("/proc"),
             Path::new("/tmp"),
-        )
+        );
+        if self.sysfs {
+            paths.push(Path::new("/sys"));
+        }
+        paths
+    }
+
+    fn init_proc<T>(&self, procdir: T) -> io::Result<()> where T: AsRef<Path> {
+        let procdir = procdir.as_ref();
+        info!("Populating {}", procdir.display());
+        let procdir_full = nest_path(&self.root, &procdir);
+        try!(mkdir_if_not(&procdir_full));
+        let flags = fs::MS_NOSUID | fs::MS_NODEV | fs::MS_NOEXEC;
+        let subset = self.procfs.subset_pid.unwrap_or(false);
+        let opt = proc_mount_options(&self.procfs, subset);
+        match mount("proc", &procdir_full, "proc", &flags, &opt.as_ref().map(|x| x.as_str())) {
+            Ok(..) => {}
+            // The subset option is only supported since Linux 5.8
+            Err(ref e) if subset && e.raw_os_error() == Some(EINVAL) => {
+                warn!("Failed to mount procfs with subset=pid, fallback without it");
+                let opt = proc_mount_options(&self.procfs, false);
+                try!(mount("proc", &procdir_full, "proc", &flags, &opt.as_ref().map(|x| x.as_str())));
+            }
+            Err(e) => return Err(e),
+        }
+
+        // Mask the sensitive entries
+        let hide = proc_paths(&self.procfs.hide, &PROC_HIDE);
+        let read_only = proc_paths(&self.procfs.read_only, &PROC_READ_ONLY);
+        for path in hide.iter().chain(read_only.iter()) {
+            if ! path.starts_with(procdir) {
+                warn!("Ignoring procfs entry outside {}: {}", procdir.display(), path.display());
+            }
+        }
+        for path in hide.iter().filter(|x| x.starts_with(procdir)) {
+            try!(self.hide_path(path, nest_path(&self.root, path)));
+        }
+        for path in read_only.iter().filter(|x| x.starts_with(procdir)) {
+            let dst = nest_path(&self.root, path);
+            if dst.symlink_metadata().is_ok() {
+                debug!("Sealing {}", path.display());
+                try!(self.add_bind(&BindMount::new(dst.clone(), dst), true));
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace `dst` (i.e. the `path` entry) with an empty file or directory
+    fn hide_path<T, U>(&self, path: T, dst: U) -> io::Result<()>
+            where T: AsRef<Path>, U: AsRef<Path> {
+        let path = path.as_ref();
+        let dst = dst.as_ref();
+        match dst.symlink_metadata() {
+            Ok(md) => if md.is_dir() {
+                debug!("Hiding directory {}", path.display());
+                let flags = fs::MS_RDONLY | fs::MS_NOSUID | fs::MS_NODEV | fs::MS_NOEXEC;
+                try!(mount("hide", dst, "tmpfs", &flags, &Some("mode=0500")));
+            } else {
+                debug!("Hiding file {}", path.display());
+                try!(self.add_bind(&BindMount::new(PathBuf::from("/dev/null"), dst.to_path_buf()), true));
+            },
+            // Not all entries are available with every kernels
+            Err(e) => debug!("Skipping {}: {}", path.display(), e),
+        }
+        Ok(())
+    }
+
+    /// Hide the denied paths nested in the `src` host directory mounted on `dst`
+    fn mask_denied<T, U>(&self, src: T, dst: U) -> io::Result<()>
+            where T: AsRef<Path>, U: AsRef<Path> {
+        let src = src.as_ref();
+        for path in self.deny.iter() {
+            match path.strip_prefix(src) {
+                Ok(rel) => try!(self.hide_path(path, nest_path(&dst, rel))),
+                Err(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn init_sys<T>(&self, sysdir: T) -> io::Result<()> where T: AsRef<Path> {
+        let sysdir = sysdir.as_ref();
+        info!("Populating {}", sysdir.display());
+        let sysdir_full = nest_path(&self.root, &sysdir);
+        try!(mkdir_if_not(&sysdir_full));
+        // Only allowed because run() also creates a network namespace owned by the jail user
+        // namespace
+        let flags = fs::MS_RDONLY | fs::MS_NOSUID | fs::MS_NODEV | fs::MS_NOEXEC;
+        try!(mount("sysfs", &sysdir_full, "sysfs", &flags, &None));
+        Ok(())
     }
 
     // TODO: impl Drop to unmount and remove mount directories/files
     fn init_fs(&mut self) -> io::Result<()> {
+        self.mount_api = has_mount_api();
+        debug!("New mount API: {}", if self.mount_api { "available" } else { "unavailable" });
+
         // Create an empty and writable root to be able to add any bind mounts
         // FIXME: Seal the root
         try!(self.add_tmpfs(&TmpfsMount::new(self.root.clone()).name("root").is_root(true)));
@@ -551,10 +1567,17 @@ impl<'a> Jail<'a> {
             exclude.push(self.root.as_ref());
             exclude
         }));
+        // The bind mounts are prepared in the root
+        try!(env::set_current_dir(&self.root));
         for bind in all_binds.iter() {
             try!(self.add_bind(bind, false));
         }
-        try!(env::set_current_dir(&self.root));
+        for bind in self.jdom.binds.iter() {
+            try!(self.mask_denied(&bind.src, nest_path(&self.root, &bind.dst)));
+        }
+        let root = self.root.clone();
+        self.add_links(&root);
+        self.add_placeholders(&root, "/");
 
         // TODO: Check all bind and tmpfs mount points consistency
         for tmp in self.tmps.iter() {
@@ -562,11 +1585,12 @@ impl<'a> Jail<'a> {
         }
 
         // procfs
-        let proc_src = "proc";
-        let proc_dst = self.root.join(&proc_src);
-        try!(mkdir_if_not(&proc_dst));
-        let proc_flags = fs::MsFlags::empty();
-        try!(mount(&proc_src, &proc_dst, "proc", &proc_flags, &None));
+        try!(self.init_proc("/proc"));
+
+        // sysfs
+        if self.sysfs {
+            try!(self.init_sys("/sys"));
+        }
 
         // Devices
         try!(self.init_dev("/dev"));
@@ -626,11 +1650,23 @@ impl<'a> Jail<'a> {
             Ok(p) => (p.reader, p.writer),
             Err(e) => panic!("Failed to create pipe #3: {}", e),
         };
+        let (grant_portal, grant_monitor) = match UnixStream::pair() {
+            Ok(p) => p,
+            Err(e) => panic!("Failed to create the grant channel: {}", e),
+        };
+        // The jailed processes must not inherit the privileged channel
+        for fd in [grant_portal.as_raw_fd(), grant_monitor.as_raw_fd()].iter() {
+            match unsafe { fcntl(*fd, F_SETFD, FD_CLOEXEC) } {
+                -1 => panic!("Failed to protect the grant channel: {}", Error::last_os_error()),
+                _ => {}
+            }
+        }
 
         let (mut slave_fd, stdin, stdout, stderr) = match stdio {
             // TODO: Use pipes if no TTY
             Some(mut s) => {
-                // XXX: The TTY must be new
+                // The session TTY is only used as the standard I/O of the jailed process, other
+                // TTYs are allocated from the jail devpts instance.
                 let slave_fd = s.take_slave_fd().unwrap();
                 let fd = slave_fd.as_raw_fd();
                 //tty::set_nonblock(&fd);
@@ -663,6 +1699,7 @@ impl<'a> Jail<'a> {
         } else if pid == 0 {
             // Child
             drop(jail_pid_rx);
+            drop(grant_portal);
             info!("Child jailing");
             // Become a process group leader
             // TODO: Change behavior for dedicated TTY
@@ -705,6 +1742,8 @@ impl<'a> Jail<'a> {
                     Ok(_) => {}
                     Err(e) => panic!("Failed to initialize the file system: {}", e),
                 }
+                // Need the jail's procfs
+                self.init_shared_cache();
                 // A normal user must not be able to drop groups to avoid permission bypass (cf.
                 // user_namespaces(7): the setgroups file)
 
@@ -726,13 +1765,45 @@ impl<'a> Jail<'a> {
                         Err(..) => {}
                     }
                 }
-                let mut process = match cmd.spawn() {
-                    Ok(p) => p,
-                    Err(e) => panic!("Failed to execute process: {}", e),
+                let (cmd_tx, cmd_rx) = channel();
+                let started = Arc::new(AtomicBool::new(false));
+                if self.seccomp {
+                    start_broker(&mut cmd, cmd_tx.clone(), started.clone());
+                }
+                let (child_tx, child_rx) = channel();
+                let (process_id, child_thread) = if self.trace {
+                    spawn_traced(cmd, cmd_tx.clone(), child_tx)
+                } else {
+                    let mut process = match cmd.spawn() {
+                        Ok(p) => p,
+                        Err(e) => panic!("Failed to execute process: {}", e),
+                    };
+                    let process_id = process.id();
+                    let child_thread = thread::spawn(move || {
+                        'child: loop {
+                            let child_ret = process.wait();
+                            match child_ret {
+                                Ok(ret) => {
+                                    debug!("Jail child (PID {}) exited with {}", process.id(), ret);
+                                    break 'child;
+                                }
+                                Err(ref e) if e.kind() == ErrorKind::TimedOut => {}
+                                Err(e) => {
+                                    warn!("Failed to wait for child (PID {}): {}", process.id(), e);
+                                    let _ = process.kill();
+                                    break 'child;
+                                }
+                            }
+                        }
+                        let _ = child_tx.send(());
+                    });
+                    (process_id, child_thread)
                 };
+                // The execution of the command itself must not wait for the monitor
+                started.store(true, Relaxed);
                 // Need to keep the slave TTY open until passing to the child
                 drop(slave_fd.take());
-                match jail_pid_tx.write_i32::<LittleEndian>(process.id() as pid_t) {
+                match jail_pid_tx.write_i32::<LittleEndian>(process_id as pid_t) {
                     Ok(()) => {}
                     Err(e) => panic!("Failed to send child PID: {}", e),
                 }
@@ -740,8 +1811,6 @@ impl<'a> Jail<'a> {
                 // TODO: Forward the ProcessExit to the jail object
 
                 let quit = Arc::new(AtomicBool::new(false));
-                let (cmd_tx, cmd_rx) = channel();
-                let (child_tx, child_rx) = channel();
 
                 let events = Select::new();
                 // Handle events from cmd::* using self
@@ -751,28 +1820,33 @@ impl<'a> Jail<'a> {
                 let mut child_handle = events.handle(&child_rx);
                 unsafe { child_handle.add() };
 
-                let cmd_quit = quit.clone();
-                let cmd_thread = thread::spawn(move || {
-                    srv::monitor_listen(cmd_tx, cmd_quit);
+                let watch_thread = if self.watch.is_empty() {
+                    None
+                } else {
+                    // Record the current host mounts
+                    self.sync_watched_mounts();
+                    let watch_tx = cmd_tx.clone();
+                    let watch_quit = quit.clone();
+                    Some(thread::spawn(move || {
+                        watch_mounts(watch_tx, watch_quit);
+                    }))
+                };
+
+                // Not joined: blocked until the portal closes the channel
+                let grant_tx = cmd_tx.clone();
+                let protected = self.protected.clone();
+                let _ = thread::spawn(move || {
+                    srv::monitor_grant_listen(grant_monitor, grant_tx, protected);
                 });
 
-                let child_thread = thread::spawn(move || {
-                    'child: loop {
-                        let child_ret = process.wait();
-                        match child_ret {
-                            Ok(ret) => {
-                                debug!("Jail child (PID {}) exited with {}", process.id(), ret);
-                                break 'child;
-                            }
-                            Err(ref e) if e.kind() == ErrorKind::TimedOut => {}
-                            Err(e) => {
-                                warn!("Failed to wait for child (PID {}): {}", process.id(), e);
-                                let _ = process.kill();
-                                break 'child;
-                            }
-                        }
-                    }
-                    let _ = child_tx.send(());
+                // The spools are flushed when the jail exits
+                self.spool_tx = Some(cmd_tx.clone());
+                self.update_spool_ticker();
+
+                let cmd_quit = quit.clone();
+                let request_size_max = self.request_size_max;
+                let cmd_thread = thread::spawn(move || {
+                    srv::monitor_listen(cmd_tx, cmd_quit, request_size_max);
                 });
 
                 // Wait for client commands and child event
@@ -792,6 +1866,12 @@ impl<'a> Jail<'a> {
                         panic!("Received unknown event");
                     }
                 }
+                self.spool_tx = None;
+                match self.spool_quit.take() {
+                    Some(quit) => quit.store(true, Relaxed),
+                    None => {}
+                }
+                self.flush_spools(true);
 
                 quit.store(true, Relaxed);
                 // TODO: Handle thread error
@@ -804,11 +1884,19 @@ impl<'a> Jail<'a> {
                 // TODO: Handle thread error
                 let _ = cmd_thread.join();
                 debug!("Jail command monitor exited");
+                match watch_thread {
+                    Some(t) => {
+                        let _ = t.join();
+                        debug!("Jail mount watcher exited");
+                    }
+                    None => {}
+                }
                 unsafe { exit(0); }
             } else {
                 // Parent
                 drop(jail_pid_tx);
                 drop(slave_fd.take());
+                drop(grant_monitor);
                 let mut status: c_int = 0;
                 // TODO: Replace waitpid(2) with wait(2)
                 let _ = unsafe { raw::waitpid(pid, &mut status, 0) };
@@ -818,6 +1906,8 @@ impl<'a> Jail<'a> {
             // Parent
             drop(jail_pid_tx);
             drop(slave_fd.take());
+            drop(grant_monitor);
+            self.grant_channel = Some(grant_portal);
             // TODO: Send fail command to the child if any error
             let _ = sync_parent.reader.read_i8();
             match self.init_userns(pid) {
@@ -879,3 +1969,716 @@ impl<'a> Jail<'a> {
         }
     }
 }
+
+#[derive(Debug)]
+struct MountEvent;
+
+impl JailFn for MountEvent {
+    fn call(&mut self, jail: &mut Jail) {
+        jail.sync_watched_mounts();
+    }
+}
+
+/// Notify the monitor when the mount table change (cf. proc(5): /proc/[pid]/mountinfo)
+fn watch_mounts(cmd_tx: Sender<Box<JailFn>>, quit: Arc<AtomicBool>) {
+    let mountinfo = match File::open("/proc/self/mountinfo") {
+        Ok(f) => f,
+        Err(e) => {
+            warn!("Failed to watch the mount points: {}", e);
+            return;
+        }
+    };
+    while !quit.load(Relaxed) {
+        let mut fds = [pollfd {
+            fd: mountinfo.as_raw_fd(),
+            events: POLLPRI,
+            revents: 0,
+        }];
+        // Regularly check the quit event
+        match unsafe { poll(fds.as_mut_ptr(), 1, 1000) } {
+            -1 => {
+                let e = Error::last_os_error();
+                if e.kind() != ErrorKind::Interrupted {
+                    warn!("Failed to watch the mount points: {}", e);
+                    return;
+                }
+            }
+            0 => {}
+            _ => if fds[0].revents & (POLLPRI | POLLERR) != 0 {
+                if cmd_tx.send(Box::new(MountEvent)).is_err() {
+                    return;
+                }
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SpoolEvent;
+
+impl JailFn for SpoolEvent {
+    fn call(&mut self, jail: &mut Jail) {
+        jail.flush_spools(false);
+    }
+}
+
+/// Let the syscall broker detect the accesses of `cmd` and all its children
+#[cfg(target_arch="x86_64")]
+fn start_broker(cmd: &mut Command, cmd_tx: Sender<Box<JailFn>>, started: Arc<AtomicBool>) {
+    let (broker_cmd, broker_monitor) = match UnixStream::pair() {
+        Ok(p) => p,
+        Err(e) => panic!("Failed to create the broker channel: {}", e),
+    };
+    // Not joined: stopped when all the jailed processes exit
+    let _ = thread::spawn(move || {
+        seccomp::broker_listen(broker_monitor, cmd_tx, started);
+    });
+    // The filter is inherited by all the jailed processes
+    let _ = unsafe { cmd.before_exec(move || seccomp::install_filter(&broker_cmd)) };
+}
+
+/// Refused by RunRequest::check()
+#[cfg(not(target_arch="x86_64"))]
+fn start_broker(_: &mut Command, _: Sender<Box<JailFn>>, _: Arc<AtomicBool>) {
+    panic!("No syscall broker for this architecture");
+}
+
+/// Execute `cmd` traced by a dedicated thread, which notifies `child_tx` when it exits
+#[cfg(target_arch="x86_64")]
+fn spawn_traced(cmd: Command, cmd_tx: Sender<Box<JailFn>>, child_tx: Sender<()>)
+        -> (u32, thread::JoinHandle<()>) {
+    let (pid_tx, pid_rx) = channel();
+    let child_thread = thread::spawn(move || {
+        trace::trace_command(cmd, pid_tx, cmd_tx, child_tx);
+    });
+    match pid_rx.recv() {
+        Ok(Ok(pid)) => (pid, child_thread),
+        Ok(Err(e)) => panic!("Failed to execute process: {}", e),
+        Err(e) => panic!("Failed to receive the child PID: {}", e),
+    }
+}
+
+/// Refused by RunRequest::check()
+#[cfg(not(target_arch="x86_64"))]
+fn spawn_traced(_: Command, _: Sender<Box<JailFn>>, _: Sender<()>) -> (u32, thread::JoinHandle<()>) {
+    panic!("No syscall tracer for this architecture");
+}
+
+/// Get the directories which are not nested in another one of `dirs`
+fn top_dirs(dirs: &Vec<PathBuf>) -> Vec<&PathBuf> {
+    dirs.iter().filter(|x| match x.parent() {
+        Some(p) => ! dirs.iter().any(|y| y == p),
+        None => true,
+    }).collect()
+}
+
+/// Regularly ask the monitor to flush the spools, until `quit` or it stops listening
+fn spool_ticker(cmd_tx: Sender<Box<JailFn>>, quit: Arc<AtomicBool>) {
+    loop {
+        thread::sleep(Duration::from_secs(SPOOL_DELAY));
+        if quit.load(Relaxed) || cmd_tx.send(Box::new(SpoolEvent)).is_err() {
+            return;
+        }
+    }
+}
+
+/// Build the procfs mount options
+fn proc_mount_options(config: &ProcConfig, subset: bool) -> Option<String> {
+    let mut opts = vec!();
+    match config.hidepid {
+        Some(h) => opts.push(format!("hidepid={}", h)),
+        None => {}
+    }
+    if subset {
+        opts.push("subset=pid".to_string());
+    }
+    if opts.is_empty() {
+        None
+    } else {
+        Some(opts.join(","))
+    }
+}
+
+/// Use the configured procfs entries or the default ones
+fn proc_paths(config: &Option<Vec<String>>, default: &[&str]) -> Vec<PathBuf> {
+    match *config {
+        Some(ref paths) => paths.iter().map(|x| PathBuf::from(x)).collect(),
+        None => default.iter().map(|x| PathBuf::from(x)).collect(),
+    }
+}
+
+#[test]
+fn test_proc_mount_options() {
+    let mut config = ProcConfig::default();
+    assert_eq!(proc_mount_options(&config, false), None);
+    assert_eq!(proc_mount_options(&config, true), Some("subset=pid".to_string()));
+    config.hidepid = Some(2);
+    assert_eq!(proc_mount_options(&config, false), Some("hidepid=2".to_string()));
+    assert_eq!(proc_mount_options(&config, true), Some("hidepid=2,subset=pid".to_string()));
+}
+
+#[test]
+fn test_proc_paths() {
+    let default = proc_paths(&None, &PROC_HIDE);
+    assert_eq!(default.len(), PROC_HIDE.len());
+    assert!(default.contains(&PathBuf::from("/proc/kcore")));
+    assert!(proc_paths(&Some(vec!()), &PROC_HIDE).is_empty());
+    assert_eq!(proc_paths(&Some(vec!("/proc/foo".to_string())), &PROC_READ_ONLY),
+               vec!(PathBuf::from("/proc/foo")));
+}
+
+/// Get the mount point, the mount options, the file system type and options, and the mounted
+/// directory of each mount of the current process
+#[cfg(test)]
+fn test_mountinfo() -> Vec<(PathBuf, String, String, String, PathBuf)> {
+    let mut content = String::new();
+    File::open("/proc/self/mountinfo").unwrap().read_to_string(&mut content).unwrap();
+    content.lines().filter_map(|line| {
+        let mut fields = line.split(" - ");
+        let (mount, fs) = match (fields.next(), fields.next()) {
+            (Some(m), Some(f)) => (m.split(' ').collect::<Vec<_>>(), f.split(' ').collect::<Vec<_>>()),
+            _ => return None,
+        };
+        if mount.len() < 6 || fs.len() < 3 {
+            return None;
+        }
+        Some((PathBuf::from(mount[4]), mount[5].to_string(), fs[0].to_string(), fs[2].to_string(),
+              PathBuf::from(mount[3])))
+    }).collect()
+}
+
+/// Check the procfs and sysfs mounts in new namespaces
+#[cfg(test)]
+fn test_proc_sys_jailed(jail: &Jail) -> Result<(), String> {
+    try!(mount("root", &jail.root, "tmpfs", &fs::MsFlags::empty(), &None).map_err(|e| e.to_string()));
+    try!(env::set_current_dir(&jail.root).map_err(|e| e.to_string()));
+    try!(jail.init_proc("/proc").map_err(|e| format!("Failed to init procfs: {}", e)));
+    try!(jail.init_sys("/sys").map_err(|e| format!("Failed to init sysfs: {}", e)));
+
+    let mounts = test_mountinfo();
+    // The last mount hides the previous ones
+    let find = |path: &Path| mounts.iter().rev().find(|x| x.0 == path);
+    match find(&jail.root.join("proc")) {
+        Some(m) => {
+            if m.2 != "proc" || ! m.1.contains("nosuid,nodev,noexec") {
+                return Err(format!("Bad procfs mount: {} {}", m.2, m.1));
+            }
+            if ! m.3.contains("hidepid=2") && ! m.3.contains("hidepid=invisible") {
+                return Err(format!("No hidepid for procfs: {}", m.3));
+            }
+        }
+        None => return Err("No procfs".to_string()),
+    }
+    // The entries not available with this kernel are skipped
+    for path in PROC_HIDE.iter().chain(PROC_READ_ONLY.iter()).map(|x| nest_path(&jail.root, x)) {
+        match (path.symlink_metadata().is_ok(), find(&path)) {
+            (false, _) => {}
+            (true, None) => return Err(format!("Not masked: {}", path.display())),
+            (true, Some(m)) => if ! m.1.starts_with("ro,") {
+                return Err(format!("Not read-only: {} {}", path.display(), m.1));
+            },
+        }
+    }
+    match find(&jail.root.join("sys")) {
+        Some(m) => {
+            if m.2 != "sysfs" || ! m.1.starts_with("ro,nosuid,nodev,noexec") {
+                return Err(format!("Bad sysfs mount: {} {}", m.2, m.1));
+            }
+        }
+        None => return Err("No sysfs".to_string()),
+    }
+    Ok(())
+}
+
+/// Wait for the child `pid` and return its exit status
+#[cfg(test)]
+fn test_wait(pid: pid_t) -> c_int {
+    let mut status: c_int = 0;
+    assert_eq!(unsafe { raw::waitpid(pid, &mut status, 0) }, pid);
+    // WIFEXITED and WEXITSTATUS
+    if status & 0x7f == 0 {
+        (status >> 8) & 0xff
+    } else {
+        1
+    }
+}
+
+/// Fork and return the exit status of the child running `f`
+#[cfg(test)]
+pub fn test_fork<F>(f: F) -> c_int where F: FnOnce() -> c_int {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    let pid = unsafe { fork() };
+    assert!(pid >= 0);
+    if pid == 0 {
+        // Must not return to the test runner
+        let status = catch_unwind(AssertUnwindSafe(f)).unwrap_or(1);
+        unsafe { exit(status) };
+    }
+    test_wait(pid)
+}
+
+/// Run `f` in new user, mount, PID and network namespaces, like a jail (cf. run), and return
+/// its exit status or 2 if the namespaces can't be created
+#[cfg(test)]
+fn test_jailed<F>(jail: &mut Jail, f: F) -> c_int where F: FnOnce(&mut Jail) -> Result<(), String> {
+    let (mut parent_rx, parent_tx) = match Pipe::new() {
+        Ok(p) => (p.reader, p.writer),
+        Err(e) => panic!("Failed to create pipe #1: {}", e),
+    };
+    let (child_rx, mut child_tx) = match Pipe::new() {
+        Ok(p) => (p.reader, p.writer),
+        Err(e) => panic!("Failed to create pipe #2: {}", e),
+    };
+    let pid = unsafe { fork() };
+    assert!(pid >= 0);
+    if pid == 0 {
+        drop(parent_rx);
+        drop(child_tx);
+        let (mut parent_tx, mut child_rx) = (parent_tx, child_rx);
+        let ns = sched::CLONE_NEWNET | sched::CLONE_NEWNS | sched::CLONE_NEWPID |
+            sched::CLONE_NEWUSER;
+        let mut mapped = [0];
+        let status = if unshare(ns).is_err() || parent_tx.write(&[0]).is_err() ||
+                child_rx.read(&mut mapped).unwrap_or(0) != 1 || mapped[0] != 0 {
+            2
+        } else {
+            // The procfs of a PID namespace can only be mounted from inside it
+            test_fork(|| match f(jail) {
+                Ok(()) => 0,
+                Err(e) => {
+                    let _ = writeln!(io::stderr(), "{}", e);
+                    1
+                }
+            })
+        };
+        unsafe { exit(status) };
+    }
+    drop(parent_tx);
+    drop(child_rx);
+    // The parent user namespace must map the child one
+    let mut unshared = [0];
+    if parent_rx.read(&mut unshared).unwrap_or(0) == 1 {
+        let mapped = if jail.init_userns(pid).is_ok() { 0 } else { 1 };
+        let _ = child_tx.write(&[mapped]);
+    }
+    test_wait(pid)
+}
+
+#[cfg(test)]
+fn test_jail(name: &str) -> Jail {
+    use stemflow::ResPool;
+
+    let root = env::temp_dir().join(format!("stemjail-test-{}-{}", name, unsafe { getpid() }));
+    create_dir(&root).unwrap();
+    let dom = ResPool::new().new_dom("test".to_string(), vec!()).unwrap();
+    let mut jail = Jail::new(JailDom::new(dom).unwrap(), vec!(), false);
+    jail.root = root;
+    jail
+}
+
+#[test]
+fn test_proc_sys_mountinfo() {
+    let mut config = ProcConfig::default();
+    config.hidepid = Some(2);
+    let mut jail = test_jail("proc").procfs(config).sysfs(true);
+    let status = test_jailed(&mut jail, |jail| test_proc_sys_jailed(jail));
+    let _ = remove_dir(&jail.root);
+    match status {
+        // Skipped without user namespace
+        0 | 2 => {}
+        _ => panic!("Bad jail procfs or sysfs mounts"),
+    }
+}
+
+/// Bind mount a read-only tree with a sub mount and an excluded one, and check them
+#[cfg(test)]
+fn test_bind_tree_jailed(jail: &Jail) -> Result<(), String> {
+    let src = jail.root.join("src");
+    let dst = jail.root.join("dst");
+    try!(mount("root", &jail.root, "tmpfs", &fs::MsFlags::empty(), &None).map_err(|e| e.to_string()));
+    try!(env::set_current_dir(&jail.root).map_err(|e| e.to_string()));
+    for dir in [&src, &dst].iter() {
+        try!(create_dir(dir).map_err(|e| e.to_string()));
+    }
+    try!(mount("src", &src, "tmpfs", &fs::MS_NOSUID, &None).map_err(|e| e.to_string()));
+    for dir in [&src.join("sub"), &src.join("excluded")].iter() {
+        try!(create_dir(dir).map_err(|e| e.to_string()));
+    }
+    try!(mount("sub", src.join("sub"), "tmpfs", &fs::MsFlags::empty(), &None).map_err(|e| e.to_string()));
+    try!(mount("excluded", src.join("excluded"), "tmpfs", &fs::MsFlags::empty(), &None)
+         .map_err(|e| e.to_string()));
+
+    let bind = BindMount::new(src.clone(), dst.clone()).executable(false);
+    let binds = try!(jail.expand_binds(vec!(bind), &vec!(src.join("excluded")))
+                     .map_err(|e| e.to_string()));
+    for bind in binds.iter() {
+        try!(jail.add_bind(bind, true).map_err(|e| format!("Failed to bind {:?}: {}", bind, e)));
+    }
+
+    let mounts = test_mountinfo();
+    let find = |path: &Path| mounts.iter().rev().find(|x| x.0 == path);
+    for path in [&dst, &dst.join("sub")].iter() {
+        match find(path) {
+            Some(m) => if ! m.1.starts_with("ro,") || ! m.1.contains("noexec") {
+                return Err(format!("Not read-only and noexec: {} {}", path.display(), m.1));
+            },
+            None => return Err(format!("Not mounted: {}", path.display())),
+        }
+    }
+    // Without the new mount API, each sub mount keeps its own flags and the excluded mounts are
+    // only not restricted
+    if jail.mount_api {
+        match find(&dst.join("sub")) {
+            Some(m) if m.1.contains("nosuid") => {}
+            _ => return Err("The source mount flags are not applied to the tree".to_string()),
+        }
+        if find(&dst.join("excluded")).is_some() {
+            return Err("The excluded mount is not detached".to_string());
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_bind_tree() {
+    for mount_api in [false, true].iter() {
+        // Skipped without the new mount API
+        if *mount_api && ! has_mount_api() {
+            continue;
+        }
+        let mut jail = test_jail("bind");
+        jail.mount_api = *mount_api;
+        let status = test_jailed(&mut jail, |jail| test_bind_tree_jailed(jail));
+        let _ = remove_dir(&jail.root);
+        match status {
+            // Skipped without user namespace
+            0 | 2 => {}
+            _ => panic!("Bad bind tree with the new mount API: {}", mount_api),
+        }
+    }
+}
+
+/// Create `dirs` and enter the `jail` sub directory, where `/work/parent` is the `host` one
+#[cfg(test)]
+fn test_parent_root(jail: &Jail, dirs: &[&str]) -> Result<(), String> {
+    use ffi::ns::chroot;
+
+    let root = jail.root.join("jail");
+    try!(mount("root", &jail.root, "tmpfs", &fs::MsFlags::empty(), &None).map_err(|e| e.to_string()));
+    for dir in ["host", "jail/proc", "jail/work/parent"].iter().chain(dirs.iter()) {
+        try!(::std::fs::create_dir_all(jail.root.join(dir)).map_err(|e| e.to_string()));
+    }
+    try!(mount(jail.root.join("host"), root.join("work/parent"), "none", &fs::MS_BIND, &None)
+         .map_err(|e| e.to_string()));
+    try!(mount("proc", root.join("proc"), "proc", &fs::MsFlags::empty(), &None)
+         .map_err(|e| e.to_string()));
+    try!(chroot(&root).map_err(|e| e.to_string()));
+    env::set_current_dir("/work").map_err(|e| e.to_string())
+}
+
+/// Import a bind mount (cf. gain_access) while its source and destination are swapped with
+/// symlinks
+#[cfg(test)]
+fn test_import_race_jailed(jail: &Jail) -> Result<(), String> {
+    use std::os::unix::fs::symlink;
+    use std::fs::rename;
+    use std::sync::Mutex;
+
+    try!(test_parent_root(jail, &["host/dir", "host/outside", "jail/data"]));
+    try!(File::create("/work/parent/outside/secret").map_err(|e| e.to_string()));
+
+    // Each swap is atomic for the checks
+    let swap = Arc::new(Mutex::new(()));
+    let quit = Arc::new(AtomicBool::new(false));
+    let swapper = {
+        let swap = swap.clone();
+        let quit = quit.clone();
+        thread::spawn(move || {
+            let paths = [("/work/parent/dir", "outside"), ("/data", "/work")];
+            while ! quit.load(Relaxed) {
+                for &(path, target) in paths.iter() {
+                    let bkp = format!("{}.bkp", path);
+                    {
+                        let _lock = swap.lock().unwrap();
+                        let _ = rename(path, &bkp);
+                        let _ = symlink(target, path);
+                        thread::yield_now();
+                        let _ = remove_file(path);
+                        let _ = rename(&bkp, path);
+                    }
+                    thread::yield_now();
+                }
+            }
+        })
+    };
+    let bind = BindMount::new(PathBuf::from("/dir"), PathBuf::from("/data/x")).from_parent(true);
+    let mut imported = 0;
+    let mut ret = Ok(());
+    for _ in 0..500 {
+        if jail._import_bind(&bind, true).is_err() {
+            continue;
+        }
+        imported += 1;
+        let _lock = swap.lock().unwrap();
+        let mounts = test_mountinfo();
+        match mounts.iter().rev().find(|x| x.2 == "tmpfs" && x.4 != Path::new("/")) {
+            Some(m) if m.0 == Path::new("/data/x") && m.4 == Path::new("/host/dir") => {}
+            Some(m) => {
+                ret = Err(format!("Bad bind mount of {} on {}", m.4.display(), m.0.display()));
+                break;
+            }
+            None => {
+                ret = Err("No bind mount".to_string());
+                break;
+            }
+        }
+        try!(umount("/data/x", &fs0::MNT_DETACH).map_err(|e| e.to_string()));
+    }
+    quit.store(true, Relaxed);
+    let _ = swapper.join();
+    match ret {
+        Ok(()) if imported == 0 => Err("Nothing imported".to_string()),
+        r => r,
+    }
+}
+
+#[test]
+fn test_import_bind_race() {
+    let mut jail = test_jail("race");
+    jail.workdir = Some(PathBuf::from("/work"));
+    let status = test_jailed(&mut jail, |jail| test_import_race_jailed(jail));
+    let _ = remove_dir(&jail.root);
+    match status {
+        // Skipped without user namespace
+        0 | 2 => {}
+        _ => panic!("Bind mount raced by a symlink swap"),
+    }
+}
+
+/// Add and remove host mounts under a watched bind mount, the first one being cloned with it
+#[cfg(test)]
+fn test_watch_jailed(jail: &mut Jail) -> Result<(), String> {
+    try!(test_parent_root(jail, &["host/media/a", "host/media/b", "jail/media"]));
+    try!(mount("a", "/work/parent/media/a", "tmpfs", &fs::MsFlags::empty(), &None)
+         .map_err(|e| e.to_string()));
+    let bind = BindMount::new(PathBuf::from("/media"), PathBuf::from("/media")).writable(true);
+    try!(jail._import_bind(&bind.clone().from_parent(true), true).map_err(|e| e.to_string()));
+    jail.jdom.binds = vec!(bind);
+    jail.watch = vec!(PathBuf::from("/media"));
+    jail.sync_watched_mounts();
+
+    let is_mounted = |path: &str| test_mountinfo().iter().any(|x| x.0 == Path::new(path));
+    if ! is_mounted("/media/a") {
+        return Err("The recursive bind mount is missing /media/a".to_string());
+    }
+    try!(mount("b", "/work/parent/media/b", "tmpfs", &fs::MsFlags::empty(), &None)
+         .map_err(|e| e.to_string()));
+    jail.sync_watched_mounts();
+    if ! is_mounted("/media/b") {
+        return Err("The new host mount is not propagated".to_string());
+    }
+
+    for path in ["/work/parent/media/a", "/work/parent/media/b"].iter() {
+        try!(umount(path, &fs0::MNT_DETACH).map_err(|e| e.to_string()));
+    }
+    jail.sync_watched_mounts();
+    for path in ["/media/a", "/media/b"].iter() {
+        if is_mounted(path) {
+            return Err(format!("The removed host mount is still in {}", path));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_watch_mounts() {
+    let mut jail = test_jail("watch");
+    jail.workdir = Some(PathBuf::from("/work"));
+    let status = test_jailed(&mut jail, |jail| test_watch_jailed(jail));
+    let _ = remove_dir(&jail.root);
+    match status {
+        // Skipped without user namespace
+        0 | 2 => {}
+        _ => panic!("Host mounts not synced"),
+    }
+}
+
+/// Create read-only placeholders, then replace them for another domain
+#[cfg(test)]
+fn test_placeholders_jailed(jail: &mut Jail) -> Result<(), String> {
+    use std::fs::{metadata, set_permissions, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+    use stemflow::ResPool;
+
+    try!(test_parent_root(jail, &["host/a/b/c", "host/a/d"]));
+    // The paths are reachable but not bind mounted
+    let new_dom = |paths: &[&str]| {
+        let acl = paths.iter().flat_map(|x| FileAccess::new_ro(PathBuf::from(x)).unwrap())
+            .map(|x| Arc::new(x)).collect();
+        let mut jdom = JailDom::new(ResPool::new().new_dom("test".to_string(), acl).unwrap()).unwrap();
+        jdom.binds = vec!();
+        jdom
+    };
+    jail.jdom = new_dom(&["/a/b/c"]);
+    jail.potential = vec!(PathBuf::from("/a/b/c"), PathBuf::from("/a/d"));
+    jail.add_placeholders("/", WORKDIR_PARENT);
+    if ! metadata("/a/b/c").map(|x| x.is_dir()).unwrap_or(false) || metadata("/a/d").is_ok() {
+        return Err("Bad placeholders".to_string());
+    }
+    if create_dir("/a/e").is_ok() || create_dir("/a/b/c/e").is_ok() ||
+            set_permissions("/a", Permissions::from_mode(0o755)).is_ok() {
+        return Err("Writable placeholder".to_string());
+    }
+
+    jail.jdom = new_dom(&["/a/d"]);
+    jail.remove_placeholders();
+    jail.add_placeholders("/", WORKDIR_PARENT);
+    if metadata("/a/b").is_ok() || ! metadata("/a/d").map(|x| x.is_dir()).unwrap_or(false) {
+        return Err("Stale placeholder".to_string());
+    }
+    jail.remove_placeholders();
+    if metadata("/a").is_ok() {
+        return Err("Placeholders not removed".to_string());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_placeholders() {
+    let mut jail = test_jail("placeholders");
+    let status = test_jailed(&mut jail, |jail| test_placeholders_jailed(jail));
+    let _ = remove_dir(&jail.root);
+    match status {
+        // Skipped without user namespace
+        0 | 2 => {}
+        _ => panic!("Bad placeholders"),
+    }
+}
+
+/// Flush a spool with a fresh file, an already dropped one, a FIFO and a symlink
+#[cfg(test)]
+fn test_flush_spool_forked(jail: &Jail) -> Result<(), String> {
+    use libc::mkfifo;
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::symlink;
+
+    let spool = jail.root.join("spool");
+    let drop_dir = jail.root.join("parent/drop");
+    for dir in [&spool, &drop_dir].iter() {
+        try!(::std::fs::create_dir_all(dir).map_err(|e| e.to_string()));
+    }
+    try!(env::set_current_dir(&jail.root).map_err(|e| e.to_string()));
+    try!(try!(File::create(spool.join("new")).map_err(|e| e.to_string())).write_all(b"new")
+         .map_err(|e| e.to_string()));
+    try!(File::create(spool.join("dup")).map_err(|e| e.to_string()));
+    try!(try!(File::create(drop_dir.join("dup")).map_err(|e| e.to_string())).write_all(b"host")
+         .map_err(|e| e.to_string()));
+    let fifo = CString::new(spool.join("fifo").as_os_str().as_bytes()).unwrap();
+    if unsafe { mkfifo(fifo.as_ptr(), 0o600) } != 0 {
+        return Err(format!("Failed to create the FIFO: {}", io::Error::last_os_error()));
+    }
+    try!(symlink(drop_dir.join("dup"), spool.join("link")).map_err(|e| e.to_string()));
+
+    let bind = BindMount::new(PathBuf::from("/drop"), spool.clone()).write_only(true);
+    match jail.flush_spool(&bind, false) {
+        Ok(0) => {}
+        r => return Err(format!("Flushed a fresh file: {:?}", r)),
+    }
+    match jail.flush_spool(&bind, true) {
+        Ok(1) => {}
+        r => return Err(format!("Bad final flush: {:?}", r)),
+    }
+    let mut content = String::new();
+    try!(try!(File::open(drop_dir.join("new")).map_err(|e| e.to_string()))
+         .read_to_string(&mut content).map_err(|e| e.to_string()));
+    if content != "new" || spool.join("new").exists() {
+        return Err("The fresh file was not moved".to_string());
+    }
+    content.clear();
+    try!(try!(File::open(drop_dir.join("dup")).map_err(|e| e.to_string()))
+         .read_to_string(&mut content).map_err(|e| e.to_string()));
+    if content != "host" {
+        return Err("A host file was overwritten".to_string());
+    }
+    let mut names: Vec<_> = try!(read_dir(&drop_dir).map_err(|e| e.to_string()))
+        .filter_map(|x| x.ok().map(|x| x.file_name()))
+        .collect();
+    names.sort();
+    if names != vec!(OsString::from("dup"), OsString::from("new")) {
+        return Err(format!("Unexpected drop directory content: {:?}", names));
+    }
+    for name in ["dup", "fifo", "link"].iter() {
+        if spool.join(name).symlink_metadata().is_err() {
+            return Err(format!("The spooled {} was not kept", name));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_flush_spool() {
+    let jail = test_jail("spool");
+    let status = test_fork(|| match test_flush_spool_forked(&jail) {
+        Ok(()) => 0,
+        Err(e) => {
+            let _ = writeln!(io::stderr(), "{}", e);
+            1
+        }
+    });
+    let _ = ::std::fs::remove_dir_all(&jail.root);
+    assert_eq!(status, 0);
+}
+
+#[test]
+fn test_spool_ticker() {
+    let mut jail = test_jail("ticker");
+    let _ = remove_dir(&jail.root);
+    let (tx, _rx) = channel();
+    jail.spool_tx = Some(tx);
+    jail.update_spool_ticker();
+    assert!(jail.spool_quit.is_none());
+
+    let bind = BindMount::new(PathBuf::from("/drop"), PathBuf::from("/spool")).write_only(true);
+    jail.jdom.binds.push(bind);
+    jail.update_spool_ticker();
+    let quit = jail.spool_quit.clone().expect("No spool ticker");
+    assert!(! quit.load(Relaxed));
+
+    let _ = jail.jdom.binds.pop();
+    jail.update_spool_ticker();
+    assert!(jail.spool_quit.is_none());
+    assert!(quit.load(Relaxed));
+}
+
+#[test]
+fn test_gain_access_exec_denied() {
+    use config::profile::exec_access;
+
+    let jail = test_jail("deny");
+    let _ = remove_dir(&jail.root);
+    let mut jail = jail.deny(vec!(PathBuf::from("/secret")));
+    match jail.gain_access(vec!(exec_access("/secret/bin").unwrap())) {
+        AccessResult::ProtectedPath(ref p) if *p == Path::new("/secret/bin") => {}
+        r => panic!("Execute access not denied: {}", r),
+    }
+}
+
+#[test]
+fn test_add_links() {
+    use std::fs::{read_link, remove_dir_all};
+
+    let mut jail = test_jail("links");
+    jail.links = vec!((PathBuf::from("/bin"), PathBuf::from("/usr/bin")),
+                      (PathBuf::from("/a/lib"), PathBuf::from("/usr/lib")));
+    let root = jail.root.clone();
+    jail.add_links(&root);
+    // Already there
+    jail.add_links(&root);
+    let bin = read_link(root.join("bin"));
+    let lib = read_link(root.join("a/lib"));
+    remove_dir_all(&root).unwrap();
+    assert_eq!(bin.unwrap(), PathBuf::from("/usr/bin"));
+    assert_eq!(lib.unwrap(), PathBuf::from("/usr/lib"));
+}
@@ -0,0 +1,376 @@
+// Copyright (C) 2016 Mickaël Salaün
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 of the License.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+/// Syscall broker: detect the accesses of the jailed processes without the shim (e.g. static
+/// binaries), thanks to the seccomp user notifications
+///
+/// The broker only detects accesses to transition the jail, the mount namespace still enforces
+/// the policy: the notified syscalls always continue.
+///
+/// The open-like, stat-like, access-like and execve-like syscalls are watched: a stat-like
+/// syscall asks for a read access, and an access-like one for the rights of its mode.
+
+use cmd::shim::AccessRequest;
+use fdpass;
+use ffi::seccomp::{notif_continue, notif_id_valid, notif_recv, notify_syscalls};
+use ffi::seccomp::raw::nr;
+use libc::{c_long, dup, O_ACCMODE, O_CREAT, O_TRUNC, W_OK, X_OK};
+use std::fs::{File, read_link};
+use std::io;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::mpsc::Sender;
+use super::{Jail, JailFn, WORKDIR_PARENT};
+use super::util::canonicalize_beneath;
+use unix_socket::UnixStream;
+
+/// Syscalls opening, looking up or executing a path
+pub static WATCHED_SYSCALLS: [c_long; 13] = [
+    nr::OPEN,
+    nr::STAT,
+    nr::LSTAT,
+    nr::ACCESS,
+    nr::EXECVE,
+    nr::CREAT,
+    nr::OPENAT,
+    nr::NEWFSTATAT,
+    nr::FACCESSAT,
+    nr::EXECVEAT,
+    nr::STATX,
+    nr::OPENAT2,
+    nr::FACCESSAT2,
+];
+
+// Same as PATH_MAX
+static PATH_MAX: usize = 4096;
+
+const AT_FDCWD: i64 = -100;
+
+/// Install the syscall filter for the current process (i.e. the jailed command before its
+/// execution) and send its listener to the monitor
+pub fn install_filter(monitor: &UnixStream) -> io::Result<()> {
+    let listener = try!(notify_syscalls(&WATCHED_SYSCALLS));
+    let mut monitor = try!(monitor.try_clone());
+    // TODO: Replace &[0] with a JSON command
+    fdpass::send_fd(&mut monitor, &[0], &listener)
+    // The listener is closed before the execution
+}
+
+/// Access asked by a syscall, with the path address in the process memory
+#[derive(Debug)]
+struct SyscallArgs {
+    dirfd: i64,
+    path: u64,
+    write: bool,
+    exec: bool,
+}
+
+impl SyscallArgs {
+    fn new(dirfd: i64, path: u64) -> SyscallArgs {
+        SyscallArgs {
+            dirfd: dirfd,
+            path: path,
+            write: false,
+            exec: false,
+        }
+    }
+
+    fn open_flags(mut self, flags: u64) -> SyscallArgs {
+        let flags = flags as i32;
+        self.write = flags & O_ACCMODE != 0 || flags & (O_CREAT | O_TRUNC) != 0;
+        self
+    }
+
+    fn access_mode(mut self, mode: u64) -> SyscallArgs {
+        let mode = mode as i32;
+        self.write = mode & W_OK != 0;
+        self.exec = mode & X_OK != 0;
+        self
+    }
+
+    fn write(mut self) -> SyscallArgs {
+        self.write = true;
+        self
+    }
+
+    fn exec(mut self) -> SyscallArgs {
+        self.exec = true;
+        self
+    }
+}
+
+/// Get the access from the syscall arguments, which can then be changed by the process
+fn decode_args(nr: c_long, args: &[u64; 6], mem: &mut File) -> io::Result<Option<SyscallArgs>> {
+    let access = match nr {
+        nr::OPEN => SyscallArgs::new(AT_FDCWD, args[0]).open_flags(args[1]),
+        nr::STAT | nr::LSTAT => SyscallArgs::new(AT_FDCWD, args[0]),
+        nr::ACCESS => SyscallArgs::new(AT_FDCWD, args[0]).access_mode(args[1]),
+        nr::EXECVE => SyscallArgs::new(AT_FDCWD, args[0]).exec(),
+        // Same as open with O_CREAT | O_WRONLY | O_TRUNC
+        nr::CREAT => SyscallArgs::new(AT_FDCWD, args[0]).write(),
+        nr::OPENAT => SyscallArgs::new(args[0] as i32 as i64, args[1]).open_flags(args[2]),
+        nr::NEWFSTATAT | nr::STATX => SyscallArgs::new(args[0] as i32 as i64, args[1]),
+        nr::FACCESSAT | nr::FACCESSAT2 => {
+            SyscallArgs::new(args[0] as i32 as i64, args[1]).access_mode(args[2])
+        }
+        nr::EXECVEAT => SyscallArgs::new(args[0] as i32 as i64, args[1]).exec(),
+        nr::OPENAT2 => {
+            // The first field of struct open_how
+            let mut flags = [0u8; 8];
+            try!(read_mem(mem, args[2], &mut flags));
+            let flags = flags.iter().rev().fold(0u64, |acc, x| acc << 8 | *x as u64);
+            SyscallArgs::new(args[0] as i32 as i64, args[1]).open_flags(flags)
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(access))
+}
+
+fn read_mem(mem: &mut File, addr: u64, buf: &mut [u8]) -> io::Result<()> {
+    let _ = try!(mem.seek(SeekFrom::Start(addr)));
+    mem.read_exact(buf)
+}
+
+/// Read a NUL-terminated path from the process memory
+fn read_path(mem: &mut File, addr: u64) -> io::Result<PathBuf> {
+    use std::ffi::OsString;
+    use std::os::unix::ffi::OsStringExt;
+
+    let _ = try!(mem.seek(SeekFrom::Start(addr)));
+    let mut path = vec!();
+    // The string may end near a page boundary
+    for byte in mem.bytes().take(PATH_MAX) {
+        match try!(byte) {
+            0 => return Ok(PathBuf::from(OsString::from_vec(path))),
+            b => path.push(b),
+        }
+    }
+    Err(io::Error::new(ErrorKind::InvalidData, "Path too long"))
+}
+
+/// Resolve `path` relative to the process working directory or `dirfd`, as seen from the jail
+/// (i.e. the monitor), and then beneath the parent `root` with its symlinks, like a shim request
+fn resolve_path<T>(root: T, pid: u32, dirfd: i64, path: &Path) -> io::Result<PathBuf>
+        where T: AsRef<Path> {
+    let full = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        let base = if dirfd == AT_FDCWD {
+            format!("/proc/{}/cwd", pid)
+        } else {
+            format!("/proc/{}/fd/{}", pid, dirfd)
+        };
+        try!(read_link(base)).join(path)
+    };
+    // The monitor checks the denied paths
+    canonicalize_beneath(root, full, |_| true)
+}
+
+/// Access asked by a syscall, resolved from the jail root
+#[derive(Debug)]
+pub struct SyscallAccess {
+    pub path: PathBuf,
+    pub write: bool,
+    pub exec: bool,
+}
+
+/// Read the access asked by the `nr` syscall of the `pid` process, if it is a watched one, with
+/// its path resolved beneath the parent `root`
+pub fn read_access<T>(root: T, pid: u32, nr: c_long, args: &[u64; 6])
+        -> io::Result<Option<SyscallAccess>> where T: AsRef<Path> {
+    let mut mem = try!(File::open(format!("/proc/{}/mem", pid)));
+    let args = match try!(decode_args(nr, args, &mut mem)) {
+        Some(a) => a,
+        None => return Ok(None),
+    };
+    let path = try!(read_path(&mut mem, args.path));
+    // Same as the current directory or dirfd (e.g. AT_EMPTY_PATH)
+    if path.as_os_str().is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(SyscallAccess {
+        path: try!(resolve_path(root, pid, args.dirfd, &path)),
+        write: args.write,
+        exec: args.exec,
+    }))
+}
+
+/// Resolved access, checked by the monitor before letting the syscall continue
+#[derive(Debug)]
+pub struct SyscallEvent {
+    id: u64,
+    /// Handled the same way as a shim request
+    request: AccessRequest,
+    listener: Arc<File>,
+}
+
+impl JailFn for SyscallEvent {
+    fn call(&mut self, jail: &mut Jail) {
+        let ok = {
+            let data = &self.request.data;
+            jail.is_accessible(&data.path, data.write, data.exec)
+        };
+        if ! ok {
+            // The syscall will fail by itself if denied
+            debug!("{}: {}", self.request.data.path.display(), self.request.gain_access(jail));
+        }
+        match notif_continue(&self.listener, self.id) {
+            Ok(()) => {}
+            Err(e) => debug!("Failed to continue the syscall: {}", e),
+        }
+    }
+}
+
+/// Receive the listener from the jailed command and forward its notifications to the monitor,
+/// except until `started` (i.e. the command execution itself)
+pub fn broker_listen(mut command: UnixStream, cmd_tx: Sender<Box<JailFn>>,
+                     started: Arc<AtomicBool>) {
+    let listener = match fdpass::recv_fd(&mut command, vec!(0u8)) {
+        Ok(fd) => match unsafe { dup(fd.as_raw_fd()) } {
+            -1 => {
+                warn!("Failed to get the syscall listener: {}", io::Error::last_os_error());
+                return;
+            }
+            fd => Arc::new(unsafe { File::from_raw_fd(fd) }),
+        },
+        Err(e) => {
+            warn!("Failed to receive the syscall listener: {}", e);
+            return;
+        }
+    };
+    drop(command);
+    loop {
+        let notif = match notif_recv(&listener) {
+            Ok(n) => n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            // No more jailed process
+            Err(e) => {
+                debug!("Stopping the syscall broker: {}", e);
+                return;
+            }
+        };
+        if ! started.load(Relaxed) {
+            let _ = notif_continue(&listener, notif.id);
+            continue;
+        }
+        let access = read_access(WORKDIR_PARENT, notif.pid, notif.data.nr as c_long,
+                                 &notif.data.args);
+        // The memory may have been reused by another process
+        if ! notif_id_valid(&listener, notif.id) {
+            continue;
+        }
+        let event = match access {
+            Ok(Some(a)) => Some(SyscallEvent {
+                id: notif.id,
+                request: AccessRequest::new(a.path, a.write, a.exec),
+                listener: listener.clone(),
+            }),
+            Ok(None) => None,
+            Err(e) => {
+                debug!("Failed to read the syscall access: {}", e);
+                None
+            }
+        };
+        match event {
+            Some(e) => if cmd_tx.send(Box::new(e)).is_err() {
+                return;
+            },
+            None => {
+                let _ = notif_continue(&listener, notif.id);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_decode_args() {
+    use libc::{O_RDONLY, O_RDWR, O_WRONLY};
+
+    let mut mem = File::open("/proc/self/mem").unwrap();
+    let decode = |nr: c_long, args: [u64; 6], mem: &mut File| {
+        decode_args(nr, &args, mem).unwrap().map(|x| (x.dirfd, x.path, x.write, x.exec))
+    };
+    assert_eq!(decode(nr::OPEN, [1, O_RDONLY as u64, 0, 0, 0, 0], &mut mem),
+               Some((AT_FDCWD, 1, false, false)));
+    assert_eq!(decode(nr::OPENAT, [3, 1, O_WRONLY as u64, 0, 0, 0], &mut mem),
+               Some((3, 1, true, false)));
+    assert_eq!(decode(nr::OPENAT, [AT_FDCWD as u64, 1, (O_RDONLY | O_CREAT) as u64, 0, 0, 0],
+                      &mut mem), Some((AT_FDCWD, 1, true, false)));
+    assert_eq!(decode(nr::EXECVE, [1, 0, 0, 0, 0, 0], &mut mem), Some((AT_FDCWD, 1, false, true)));
+    // The flags of struct open_how are read from the process memory
+    let how: [u64; 3] = [O_RDWR as u64, 0, 0];
+    assert_eq!(decode(nr::OPENAT2, [3, 1, how.as_ptr() as u64, 24, 0, 0], &mut mem),
+               Some((3, 1, true, false)));
+
+    // The syscalls without path (e.g. fstat) are not watched
+    for nr in [5, 8].iter() {
+        assert!(! WATCHED_SYSCALLS.contains(nr));
+        assert!(decode(*nr, [1, 0, 0, 0, 0, 0], &mut mem).is_none());
+    }
+}
+
+#[test]
+fn test_decode_lookup_args() {
+    use libc::{F_OK, R_OK};
+
+    let mut mem = File::open("/proc/self/mem").unwrap();
+    let decode = |nr: c_long, args: [u64; 6], mem: &mut File| {
+        decode_args(nr, &args, mem).unwrap().map(|x| (x.dirfd, x.path, x.write, x.exec))
+    };
+    for nr in [nr::STAT, nr::LSTAT, nr::NEWFSTATAT, nr::STATX, nr::ACCESS, nr::FACCESSAT,
+               nr::FACCESSAT2, nr::CREAT].iter() {
+        assert!(WATCHED_SYSCALLS.contains(nr));
+    }
+    // The stat-like syscalls ask for a read access
+    assert_eq!(decode(nr::STAT, [1, 2, 0, 0, 0, 0], &mut mem), Some((AT_FDCWD, 1, false, false)));
+    assert_eq!(decode(nr::LSTAT, [1, 2, 0, 0, 0, 0], &mut mem), Some((AT_FDCWD, 1, false, false)));
+    assert_eq!(decode(nr::NEWFSTATAT, [3, 1, 2, 0, 0, 0], &mut mem), Some((3, 1, false, false)));
+    assert_eq!(decode(nr::STATX, [AT_FDCWD as u64, 1, 0, 0, 2, 0], &mut mem),
+               Some((AT_FDCWD, 1, false, false)));
+    // The access-like syscalls ask for the rights of their mode
+    assert_eq!(decode(nr::ACCESS, [1, F_OK as u64, 0, 0, 0, 0], &mut mem),
+               Some((AT_FDCWD, 1, false, false)));
+    assert_eq!(decode(nr::ACCESS, [1, (R_OK | X_OK) as u64, 0, 0, 0, 0], &mut mem),
+               Some((AT_FDCWD, 1, false, true)));
+    assert_eq!(decode(nr::FACCESSAT, [3, 1, W_OK as u64, 0, 0, 0], &mut mem),
+               Some((3, 1, true, false)));
+    assert_eq!(decode(nr::FACCESSAT2, [3, 1, X_OK as u64, 0, 0, 0], &mut mem),
+               Some((3, 1, false, true)));
+    // creat() always opens for writing
+    assert_eq!(decode(nr::CREAT, [1, 0o600, 0, 0, 0, 0], &mut mem), Some((AT_FDCWD, 1, true, false)));
+}
+
+#[test]
+fn test_resolve_path() {
+    use libc::getpid;
+    use std::env;
+    use std::fs::{create_dir_all, remove_dir_all};
+    use std::os::unix::fs::symlink;
+
+    let root = env::temp_dir().join(format!("stemjail-test-resolve-{}", unsafe { getpid() }));
+    create_dir_all(root.join("a")).unwrap();
+    create_dir_all(root.join("b/c")).unwrap();
+    symlink("/b/c", root.join("a/link")).unwrap();
+    let pid = unsafe { getpid() } as u32;
+    let resolve = |path: &str| resolve_path(&root, pid, AT_FDCWD, Path::new(path)).unwrap();
+    // The parent directory of a symlink is its target one, not its lexical one
+    assert_eq!(resolve("/a/link/../file"), PathBuf::from("/b/file"));
+    assert_eq!(resolve("/a/link/new"), PathBuf::from("/b/c/new"));
+    assert_eq!(resolve("/../a/./missing/../file"), PathBuf::from("/a/file"));
+    remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,326 @@
+// Copyright (C) 2016 Mickaël Salaün
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 of the License.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+/// Syscall tracer: detect the accesses of the jailed processes with ptrace, for the kernels
+/// without seccomp user notifications (cf. the seccomp module)
+///
+/// Each watched syscall waits for the monitor to handle its access as a shim request.
+///
+/// The tracees are seized to keep the job control working: a group-stop lasts until a SIGCONT.
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use cmd::shim::AccessRequest;
+use fd::Pipe;
+use ffi::ptrace::{get_syscall, listen, resume_syscall, seize, wait_stopped, wait_tracees};
+use ffi::ptrace::raw::{PTRACE_EVENT_STOP, PTRACE_O_EXITKILL, PTRACE_O_TRACECLONE};
+use ffi::ptrace::raw::{PTRACE_O_TRACEEXEC, PTRACE_O_TRACEFORK, PTRACE_O_TRACESYSGOOD};
+use ffi::ptrace::raw::PTRACE_O_TRACEVFORK;
+use libc::{c_int, c_long, c_void, fcntl, getpid, kill, pid_t, raise, write};
+use libc::{FD_CLOEXEC, F_SETFD, SIGKILL, SIGSTOP, SIGTRAP, SIGTSTP, SIGTTIN, SIGTTOU};
+use std::collections::BTreeSet;
+use std::io;
+use std::io::ErrorKind;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use super::{Jail, JailFn, WORKDIR_PARENT};
+use super::seccomp::read_access;
+
+const TRACE_OPTIONS: c_int = PTRACE_O_TRACESYSGOOD | PTRACE_O_TRACEFORK | PTRACE_O_TRACEVFORK |
+    PTRACE_O_TRACECLONE | PTRACE_O_TRACEEXEC | PTRACE_O_EXITKILL;
+
+/// Access detected by the tracer, handled the same way as a shim request
+#[derive(Debug)]
+pub struct TraceEvent {
+    request: AccessRequest,
+    /// Resume the traced process
+    done: Sender<()>,
+}
+
+impl JailFn for TraceEvent {
+    fn call(&mut self, jail: &mut Jail) {
+        let ok = {
+            let data = &self.request.data;
+            jail.is_accessible(&data.path, data.write, data.exec)
+        };
+        if ! ok {
+            // The syscall will fail by itself if denied
+            let _ = self.request.gain_access(jail);
+        }
+        let _ = self.done.send(());
+    }
+}
+
+fn is_terminated(status: c_int) -> bool {
+    // WIFEXITED or WIFSIGNALED
+    status & 0x7f == 0 || ((status & 0x7f) + 1) as i8 >= 2
+}
+
+/// The job control signals, whose group-stops last until a SIGCONT
+fn is_stop_signal(signal: c_int) -> bool {
+    signal == SIGSTOP || signal == SIGTSTP || signal == SIGTTIN || signal == SIGTTOU
+}
+
+/// Syscall-stops of each tracee, which alternate between entries and exits (including for
+/// execve, whose exit follows the exec event)
+#[derive(Debug, Default)]
+struct SyscallStops {
+    /// Tracees stopped at a syscall entry and not yet at its exit
+    entered: BTreeSet<pid_t>,
+}
+
+impl SyscallStops {
+    /// Record a syscall-stop of `pid`, and check if it is an entry
+    fn is_entry(&mut self, pid: pid_t) -> bool {
+        if self.entered.remove(&pid) {
+            false
+        } else {
+            self.entered.insert(pid)
+        }
+    }
+
+    fn forget(&mut self, pid: pid_t) {
+        let _ = self.entered.remove(&pid);
+    }
+}
+
+/// Ask the monitor for the access of a tracee stopped at a syscall entry, resolved beneath the
+/// parent `root`
+fn trace_syscall(root: &Path, pid: pid_t, cmd_tx: &Sender<Box<JailFn>>) {
+    let regs = match get_syscall(pid) {
+        Ok(r) => r,
+        Err(e) => {
+            debug!("Failed to get the syscall of PID {}: {}", pid, e);
+            return;
+        }
+    };
+    match read_access(root, pid as u32, regs.nr as c_long, &regs.args) {
+        Ok(Some(access)) => {
+            let (done_tx, done_rx) = channel();
+            let event = TraceEvent {
+                request: AccessRequest::new(access.path, access.write, access.exec),
+                done: done_tx,
+            };
+            if cmd_tx.send(Box::new(event)).is_ok() {
+                let _ = done_rx.recv();
+            }
+        }
+        Ok(None) => {}
+        Err(e) => debug!("Failed to read the syscall access of PID {}: {}", pid, e),
+    }
+}
+
+fn trace_loop<T>(root: T, main: pid_t, cmd_tx: &Sender<Box<JailFn>>) -> io::Result<()>
+        where T: AsRef<Path> {
+    let root = root.as_ref();
+    // The tracees already stopped once (i.e. the new ones start with a PTRACE_EVENT_STOP)
+    let mut seen = BTreeSet::new();
+    let mut stops = SyscallStops::default();
+    loop {
+        // The tracer thread has no child of its own, only the seized command and its descendants
+        let (pid, status) = match wait_tracees() {
+            Ok(r) => r,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if is_terminated(status) {
+            if pid == main {
+                return Ok(());
+            }
+            let _ = seen.remove(&pid);
+            stops.forget(pid);
+            continue;
+        }
+        let signal = (status >> 8) & 0xff;
+        let event = status >> 16;
+        let new = seen.insert(pid);
+        let forward = if signal == SIGTRAP | 0x80 {
+            if stops.is_entry(pid) {
+                trace_syscall(root, pid, cmd_tx);
+            }
+            0
+        } else if event == PTRACE_EVENT_STOP && ! new && is_stop_signal(signal) {
+            // Group-stop: wait for a SIGCONT, which ends with another PTRACE_EVENT_STOP
+            match listen(pid) {
+                Ok(()) => {}
+                Err(e) => debug!("Failed to listen PID {}: {}", pid, e),
+            }
+            continue;
+        } else if event != 0 {
+            // The fork, clone and exec events, the initial stop of the new tracees, or the end of
+            // a group-stop
+            0
+        } else {
+            signal
+        };
+        match resume_syscall(pid, forward) {
+            Ok(()) => {}
+            // The tracee may have been killed in the meantime
+            Err(e) => debug!("Failed to resume PID {}: {}", pid, e),
+        }
+    }
+}
+
+/// Spawn the command traced by the current thread (the only one able to trace it), send its PID,
+/// and forward the accesses of all the jailed processes to the monitor until the command exits
+pub fn trace_command(cmd: Command, pid_tx: Sender<io::Result<u32>>,
+                     cmd_tx: Sender<Box<JailFn>>, child_tx: Sender<()>) {
+    trace_beneath(WORKDIR_PARENT, cmd, pid_tx, &cmd_tx);
+    let _ = child_tx.send(());
+}
+
+/// Stop the command before its execution, seize it, and then trace it with the accesses resolved
+/// beneath the parent `root`
+fn trace_beneath<T>(root: T, mut cmd: Command, pid_tx: Sender<io::Result<u32>>,
+                    cmd_tx: &Sender<Box<JailFn>>) where T: AsRef<Path> {
+    let (mut pid_rx, pid_wr) = match Pipe::new() {
+        Ok(p) => (p.reader, p.writer),
+        Err(e) => {
+            let _ = pid_tx.send(Err(e));
+            return;
+        }
+    };
+    // Not inherited by the command
+    for fd in [pid_rx.as_raw_fd(), pid_wr.as_raw_fd()].iter() {
+        let _ = unsafe { fcntl(*fd, F_SETFD, FD_CLOEXEC) };
+    }
+    let _ = unsafe { cmd.before_exec(move || {
+        let pid = getpid() as u32;
+        let buf = [pid as u8, (pid >> 8) as u8, (pid >> 16) as u8, (pid >> 24) as u8];
+        if write(pid_wr.as_raw_fd(), buf.as_ptr() as *const c_void, buf.len()) != buf.len() as isize ||
+                raise(SIGSTOP) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }) };
+    // The spawn returns after the execution, which waits for the seizing
+    let spawner = thread::spawn(move || cmd.spawn());
+    let pid = match pid_rx.read_u32::<LittleEndian>() {
+        Ok(pid) => pid as pid_t,
+        Err(e) => {
+            let _ = pid_tx.send(match spawner.join() {
+                Ok(Err(e)) => Err(e),
+                _ => Err(e),
+            });
+            return;
+        }
+    };
+    match wait_stopped(pid).and_then(|_| seize(pid, TRACE_OPTIONS)) {
+        Ok(()) => {}
+        Err(e) => {
+            let _ = unsafe { kill(pid, SIGKILL) };
+            let _ = spawner.join();
+            let _ = pid_tx.send(Err(e));
+            return;
+        }
+    }
+    let _ = pid_tx.send(Ok(pid as u32));
+    match trace_loop(root, pid, cmd_tx) {
+        Ok(()) => debug!("Jail child (PID {}) exited", pid),
+        Err(e) => {
+            warn!("Failed to trace child (PID {}): {}", pid, e);
+            let _ = unsafe { kill(pid, SIGKILL) };
+        }
+    }
+    match spawner.join() {
+        Ok(Ok(..)) => {}
+        Ok(Err(e)) => warn!("Failed to execute process: {}", e),
+        Err(_) => warn!("Failed to spawn process"),
+    }
+}
+
+#[test]
+fn test_syscall_stops() {
+    let mut stops = SyscallStops::default();
+    assert!(stops.is_entry(1));
+    // Another tracee enters a syscall in the meantime
+    assert!(stops.is_entry(2));
+    assert!(! stops.is_entry(1));
+    assert!(stops.is_entry(1));
+    assert!(! stops.is_entry(2));
+    // A new tracee with a reused PID
+    stops.forget(1);
+    assert!(stops.is_entry(1));
+}
+
+/// Trace a command stopping itself between two opens
+#[test]
+fn test_trace_beneath() {
+    use libc::{id_t, siginfo_t, waitid, waitpid, P_PID, SIGCONT, WEXITED, WNOWAIT};
+    use std::env;
+    use std::mem;
+    use std::ptr;
+    use std::fs::{remove_file, File};
+    use std::io::Write;
+    use std::sync::mpsc::RecvTimeoutError;
+    use std::time::Duration;
+
+    let base = env::temp_dir().join(format!("stemjail-test-trace-{}", unsafe { getpid() }));
+    let stop = base.with_extension("stop");
+    let cont = base.with_extension("cont");
+    for file in [&stop, &cont].iter() {
+        let _ = File::create(file).unwrap();
+    }
+    let mut cmd = Command::new("/bin/sh");
+    let script = format!("exec 3<{}; kill -STOP $$; exec 4<{}", stop.display(), cont.display());
+    let _ = cmd.args(&["-c", &script]);
+    // An exited child of another thread must not be reaped by the tracer
+    let other = Command::new("/bin/true").spawn().unwrap().id() as pid_t;
+    let mut info: siginfo_t = unsafe { mem::zeroed() };
+    assert_eq!(unsafe { waitid(P_PID, other as id_t, &mut info, WEXITED | WNOWAIT) }, 0);
+    let (pid_tx, pid_rx) = channel();
+    let (cmd_tx, cmd_rx) = channel();
+    let tracer = thread::spawn(move || trace_beneath("/", cmd, pid_tx, &cmd_tx));
+    let pid = match pid_rx.recv().unwrap() {
+        Ok(pid) => pid as pid_t,
+        // Skipped without ptrace (e.g. in a sandbox)
+        Err(_) => {
+            tracer.join().unwrap();
+            let _ = unsafe { waitpid(other, ptr::null_mut(), 0) };
+            return;
+        }
+    };
+    let name = |path: &Path| path.file_name().unwrap().to_string_lossy().into_owned();
+    let (stop_name, cont_name) = (name(&stop), name(&cont));
+    // The events are dropped, which resumes the tracees
+    let mut events: Vec<String> = vec!();
+    while ! events.iter().any(|x| x.contains(&stop_name)) {
+        events.push(format!("{:?}", cmd_rx.recv().unwrap()));
+    }
+    // The group-stop lasts until a SIGCONT
+    let stopped = match cmd_rx.recv_timeout(Duration::from_millis(300)) {
+        Err(RecvTimeoutError::Timeout) => true,
+        Ok(e) => {
+            events.push(format!("{:?}", e));
+            false
+        }
+        Err(RecvTimeoutError::Disconnected) => false,
+    };
+    assert_eq!(unsafe { kill(pid, SIGCONT) }, 0);
+    events.extend(cmd_rx.iter().map(|x| format!("{:?}", x)));
+    tracer.join().unwrap();
+    for file in [&stop, &cont].iter() {
+        let _ = remove_file(file);
+    }
+    let mut status = 0;
+    assert_eq!(unsafe { waitpid(other, &mut status, 0) }, other);
+    let count = |name: &str| events.iter().filter(|x| x.contains(name)).count();
+    if ! stopped || count(&stop_name) != 1 || count(&cont_name) != 1 {
+        let _ = writeln!(io::stderr(), "{:#?}", events);
+        panic!("Bad trace: stopped={}", stopped);
+    }
+}
@@ -12,12 +12,15 @@
 // You should have received a copy of the GNU Lesser General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
-use ffi::ns::{fs0, umount};
+use ffi::ns::{fs0, mkdirat, openat, openat2, raw, umount};
+use libc::{mode_t, EPERM, ENOSYS, O_CLOEXEC, O_CREAT, O_DIRECTORY, O_EXCL, O_NOFOLLOW, O_WRONLY};
 use rand::{Rng, thread_rng};
-use std::fs::{File, create_dir, create_dir_all, remove_dir};
+use std::ffi::OsString;
+use std::fs::{File, create_dir, create_dir_all, read_link, remove_dir};
 use std::io;
 use std::io::ErrorKind;
-use std::path::{Path, PathBuf};
+use std::os::unix::io::AsRawFd;
+use std::path::{Component, Path, PathBuf};
 
 /// Concatenate two paths (different from `join()`)
 pub fn nest_path<T, U>(root: T, subdir: U) -> PathBuf where T: AsRef<Path>, U: AsRef<Path> {
@@ -103,6 +106,338 @@ pub fn create_same_type<T, U>(src: T, dst: U) -> io::Result<()>
     Ok(())
 }
 
+/// A path resolved once, without following any symlink, which can then be used as a stable path
+/// (e.g. mount source or target) through procfs
+pub struct PathFd {
+    file: File,
+    path: PathBuf,
+}
+
+impl PathFd {
+    fn new(file: File) -> PathFd {
+        let path = PathBuf::from(format!("/proc/self/fd/{}", file.as_raw_fd()));
+        PathFd {
+            file: file,
+            path: path,
+        }
+    }
+
+    /// Resolve `path` beneath the `root` directory
+    ///
+    /// Any symlink is refused, including the intermediate ones (e.g. */lib* with a merged */usr*):
+    /// a symlink could be swapped (e.g. by a jailed process) to expose a path which is not the
+    /// one checked by the policy, so the profile paths must be canonical (cf. `Portal::new`).
+    pub fn open_beneath<T, U>(root: T, path: U) -> io::Result<PathFd>
+            where T: AsRef<Path>, U: AsRef<Path> {
+        let root = try!(open_dir(root));
+        let path = relative_path(path);
+        let resolve = raw::RESOLVE_BENEATH | raw::RESOLVE_NO_SYMLINKS | raw::RESOLVE_NO_MAGICLINKS;
+        match openat2(&root, &path, raw::O_PATH | O_CLOEXEC, resolve) {
+            Ok(f) => Ok(PathFd::new(f)),
+            // Fallback for kernels older than Linux 5.6
+            Err(ref e) if e.raw_os_error() == Some(ENOSYS) || e.raw_os_error() == Some(EPERM) => {
+                walk_beneath(&root, &path, None, 0).map(|f| PathFd::new(f))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Resolve `path` beneath the `root` directory and create the missing directories, including
+    /// the last component if `is_dir`, or an empty file otherwise
+    pub fn create_beneath<T, U>(root: T, path: U, is_dir: bool) -> io::Result<PathFd>
+            where T: AsRef<Path>, U: AsRef<Path> {
+        let root = try!(open_dir(root));
+        walk_beneath(&root, &relative_path(path), Some(is_dir), 0o777).map(|f| PathFd::new(f))
+    }
+
+    /// Resolve `path` beneath the `root` directory and create the missing directories, including
+    /// the last component, with the `mode` permissions
+    pub fn create_dirs_beneath<T, U>(root: T, path: U, mode: mode_t) -> io::Result<PathFd>
+            where T: AsRef<Path>, U: AsRef<Path> {
+        let root = try!(open_dir(root));
+        walk_beneath(&root, &relative_path(path), Some(true), mode).map(|f| PathFd::new(f))
+    }
+
+    pub fn as_file(&self) -> &File {
+        &self.file
+    }
+}
+
+impl AsRef<Path> for PathFd {
+    fn as_ref(&self) -> &Path {
+        self.path.as_ref()
+    }
+}
+
+fn open_dir<T>(path: T) -> io::Result<File> where T: AsRef<Path> {
+    let cwd = try!(File::open("."));
+    openat(&cwd, path, raw::O_PATH | O_CLOEXEC | O_DIRECTORY)
+}
+
+/// Strip the root and current directory components, or return "." if nothing remains
+fn relative_path<T>(path: T) -> PathBuf where T: AsRef<Path> {
+    let path = nest_path(".", path);
+    let rel = path.components().filter(|x| *x != Component::CurDir).map(|x| x.as_os_str())
+        .collect::<PathBuf>();
+    if rel.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        rel
+    }
+}
+
+/// Walk `path` one component at a time: refuse any symlink or parent directory
+fn walk_beneath(root: &File, path: &Path, create: Option<bool>, dir_mode: mode_t)
+        -> io::Result<File> {
+    let flags = raw::O_PATH | O_CLOEXEC | O_NOFOLLOW;
+    let mut cur = try!(openat(root, ".", flags | O_DIRECTORY));
+    let mut comps = path.components().peekable();
+    while let Some(comp) = comps.next() {
+        let name = match comp {
+            Component::Normal(n) => n,
+            Component::CurDir => continue,
+            _ => return Err(io::Error::new(ErrorKind::PermissionDenied, "Path traversal")),
+        };
+        let is_last = comps.peek().is_none();
+        let next = match openat(&cur, name, flags) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == ErrorKind::NotFound && create.is_some() => {
+                let ret = if ! is_last || create == Some(true) {
+                    mkdirat(&cur, name, dir_mode)
+                } else {
+                    openat(&cur, name, O_CREAT | O_EXCL | O_WRONLY | O_NOFOLLOW | O_CLOEXEC)
+                        .map(|_| ())
+                };
+                match ret {
+                    Ok(..) => {}
+                    // Concurrent creation, the type is checked below
+                    Err(ref e) if e.kind() == ErrorKind::AlreadyExists => {}
+                    Err(e) => return Err(e),
+                }
+                try!(openat(&cur, name, flags))
+            }
+            Err(e) => return Err(e),
+        };
+        let md = try!(next.metadata());
+        if md.file_type().is_symlink() {
+            return Err(io::Error::new(ErrorKind::PermissionDenied, "Symlink"));
+        }
+        if ! is_last && ! md.is_dir() {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "Not a directory"));
+        }
+        cur = next;
+    }
+    Ok(cur)
+}
+
+/// Maximum number of symlinks followed by a path resolution (cf. path_resolution(7))
+const MAX_SYMLINKS: usize = 40;
+
+/// Path components to resolve, in reverse order
+fn pending_components<T>(path: T) -> Vec<OsString> where T: AsRef<Path> {
+    path.as_ref().components().rev().filter_map(|x| match x {
+        Component::Normal(n) => Some(n.to_os_string()),
+        Component::ParentDir => Some(OsString::from("..")),
+        _ => None,
+    }).collect()
+}
+
+/// Resolve `path` beneath the `root` directory like realpath(3), as if `root` was the root
+/// directory (i.e. the "..", "." and symlinks never lead outside), and check each traversed path,
+/// including the symlinks and their targets, with `allow`
+///
+/// The missing components are resolved lexically (e.g. a file to create).
+pub fn canonicalize_beneath<T, U, F>(root: T, path: U, allow: F) -> io::Result<PathBuf>
+        where T: AsRef<Path>, U: AsRef<Path>, F: Fn(&Path) -> bool {
+    let root = root.as_ref();
+    let denied = |path: &Path| {
+        io::Error::new(ErrorKind::PermissionDenied, format!("Access denied to {}", path.display()))
+    };
+    let mut pending = pending_components(path);
+    let mut resolved = PathBuf::from("/");
+    // Number of trailing components of `resolved` not found
+    let mut missing = 0;
+    let mut links = 0;
+    while let Some(name) = pending.pop() {
+        if name == OsString::from("..") {
+            if resolved.pop() && missing > 0 {
+                missing -= 1;
+            }
+            continue;
+        }
+        let next = resolved.join(&name);
+        if ! allow(&next) {
+            return Err(denied(&next));
+        }
+        if missing > 0 {
+            missing += 1;
+            resolved = next;
+            continue;
+        }
+        let parent = try!(PathFd::open_beneath(root, &resolved));
+        match read_link(parent.as_ref().join(&name)) {
+            Ok(target) => {
+                links += 1;
+                if links > MAX_SYMLINKS {
+                    return Err(io::Error::new(ErrorKind::Other,
+                                              "Too many levels of symbolic links"));
+                }
+                if target.is_absolute() {
+                    resolved = PathBuf::from("/");
+                }
+                pending.extend(pending_components(target));
+                continue;
+            }
+            Err(ref e) if e.kind() == ErrorKind::NotFound => missing = 1,
+            // Not a symlink
+            Err(ref e) if e.kind() == ErrorKind::InvalidInput => {}
+            Err(e) => return Err(e),
+        }
+        resolved = next;
+    }
+    if ! allow(&resolved) {
+        return Err(denied(&resolved));
+    }
+    Ok(resolved)
+}
+
+#[cfg(test)]
+fn test_root(name: &str) -> PathBuf {
+    let suffix: String = thread_rng().gen_ascii_chars().take(12).collect();
+    let root = ::std::env::temp_dir().join(format!("stemjail_{}_{}", name, suffix));
+    create_dir_all(root.join("dir/sub")).unwrap();
+    File::create(root.join("dir/file")).unwrap();
+    ::std::os::unix::fs::symlink("/", root.join("abs")).unwrap();
+    ::std::os::unix::fs::symlink("dir", root.join("rel")).unwrap();
+    root
+}
+
+#[cfg(test)]
+fn fd_target(fd: &PathFd) -> PathBuf {
+    ::std::fs::read_link(fd).unwrap()
+}
+
+#[test]
+fn test_open_beneath() {
+    let root = test_root("open");
+    let root_real = root.canonicalize().unwrap();
+    for path in ["/", "/dir", "dir/sub", "/dir/./sub", "/dir/file"].iter() {
+        let fd = PathFd::open_beneath(&root, path).unwrap();
+        assert_eq!(fd_target(&fd), nest_path(&root_real, path).canonicalize().unwrap());
+    }
+    // Symlinks and parent directories must be refused
+    for path in ["/abs", "/abs/etc", "/rel", "/rel/sub", "/dir/../..", "/../etc", "/nofile"].iter() {
+        assert!(PathFd::open_beneath(&root, path).is_err(), "{}", path);
+    }
+    ::std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_create_beneath() {
+    let root = test_root("create");
+    let root_real = root.canonicalize().unwrap();
+    let fd = PathFd::create_beneath(&root, "/dir/new/dir", true).unwrap();
+    assert_eq!(fd_target(&fd), root_real.join("dir/new/dir"));
+    assert!(root.join("dir/new/dir").metadata().unwrap().is_dir());
+    let fd = PathFd::create_beneath(&root, "/dir/new/file", false).unwrap();
+    assert_eq!(fd_target(&fd), root_real.join("dir/new/file"));
+    assert!(root.join("dir/new/file").metadata().unwrap().is_file());
+    let fd = PathFd::create_dirs_beneath(&root, "/dir/placeholder", 0o555).unwrap();
+    assert_eq!(fd_target(&fd), root_real.join("dir/placeholder"));
+    let md = root.join("dir/placeholder").metadata().unwrap();
+    assert!(md.is_dir());
+    assert_eq!(::std::os::unix::fs::PermissionsExt::mode(&md.permissions()) & 0o222, 0);
+    // Must not create anything through a symlink
+    assert!(PathFd::create_beneath(&root, "/abs/tmp/stemjail", true).is_err());
+    assert!(PathFd::create_beneath(&root, "/rel/other", true).is_err());
+    assert!(! root.join("dir/other").exists());
+    ::std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_canonicalize_beneath() {
+    use std::os::unix::fs::symlink;
+
+    let root = test_root("canonicalize");
+    symlink("loop", root.join("loop")).unwrap();
+    symlink("../../dir/./sub/", root.join("dir/up")).unwrap();
+    let all = |_: &Path| true;
+    let cases = [
+        ("/", "/"),
+        ("/dir//sub/./", "/dir/sub"),
+        ("/dir/sub/../file", "/dir/file"),
+        // Can't go above the root
+        ("/../../dir/..", "/"),
+        ("/rel/sub", "/dir/sub"),
+        ("/abs/dir/file", "/dir/file"),
+        ("/dir/up", "/dir/sub"),
+        // Missing components
+        ("/dir/new/file", "/dir/new/file"),
+        ("/dir/new/../../rel", "/dir"),
+    ];
+    for &(path, canonical) in cases.iter() {
+        assert_eq!(canonicalize_beneath(&root, path, &all).unwrap(), PathBuf::from(canonical),
+                   "{}", path);
+    }
+    assert!(canonicalize_beneath(&root, "/loop", &all).is_err());
+
+    // The symlink targets are checked as well
+    let deny = |path: &Path| ! path.starts_with("/dir/sub");
+    assert!(canonicalize_beneath(&root, "/dir/file", &deny).is_ok());
+    for path in ["/dir/sub/file", "/rel/sub", "/dir/up", "/dir/file/../sub"].iter() {
+        let err = canonicalize_beneath(&root, path, &deny).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied, "{}", path);
+    }
+    ::std::fs::remove_dir_all(&root).unwrap();
+}
+
+/// Race a symlink swap against the path resolution
+#[test]
+fn test_open_beneath_race() {
+    use std::fs::{remove_file, rename};
+    use std::os::unix::fs::symlink;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    let root = test_root("race");
+    let root_real = root.canonicalize().unwrap();
+    let swap = root.join("swap");
+    create_dir(&swap).unwrap();
+    let quit = Arc::new(AtomicBool::new(false));
+    let swapper = {
+        let quit = quit.clone();
+        let root = root.clone();
+        thread::spawn(move || {
+            let dir = root.join("swap");
+            let dir_bkp = root.join("swap.dir");
+            // The resolution may concurrently create a directory
+            while ! quit.load(Ordering::Relaxed) {
+                let _ = rename(&dir, &dir_bkp);
+                let _ = symlink("/", &dir);
+                let _ = remove_file(&dir);
+                let _ = rename(&dir_bkp, &dir);
+            }
+        })
+    };
+    for _ in 0..10000 {
+        for &create in [false, true].iter() {
+            let ret = if create {
+                PathFd::create_beneath(&root, "/swap/etc", true)
+            } else {
+                PathFd::open_beneath(&root, "/swap")
+            };
+            match ret {
+                Ok(fd) => assert!(fd_target(&fd).starts_with(&root_real)),
+                Err(_) => {}
+            }
+        }
+    }
+    quit.store(true, Ordering::Relaxed);
+    swapper.join().unwrap();
+    ::std::fs::remove_dir_all(&root).unwrap();
+}
+
 // TODO: Handle temporary file (e.g. bind mount a file)
 pub struct TmpWorkDir {
     path: PathBuf,
@@ -30,6 +30,7 @@ extern crate libc;
 extern crate mnt;
 extern crate rand;
 extern crate rustc_serialize;
+extern crate sha2;
 extern crate stemflow;
 extern crate toml;
 extern crate tty;
@@ -46,5 +47,8 @@ mod ffi;
 
 pub static PORTAL_SOCKET_PATH: &'static str = "./portal.sock";
 pub static PORTAL_PROFILES_PATH: &'static str = "./config/profiles";
+pub static PORTAL_CONFIG_PATH: &'static str = "./config/portal.toml";
+/// Never writable from a jail, including with a runtime grant
+pub static PORTAL_PROTECTED_PATHS: [&'static str; 2] = [PORTAL_PROFILES_PATH, PORTAL_CONFIG_PATH];
 
 pub static MONITOR_SOCKET_PATH: &'static str = "/tmp/monitor.sock";
@@ -13,16 +13,35 @@
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
 use config::portal::Portal;
-use config::profile::ProfileDom;
+use config::profile::{exec_access, ProfileDom};
+use std::collections::BTreeMap;
 use std::io;
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::sync::mpsc::{Receiver, Sender};
-use stemflow::{Action, FileAccess};
+use unix_socket::UnixStream;
 
 pub enum ManagerAction {
     NewDom(NewDomRequest),
     GetDot(GetDotRequest),
+    AddJail(AddJailRequest),
+    DelJail(DelJailRequest),
+    GetJail(GetJailRequest),
+    ListJails(ListJailsRequest),
+}
+
+/// Running jails, reachable through their privileged monitor channel
+pub struct JailRegistry {
+    last_id: u64,
+    jails: BTreeMap<u64, (String, Arc<Mutex<UnixStream>>)>,
+}
+
+impl JailRegistry {
+    pub fn new() -> JailRegistry {
+        JailRegistry {
+            last_id: 0,
+            jails: BTreeMap::new(),
+        }
+    }
 }
 
 pub struct NewDomResponse {
@@ -53,10 +72,15 @@ impl NewDomRequest {
                     match cmd_opt {
                         Some(cmd) => {
                             match portal.domain(name) {
-                                Some(jdom) => Some(ProfileDom {
-                                    cmd: cmd,
-                                    jdom: jdom.into(),
-                                }),
+                                Some(jdom) => {
+                                    match ProfileDom::new(cmd, jdom, portal.profile(name), portal) {
+                                        Ok(p) => Some(p),
+                                        Err(e) => {
+                                            error!("Failed to load {:?}: {}", self.desc, e);
+                                            None
+                                        }
+                                    }
+                                }
                                 None => {
                                     error!("No domain found for {:?}", self.desc);
                                     None
@@ -70,15 +94,24 @@ impl NewDomRequest {
                     match cmd.iter().next() {
                         Some(path) => {
                             // Build an artificial access request from the executable
-                            let access = vec!(Arc::new(FileAccess {
-                                path: Arc::new(PathBuf::from(path)),
-                                action: Action::Read,
-                            }));
+                            let access = match exec_access(path) {
+                                Ok(a) => vec!(Arc::new(a)),
+                                Err(()) => {
+                                    error!("Invalid executable path for {:?}", self.desc);
+                                    return Err(());
+                                }
+                            };
                             match portal.allow(&access) {
-                                Some(jdom) => Some(ProfileDom {
-                                    cmd: cmd.clone(),
-                                    jdom: jdom.into(),
-                                }),
+                                Some(jdom) => {
+                                    let config = portal.profile(&jdom.name);
+                                    match ProfileDom::new(cmd.clone(), jdom, config, portal) {
+                                        Ok(p) => Some(p),
+                                        Err(e) => {
+                                            error!("Failed to load {:?}: {}", self.desc, e);
+                                            None
+                                        }
+                                    }
+                                }
                                 None => {
                                     error!("No domain found for {:?}", self.desc);
                                     None
@@ -130,13 +163,91 @@ impl GetDotRequest {
     }
 }
 
+pub struct AddJailResponse {
+    pub id: u64,
+}
+
+pub struct AddJailRequest {
+    pub name: String,
+    pub channel: UnixStream,
+    pub response: Sender<AddJailResponse>,
+}
+
+impl AddJailRequest {
+    fn call(self, jails: &mut JailRegistry) -> Result<(), ()> {
+        jails.last_id += 1;
+        let id = jails.last_id;
+        let _ = jails.jails.insert(id, (self.name, Arc::new(Mutex::new(self.channel))));
+        // Do not block
+        match self.response.send(AddJailResponse { id: id }) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(()),
+        }
+    }
+}
+
+pub struct DelJailRequest {
+    pub id: u64,
+}
+
+impl DelJailRequest {
+    fn call(self, jails: &mut JailRegistry) -> Result<(), ()> {
+        let _ = jails.jails.remove(&self.id);
+        Ok(())
+    }
+}
+
+pub struct GetJailResponse {
+    pub channel: Option<Arc<Mutex<UnixStream>>>,
+}
+
+pub struct GetJailRequest {
+    pub id: u64,
+    pub response: Sender<GetJailResponse>,
+}
+
+impl GetJailRequest {
+    fn call(self, jails: &mut JailRegistry) -> Result<(), ()> {
+        let channel = jails.jails.get(&self.id).map(|&(_, ref c)| c.clone());
+        // Do not block
+        match self.response.send(GetJailResponse { channel: channel }) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(()),
+        }
+    }
+}
+
+pub struct ListJailsResponse {
+    pub jails: Vec<(u64, String)>,
+}
+
+pub struct ListJailsRequest {
+    pub response: Sender<ListJailsResponse>,
+}
+
+impl ListJailsRequest {
+    fn call(self, jails: &mut JailRegistry) -> Result<(), ()> {
+        let list = jails.jails.iter().map(|(id, &(ref name, _))| (*id, name.clone())).collect();
+        // Do not block
+        match self.response.send(ListJailsResponse { jails: list }) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(()),
+        }
+    }
+}
+
 pub fn manager_listen(mut portal: Portal, manager_rx: Receiver<ManagerAction>) {
+    let mut jails = JailRegistry::new();
     'listen: loop {
         match manager_rx.recv() {
             Ok(req) => {
                 let ret = match req {
                     ManagerAction::NewDom(req) => req.call(&mut portal),
                     ManagerAction::GetDot(req) => req.call(&mut portal),
+                    ManagerAction::AddJail(req) => req.call(&mut jails),
+                    ManagerAction::DelJail(req) => req.call(&mut jails),
+                    ManagerAction::GetJail(req) => req.call(&mut jails),
+                    ManagerAction::ListJails(req) => req.call(&mut jails),
                 };
                 if ret.is_err() {
                     break 'listen;
@@ -15,46 +15,136 @@
 #![allow(deprecated)]
 
 use cmd::{MonitorCall, PortalCall};
+use cmd::grant::{GrantBundle, GrantRequest, GrantResponse};
+use cmd::shim::{SessionRequest, SessionResponse, ShimAction};
 use config::portal::Portal;
 use jail::JailFn;
 use {MONITOR_SOCKET_PATH, PORTAL_SOCKET_PATH};
 use self::manager::manager_listen;
+use self::pool::{MonitorJob, WorkerPool};
 use std::fs;
 use std::io::ErrorKind;
+use std::path::PathBuf;
 use std::process::exit;
 use std::sync::Arc;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::Relaxed;
-use std::sync::mpsc::{Sender, channel};
+use std::sync::mpsc::{Receiver, Sender, channel};
 use std::thread;
+use std::time::Duration;
 use unix_socket::{UnixListener, UnixStream};
-use util::recv;
+use util::{handshake, recv, recv_limit, send};
 
-pub use srv::manager::{DomDesc, GetDotRequest, ManagerAction, NewDomRequest};
+pub use srv::manager::{AddJailRequest, DelJailRequest, DomDesc, GetDotRequest, GetJailRequest};
+pub use srv::manager::{ListJailsRequest, ManagerAction, NewDomRequest};
 
 mod manager;
+mod pool;
 
-fn portal_handle(mut stream: UnixStream, manager_tx: Sender<ManagerAction>) -> Result<(), String> {
-    let decoded = try!(recv(&mut stream));
+static MONITOR_WORKERS: usize = 4;
+static MONITOR_QUEUE: usize = 64;
+/// Maximum time (in seconds) for a client to send its request or to read a one-shot response
+static MONITOR_IO_TIMEOUT: u64 = 5;
+
+fn portal_handle(mut stream: UnixStream, manager_tx: Sender<ManagerAction>, request_size_max: usize,
+                 protected: &[PathBuf]) -> Result<(), String> {
+    let _ = try!(handshake(&mut stream));
+    let decoded = try!(recv_limit(&mut stream, request_size_max));
     debug!("Portal got request: {:?}", decoded);
     // Use the client command if any or the configuration command otherwise
     match decoded {
         PortalCall::Run(action) => action.call(stream, manager_tx),
         PortalCall::Info(action) => action.call(stream, manager_tx),
+        PortalCall::Grant(action) => action.call(stream, manager_tx, protected),
     }
 }
 
-// TODO: Handle return error
-fn monitor_handle(mut stream: UnixStream, cmd_tx: Sender<Box<JailFn>>) -> Result<(), String> {
-    let decoded = try!(recv(&mut stream));
+/// Read the request of a new monitor client, which can't block the monitor longer than
+/// MONITOR_IO_TIMEOUT
+fn monitor_read(stream: &mut UnixStream, request_size_max: usize) -> Result<MonitorCall, String> {
+    let timeout = Some(Duration::from_secs(MONITOR_IO_TIMEOUT));
+    try!(stream.set_read_timeout(timeout).and_then(|_| stream.set_write_timeout(timeout))
+         .map_err(|e| format!("Failed to set the client timeouts: {}", e)));
+    let _ = try!(handshake(stream));
+    let decoded = try!(recv_limit(stream, request_size_max));
     debug!("Monitor got request: {:?}", decoded);
-    match decoded {
-        MonitorCall::Mount(action) => action.call(cmd_tx),
+    Ok(decoded)
+}
+
+/// Handle a new monitor client from a worker: the sessions get a dedicated thread and the other
+/// requests are called right away
+fn monitor_handle(mut stream: UnixStream, pool: &WorkerPool, request_size_max: usize,
+                  cmd_tx: &Sender<Box<JailFn>>) -> Result<(), String> {
+    match try!(monitor_read(&mut stream, request_size_max)) {
+        // A session is read by a dedicated thread to not hold a worker
+        MonitorCall::Shim(ShimAction::Session) => {
+            try!(stream.set_read_timeout(None).and_then(|_| stream.set_write_timeout(None))
+                 .map_err(|e| format!("Failed to reset the session timeouts: {}", e)));
+            let pool = pool.clone();
+            // TODO: Join all threads
+            thread::spawn(move || session_listen(stream, pool, request_size_max));
+            Ok(())
+        }
+        call => monitor_call(call, stream, cmd_tx.clone()),
+    }
+}
+
+// TODO: Handle return error
+fn monitor_call(call: MonitorCall, stream: UnixStream, cmd_tx: Sender<Box<JailFn>>)
+        -> Result<(), String> {
+    match call {
+        MonitorCall::Mount(action) => action.call(cmd_tx, stream),
         MonitorCall::Shim(action) => action.call(cmd_tx, stream),
     }
 }
 
-fn portal_ext_listen(manager_tx: Sender<ManagerAction>) {
+/// Send the responses of a shim session in their completion order, until the session ends
+fn session_write(mut stream: UnixStream, responses: Receiver<SessionResponse>) {
+    for response in responses.iter() {
+        match send(&mut stream, response) {
+            Ok(()) => {}
+            Err(e) => {
+                debug!("Session closed: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// Dispatch the requests of a shim session to the workers, which queue the responses to a
+/// dedicated writer
+fn session_listen(stream: UnixStream, pool: WorkerPool, request_size_max: usize) {
+    let mut reader = match stream.try_clone() {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("Failed to clone the session stream: {}", e);
+            return;
+        }
+    };
+    let (responses_tx, responses_rx) = channel();
+    // Not joined: stopped when the reader and the pending requests are done
+    let _ = thread::spawn(move || session_write(stream, responses_rx));
+    loop {
+        let request: SessionRequest = match recv_limit(&mut reader, request_size_max) {
+            Ok(r) => r,
+            Err(e) => {
+                debug!("Session closed: {}", e);
+                return;
+            }
+        };
+        debug!("Monitor got session request: {:?}", request);
+        match pool.submit(MonitorJob::Session(request, responses_tx.clone())) {
+            Ok(()) => {}
+            Err(e) => {
+                warn!("Failed to handle the session request: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+fn portal_ext_listen(manager_tx: Sender<ManagerAction>, request_size_max: usize,
+                     protected: Arc<Vec<PathBuf>>) {
     let server = PORTAL_SOCKET_PATH;
     // FIXME: Use libc::SO_REUSEADDR for unix socket instead of removing the file
     let _ = fs::remove_file(&server);
@@ -72,9 +162,10 @@ fn portal_ext_listen(manager_tx: Sender<ManagerAction>) {
         match client {
             Ok(c) => {
                 let manager_tx = manager_tx.clone();
+                let protected = protected.clone();
                 // TODO: Join all threads
-                thread::spawn(|| {
-                    match portal_handle(c, manager_tx) {
+                thread::spawn(move || {
+                    match portal_handle(c, manager_tx, request_size_max, &protected) {
                         Ok(_) => {},
                         Err(e) => error!("Error handling portal client: {}", e),
                     }
@@ -90,7 +181,9 @@ fn portal_ext_listen(manager_tx: Sender<ManagerAction>) {
 
 pub fn portal_listen(portal: Portal) -> Result<(), String> {
     let (manager_tx, manager_rx) = channel();
-    thread::spawn(|| portal_ext_listen(manager_tx));
+    let request_size_max = portal.request_size_max();
+    let protected = Arc::new(portal.protected().clone());
+    thread::spawn(move || portal_ext_listen(manager_tx, request_size_max, protected));
 
     // Spawn the domain manager on the current thread
     manager_listen(portal, manager_rx);
@@ -98,7 +191,7 @@ pub fn portal_listen(portal: Portal) -> Result<(), String> {
 }
 
 // FIXME: Handle return error
-pub fn monitor_listen(cmd_tx: Sender<Box<JailFn>>, quit: Arc<AtomicBool>) {
+pub fn monitor_listen(cmd_tx: Sender<Box<JailFn>>, quit: Arc<AtomicBool>, request_size_max: usize) {
     let server = MONITOR_SOCKET_PATH;
     let mut request_count = 0u64;
     // FIXME: Use libc::SO_REUSEADDR for unix socket instead of removing the file
@@ -113,19 +206,17 @@ pub fn monitor_listen(cmd_tx: Sender<Box<JailFn>>, quit: Arc<AtomicBool>) {
         }
         Ok(v) => v,
     };
+    // TODO: Forward the quit event to the workers
+    let pool = WorkerPool::new(MONITOR_WORKERS, MONITOR_QUEUE, cmd_tx, request_size_max);
     while !quit.load(Relaxed) {
         match acceptor.accept() {
             Ok((s, _)) => {
-                let client_cmd_fn = cmd_tx.clone();
                 request_count += 1;
-                // TODO: Join all threads
-                thread::spawn(|| {
-                    // TODO: Forward the quit event to monitor_handle
-                    match monitor_handle(s, client_cmd_fn) {
-                        Ok(_) => {},
-                        Err(e) => debug!("Error handling monitor client: {}", e),
-                    }
-                });
+                // The clients are only read by the workers, a slow one can't hold the listener
+                match pool.try_submit(MonitorJob::Client(s)) {
+                    Ok(()) => {}
+                    Err(e) => warn!("Failed to handle the monitor client: {}", e),
+                }
             }
             Err(ref e) if e.kind() == ErrorKind::TimedOut => {}
             Err(e) => debug!("Connection error: {}", e),
@@ -133,3 +224,49 @@ pub fn monitor_listen(cmd_tx: Sender<Box<JailFn>>, quit: Arc<AtomicBool>) {
     }
     info!("Monitor got {} requests", request_count);
 }
+
+/// Handle the trusted requests forwarded by the portal
+pub fn monitor_grant_listen(mut stream: UnixStream, cmd_tx: Sender<Box<JailFn>>,
+                            protected: Vec<PathBuf>) {
+    loop {
+        let request: GrantRequest = match recv(&mut stream) {
+            Ok(r) => r,
+            Err(e) => {
+                debug!("Grant channel closed: {}", e);
+                return;
+            }
+        };
+        debug!("Monitor got grant: {:?}", request);
+        let response = match request.check(&protected) {
+            Ok(()) => {
+                let (response_tx, response_rx) = channel();
+                let bundle = GrantBundle {
+                    request: request,
+                    response: response_tx,
+                };
+                match cmd_tx.send(Box::new(bundle)) {
+                    Ok(()) => match response_rx.recv() {
+                        Ok(r) => r,
+                        Err(e) => GrantResponse {
+                            error: Some(format!("Failed to receive the grant result: {}", e)),
+                        },
+                    },
+                    Err(e) => {
+                        warn!("Failed to spawn grant action: {}", e);
+                        return;
+                    }
+                }
+            }
+            Err(e) => GrantResponse {
+                error: Some(format!("Request error: {}", e)),
+            },
+        };
+        match send(&mut stream, response) {
+            Ok(()) => {}
+            Err(e) => {
+                warn!("Failed to send the grant response: {}", e);
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,95 @@
+// Copyright (C) 2016 Mickaël Salaün
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 of the License.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use cmd::shim::{SessionRequest, SessionResponse};
+use jail::JailFn;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Receiver, Sender, SyncSender, TrySendError, sync_channel};
+use std::thread;
+use super::monitor_handle;
+use unix_socket::UnixStream;
+
+/// Monitor job, the listener never waits for a client to send something
+pub enum MonitorJob {
+    /// New client connection, whose request is read by the worker (cf. `MONITOR_IO_TIMEOUT`)
+    Client(UnixStream),
+    /// Tagged request from a session, whose response is queued to the session writer
+    Session(SessionRequest, Sender<SessionResponse>),
+}
+
+/// Bounded pool of threads handling the monitor requests, instead of one thread per request
+#[derive(Clone)]
+pub struct WorkerPool {
+    jobs: SyncSender<MonitorJob>,
+}
+
+impl WorkerPool {
+    pub fn new(workers: usize, queue: usize, cmd_tx: Sender<Box<JailFn>>, request_size_max: usize)
+            -> WorkerPool {
+        let (jobs_tx, jobs_rx) = sync_channel(queue);
+        let jobs_rx = Arc::new(Mutex::new(jobs_rx));
+        let pool = WorkerPool {
+            jobs: jobs_tx,
+        };
+        for _ in 0..workers {
+            let worker_rx = jobs_rx.clone();
+            let worker_tx = cmd_tx.clone();
+            // The sessions submit their requests to the same pool
+            let worker_pool = pool.clone();
+            // Not joined: the workers live as long as the monitor
+            let _ = thread::spawn(move || {
+                worker(worker_rx, worker_tx, worker_pool, request_size_max);
+            });
+        }
+        pool
+    }
+
+    /// Queue a job, or wait for a free slot if the queue is full
+    pub fn submit(&self, job: MonitorJob) -> Result<(), String> {
+        self.jobs.send(job).map_err(|_| "The monitor workers are gone".to_string())
+    }
+
+    /// Queue a job, or refuse it if the queue is full
+    pub fn try_submit(&self, job: MonitorJob) -> Result<(), String> {
+        match self.jobs.try_send(job) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(..)) => Err("The monitor workers are busy".to_string()),
+            Err(TrySendError::Disconnected(..)) => Err("The monitor workers are gone".to_string()),
+        }
+    }
+}
+
+fn worker(jobs: Arc<Mutex<Receiver<MonitorJob>>>, cmd_tx: Sender<Box<JailFn>>, pool: WorkerPool,
+          request_size_max: usize) {
+    loop {
+        let job = {
+            let jobs = match jobs.lock() {
+                Ok(j) => j,
+                Err(_) => return,
+            };
+            match jobs.recv() {
+                Ok(j) => j,
+                Err(_) => return,
+            }
+        };
+        let ret = match job {
+            MonitorJob::Client(stream) => monitor_handle(stream, &pool, request_size_max, &cmd_tx),
+            MonitorJob::Session(request, responses) => request.call(&cmd_tx, &responses),
+        };
+        match ret {
+            Ok(_) => {},
+            Err(e) => debug!("Error handling monitor client: {}", e),
+        }
+    }
+}
@@ -19,26 +19,119 @@ use std::io::{Read, Write};
 
 pub use stemflow::absolute_path;
 
-// Message format: 2 bytes for the size + bincode encoding
+/// First bytes sent by each peer of a connection
+pub const PROTOCOL_MAGIC: &'static [u8; 4] = b"STMJ";
+
+/// Must be incremented for each incompatible change of the messages
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Hard upper bound of an encoded message (e.g. a DOT graph of hundreds of domains)
+pub const MESSAGE_SIZE_MAX: usize = 16 * 1024 * 1024;
+
+/// Default maximum size of a request read by the servers from a (potentially untrusted) client
+/// (cf. `PortalConfig::request_size_max`)
+pub const REQUEST_SIZE_MAX: usize = 64 * 1024;
+
+bitflags! {
+    pub flags Features: u32 {
+        /** Shim sessions (cf. `ShimSession`) */
+        const FEATURE_SESSION = 1 << 0,
+
+        /** Domain change subscriptions */
+        const FEATURE_SUBSCRIBE = 1 << 1,
+
+        /** Shared access cache (cf. `SharedCache`) */
+        const FEATURE_SHARED_CACHE = 1 << 2,
+
+        /** Batched access requests */
+        const FEATURE_ACCESS_BATCH = 1 << 3,
+    }
+}
+
+/// Features supported by this build
+pub fn local_features() -> Features {
+    Features::all()
+}
+
+// Handshake format: magic + version (2 bytes) + feature bitmap (4 bytes), little-endian
+const HELLO_SIZE: usize = 10;
+
+pub fn send_hello<T>(stream: &mut T) -> Result<(), String> where T: Write {
+    let mut hello = [0u8; HELLO_SIZE];
+    hello[..4].copy_from_slice(PROTOCOL_MAGIC);
+    for i in 0..2 {
+        hello[4 + i] = (PROTOCOL_VERSION >> (i * 8)) as u8;
+    }
+    let features = local_features().bits();
+    for i in 0..4 {
+        hello[6 + i] = (features >> (i * 8)) as u8;
+    }
+    match stream.write_all(&hello).and_then(|_| stream.flush()) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(format!("Failed to send the handshake: {}", e)),
+    }
+}
+
+/// Check the peer protocol version and get the features supported by both peers
+pub fn recv_hello<T>(stream: &mut T) -> Result<Features, String> where T: Read {
+    let mut hello = [0u8; HELLO_SIZE];
+    match stream.read_exact(&mut hello) {
+        Ok(_) => {}
+        Err(e) => return Err(format!("Failed to read the handshake: {}", e)),
+    }
+    if &hello[..4] != PROTOCOL_MAGIC {
+        return Err("Failed to read the handshake: Not a StemJail peer".to_string());
+    }
+    let mut version = 0u16;
+    for i in 0..2 {
+        version |= (hello[4 + i] as u16) << (i * 8);
+    }
+    if version != PROTOCOL_VERSION {
+        return Err(format!("Protocol version mismatch: the peer uses version {} but this one \
+                            uses version {} (kage, portal and monitor must be upgraded together)",
+                           version, PROTOCOL_VERSION));
+    }
+    let mut features = 0u32;
+    for i in 0..4 {
+        features |= (hello[6 + i] as u32) << (i * 8);
+    }
+    // Ignore the unknown features
+    Ok(Features::from_bits_truncate(features) & local_features())
+}
+
+/// Must be called by both peers at the start of a connection
+pub fn handshake<T>(stream: &mut T) -> Result<Features, String> where T: Read + Write {
+    try!(send_hello(stream));
+    recv_hello(stream)
+}
+
 pub fn send<T, U>(stream: &mut T, object: U) -> Result<(), String>
         where T: Write, U: Encodable {
+    send_limit(stream, object, MESSAGE_SIZE_MAX)
+}
+
+// Message format: 4 bytes for the size (little-endian) + bincode encoding
+pub fn send_limit<T, U>(stream: &mut T, object: U, limit: usize) -> Result<(), String>
+        where T: Write, U: Encodable {
+    let limit = ::std::cmp::min(limit, MESSAGE_SIZE_MAX);
     let encoded = match encode(&object, SizeLimit::Infinite) {
         Ok(s) => s,
         Err(e) => return Err(format!("Failed to encode request: {}", e)),
     };
-    let mut encoded_size = [0u8; 2];
-    if encoded.len() | 0xffff != 0xffff {
-        return Err(format!("Failed to send request: Command too big"));
+    if encoded.len() > limit {
+        return Err(format!("Failed to send request: Command too big ({} bytes)", encoded.len()));
     }
-    let size = encoded.len() as u16;
-    for i in 0..2 {
+    let mut encoded_size = [0u8; 4];
+    let size = encoded.len() as u32;
+    for i in 0..4 {
         encoded_size[i] = (size >> (i * 8)) as u8;
     }
     match stream.write_all(&encoded_size) {
         Ok(_) => {}
         Err(e) => return Err(format!("Failed to send request: {}", e)),
     };
-    match stream.write_all(encoded.as_ref()) {
+    // Flush the buffered streams (e.g. BufStream) before waiting for a response
+    match stream.write_all(encoded.as_ref()).and_then(|_| stream.flush()) {
         Ok(_) => Ok(()),
         Err(e) => return Err(format!("Failed to send request: {}", e)),
     }
@@ -46,16 +139,24 @@ pub fn send<T, U>(stream: &mut T, object: U) -> Result<(), String>
 
 pub fn recv<T, U>(stream: &mut T) -> Result<U, String>
         where T: Read, U: Decodable {
-    let mut encoded_size = [0u8; 2];
+    recv_limit(stream, MESSAGE_SIZE_MAX)
+}
+
+pub fn recv_limit<T, U>(stream: &mut T, limit: usize) -> Result<U, String>
+        where T: Read, U: Decodable {
+    let limit = ::std::cmp::min(limit, MESSAGE_SIZE_MAX);
+    let mut encoded_size = [0u8; 4];
     match stream.read_exact(&mut encoded_size) {
         Ok(_) => {}
         Err(e) => return Err(format!("Failed to read: {}", e)),
     }
-    let mut size = 0u16;
-    for i in 0..2 {
-        size |= (encoded_size[i] as u16) << i;
+    let mut size = 0u32;
+    for i in 0..4 {
+        size |= (encoded_size[i] as u32) << (i * 8);
+    }
+    if size as usize > limit {
+        return Err(format!("Failed to read: Message too big ({} bytes)", size));
     }
-    // TODO: Add size limit (less than 64K)
     let mut encoded = Vec::with_capacity(size as usize);
     let encoded = match stream.take(size as u64).read_to_end(&mut encoded) {
         Ok(s) if s == size as usize => encoded,
@@ -67,3 +168,45 @@ pub fn recv<T, U>(stream: &mut T) -> Result<U, String>
         Err(e) => Err(format!("Failed to decode: {:?}", e)),
     }
 }
+
+#[test]
+fn test_framing() {
+    use std::io::Cursor;
+
+    // The size needs more than two bytes
+    for &len in [300, 100 * 1024].iter() {
+        let message: Vec<u8> = (0..len).map(|x| x as u8).collect();
+        let mut stream = Cursor::new(vec!());
+        send(&mut stream, &message).unwrap();
+        stream.set_position(0);
+        let received: Vec<u8> = recv(&mut stream).unwrap();
+        assert_eq!(received, message);
+
+        stream.set_position(0);
+        assert!(recv_limit::<_, Vec<u8>>(&mut stream, 256).is_err());
+        assert!(send_limit(&mut Cursor::new(vec!()), &message, 256).is_err());
+    }
+
+    // The hard bound can't be raised
+    let mut stream = Cursor::new(vec!(0xff, 0xff, 0xff, 0xff));
+    assert!(recv_limit::<_, Vec<u8>>(&mut stream, ::std::usize::MAX).is_err());
+}
+
+#[test]
+fn test_handshake() {
+    use std::io::Cursor;
+
+    let mut stream = Cursor::new(vec!());
+    send_hello(&mut stream).unwrap();
+    stream.set_position(0);
+    assert_eq!(recv_hello(&mut stream).unwrap(), local_features());
+
+    // Another version
+    let mut hello = stream.into_inner();
+    hello[4] = hello[4].wrapping_add(1);
+    let err = recv_hello(&mut Cursor::new(hello.clone())).unwrap_err();
+    assert!(err.contains("version"), "{}", err);
+
+    hello[0] = 0;
+    assert!(recv_hello(&mut Cursor::new(hello)).is_err());
+}